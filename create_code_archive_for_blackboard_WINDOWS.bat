@@ -10,5 +10,6 @@ if exist source.zip (
     resources/* ^
     -x!resources/helicopter.obj ^
     -x!resources/lunarsurface.obj ^
-    -x!resources/.gitkeep
+    -x!resources/.gitkeep ^
+    -x!resources/*.meshcache
 pause
@@ -0,0 +1,18 @@
+#pragma once
+
+vec3 lightDirection = normalize(vec3(0.8, -0.5, 0.6));
+
+float lambert(vec3 normal)
+{
+    return max(0.0, dot(normal, -lightDirection));
+}
+
+// Blinn-Phong highlight, none at all for a shininess of 0 or on the side facing away from the light
+float highlight(vec3 normal, vec3 view_direction, float shininess)
+{
+    if (shininess <= 0.0 || dot(normal, -lightDirection) <= 0.0) {
+        return 0.0;
+    }
+    vec3 halfway = normalize(view_direction - lightDirection);
+    return pow(max(0.0, dot(normalize(normal), halfway)), shininess);
+}
@@ -2,15 +2,60 @@
 
 in  vec4 vert_color;
 in  vec3 vert_normal;
+in  vec3 vert_position;
+in  vec2 vert_texcoord;
+#ifdef NORMAL_MAP
+in  vec4 vert_tangent;
+#endif
 
 out vec4 color;
 
-vec3 lightDirection = normalize(vec3(0.8, -0.5, 0.6));
+#include "lighting.glsl"
+
+// From the MTL file, see `mesh::Material`
+struct Material {
+    vec3  ambient;
+    vec3  diffuse;
+    vec3  specular;
+    float shininess;
+    float dissolve;
+};
+
+uniform Material material;
+uniform vec3 camera_position;
+
+// Multiplied with the diffuse colour of the material
+uniform sampler2D diffuse_texture;
+uniform bool has_diffuse_texture;
+
+#ifdef NORMAL_MAP
+// In tangent space, with the bitangent rebuilt the same way as MikkTSpace does it
+uniform sampler2D normal_texture;
+#endif
+
+vec3 surface_normal()
+{
+#ifdef NORMAL_MAP
+    vec3 normal = normalize(vert_normal);
+    vec3 tangent = normalize(vert_tangent.xyz - normal * dot(normal, vert_tangent.xyz));
+    vec3 bitangent = vert_tangent.w * cross(normal, tangent);
+    vec3 mapped = texture(normal_texture, vert_texcoord).xyz * 2.0 - 1.0;
+    return normalize(mat3(tangent, bitangent, normal) * mapped);
+#else
+    return vert_normal;
+#endif
+}
 
 void main()
 {
-    
-    vec4 tempcolor = vert_color*max(0.0, dot(vert_normal, -lightDirection));
-    color = vec4(tempcolor.x, tempcolor.y, tempcolor.z, 1.0f);
-    
-}
\ No newline at end of file
+    vec4 albedo = vert_color;
+    if (has_diffuse_texture) {
+        albedo *= texture(diffuse_texture, vert_texcoord);
+    }
+
+    vec3 normal = surface_normal();
+    vec3 view_direction = normalize(camera_position - vert_position);
+    vec3 lit = albedo.rgb * (material.ambient + material.diffuse * lambert(normal))
+             + material.specular * highlight(normal, view_direction, material.shininess);
+    color = vec4(lit, albedo.a * material.dissolve);
+}
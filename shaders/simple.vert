@@ -3,12 +3,26 @@
 in vec3 position;
 in vec4 color;
 in vec3 normal;
+in vec2 texcoord;
 
 out vec4 vert_color;
 out vec3 vert_normal;
-
-layout(location= 10) uniform mat4 model_mat;
-layout(location= 26) uniform mat4 view_mat;
+out vec3 vert_position;     // In world space
+out vec2 vert_texcoord;
+
+// Built with NORMAL_MAP defined for meshes with a normal map, see `Mesh::compute_tangents`
+#ifdef NORMAL_MAP
+in vec4 tangent;            // w is the sign of the bitangent
+out vec4 vert_tangent;
+#endif
+
+// Built with INSTANCED defined for drawing many copies at once, see `GpuMesh::draw_instanced`
+#ifdef INSTANCED
+in mat4 model_mat;          // One for each copy
+#else
+uniform mat4 model_mat;
+#endif
+uniform mat4 view_mat;
 mat4 MVP = view_mat * model_mat;
 
 
@@ -24,4 +38,9 @@ void main()
     gl_Position = MVP * vec4(position, 1.0f);
     vert_color = color;
     vert_normal = normal_new_norm;
+    vert_position = vec3(model_mat * vec4(position, 1.0f));
+    vert_texcoord = texcoord;
+#ifdef NORMAL_MAP
+    vert_tangent = vec4(normalize(model_mat3 * tangent.xyz), tangent.w);
+#endif
 }
\ No newline at end of file
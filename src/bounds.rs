@@ -0,0 +1,362 @@
+extern crate nalgebra_glm as glm;
+
+// Bounding volumes: boxes and spheres around meshes and parts of the scene graph, for finding out
+// cheaply whether something can be on screen, under the mouse, and so on.
+//
+// Both are kept for everything, since neither is always the tighter fit. A box fits a flat
+// terrain much better, while a sphere doesn't grow when it's rotated.
+
+// An axis-aligned bounding box. It's empty if the minimum is above the maximum along any axis,
+// as it is for the box around no points at all.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min : glm::Vec3,
+    pub max : glm::Vec3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sphere {
+    pub center : glm::Vec3,
+    pub radius : f32,
+}
+
+// A box and a sphere around the same thing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub aabb   : Aabb,
+    pub sphere : Sphere,
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min : glm::Vec3::repeat(f32::INFINITY),
+            max : glm::Vec3::repeat(f32::NEG_INFINITY),
+        }
+    }
+
+    // The box around the points in `positions`, which holds three floats for each
+    pub fn around(positions: &[f32]) -> Aabb {
+        positions.chunks_exact(3).fold(Aabb::empty(), |aabb, p| aabb.including(&glm::make_vec3(p)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn center(&self) -> glm::Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    // The length of the box along each axis
+    pub fn size(&self) -> glm::Vec3 {
+        self.max - self.min
+    }
+
+    pub fn contains(&self, point: &glm::Vec3) -> bool {
+        (0..3).all(|axis| self.min[axis] <= point[axis] && point[axis] <= self.max[axis])
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3).all(|axis| self.min[axis] <= other.max[axis] && other.min[axis] <= self.max[axis])
+    }
+
+    pub fn including(&self, point: &glm::Vec3) -> Aabb {
+        Aabb { min: glm::min2(&self.min, point), max: glm::max2(&self.max, point) }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb { min: glm::min2(&self.min, &other.min), max: glm::max2(&self.max, &other.max) }
+    }
+
+    // The box around this box after it has been transformed, e.g. by the world matrix of a node.
+    // The corners are not transformed one by one, instead each axis of the matrix stretches the
+    // box by what it adds at either end, as in "Transforming Axis-Aligned Bounding Boxes" by
+    // James Arvo (Graphics Gems, 1990).
+    pub fn transformed(&self, matrix: &glm::Mat4) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let translation = glm::vec3(matrix[(0, 3)], matrix[(1, 3)], matrix[(2, 3)]);
+        let (mut min, mut max) = (translation, translation);
+        for row in 0..3 {
+            for column in 0..3 {
+                let a = matrix[(row, column)] * self.min[column];
+                let b = matrix[(row, column)] * self.max[column];
+                min[row] += a.min(b);
+                max[row] += a.max(b);
+            }
+        }
+        Aabb { min, max }
+    }
+
+    // How far along the ray from `origin` in `direction` it first touches the box, in multiples
+    // of `direction`. 0 if it starts inside, and None if it misses.
+    pub fn ray_intersection(&self, origin: &glm::Vec3, direction: &glm::Vec3) -> Option<f32> {
+        let (mut near, mut far) = (0.0f32, f32::INFINITY);
+        for axis in 0..3 {
+            if direction[axis] == 0.0 {
+                // Parallel to the slab, so it has to be between its sides all along
+                if origin[axis] < self.min[axis] || origin[axis] > self.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (self.min[axis] - origin[axis]) / direction[axis];
+            let t2 = (self.max[axis] - origin[axis]) / direction[axis];
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+        if near <= far { Some(near) } else { None }
+    }
+}
+
+impl Sphere {
+    // Around the center of the box around `positions`, which is not the smallest sphere, but
+    // close enough for most meshes
+    pub fn around(positions: &[f32]) -> Sphere {
+        let aabb = Aabb::around(positions);
+        if aabb.is_empty() {
+            return Sphere { center: glm::zero(), radius: 0.0 };
+        }
+        let center = aabb.center();
+        let radius = positions.chunks_exact(3)
+            .map(|p| glm::distance(&glm::make_vec3(p), &center))
+            .fold(0.0, f32::max);
+        Sphere { center, radius }
+    }
+
+    pub fn contains(&self, point: &glm::Vec3) -> bool {
+        glm::distance(&self.center, point) <= self.radius
+    }
+
+    pub fn intersects(&self, other: &Sphere) -> bool {
+        glm::distance(&self.center, &other.center) <= self.radius + other.radius
+    }
+
+    // The smallest sphere around both spheres
+    pub fn union(&self, other: &Sphere) -> Sphere {
+        let offset = other.center - self.center;
+        let distance = offset.norm();
+        if distance + other.radius <= self.radius {
+            return *self;
+        }
+        if distance + self.radius <= other.radius {
+            return *other;
+        }
+        let radius = (distance + self.radius + other.radius) / 2.0;
+        Sphere { center: self.center + offset * ((radius - self.radius) / distance), radius }
+    }
+
+    // The sphere around this sphere after it has been transformed. Scaling it more along one
+    // axis than the others turns it into an ellipsoid, so the radius grows with the largest scale.
+    pub fn transformed(&self, matrix: &glm::Mat4) -> Sphere {
+        let center = matrix * glm::vec4(self.center.x, self.center.y, self.center.z, 1.0);
+        let linear = glm::mat4_to_mat3(matrix);
+        let scale = (0..3).map(|column| linear.column(column).norm()).fold(0.0, f32::max);
+        Sphere { center: glm::vec3(center.x, center.y, center.z), radius: self.radius * scale }
+    }
+
+    // See `Aabb::ray_intersection`
+    pub fn ray_intersection(&self, origin: &glm::Vec3, direction: &glm::Vec3) -> Option<f32> {
+        let to_origin = origin - self.center;
+        let a = direction.norm_squared();
+        let b = to_origin.dot(direction);
+        let c = to_origin.norm_squared() - self.radius * self.radius;
+        if c <= 0.0 {
+            return Some(0.0);
+        }
+        let discriminant = b * b - a * c;
+        if a == 0.0 || discriminant < 0.0 {
+            return None;
+        }
+        let t = (-b - discriminant.sqrt()) / a;
+        if t >= 0.0 { Some(t) } else { None }
+    }
+
+    // How far from the center a camera with a vertical field of view of `fovy` (in radians)
+    // must be to see all of the sphere
+    pub fn framing_distance(&self, fovy: f32) -> f32 {
+        self.radius / (fovy / 2.0).sin()
+    }
+}
+
+impl Bounds {
+    // What has no points, which is left out of unions
+    pub fn empty() -> Bounds {
+        Bounds { aabb: Aabb::empty(), sphere: Sphere { center: glm::zero(), radius: 0.0 } }
+    }
+
+    // Around the points in `positions`, which holds three floats for each
+    pub fn around(positions: &[f32]) -> Bounds {
+        Bounds { aabb: Aabb::around(positions), sphere: Sphere::around(positions) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aabb.is_empty()
+    }
+
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        match (self.is_empty(), other.is_empty()) {
+            (true, _) => *other,
+            (_, true) => *self,
+            _ => Bounds { aabb: self.aabb.union(&other.aabb), sphere: self.sphere.union(&other.sphere) },
+        }
+    }
+
+    pub fn transformed(&self, matrix: &glm::Mat4) -> Bounds {
+        if self.is_empty() {
+            return *self;
+        }
+        Bounds { aabb: self.aabb.transformed(matrix), sphere: self.sphere.transformed(matrix) }
+    }
+
+    // Where the ray first touches both volumes, see `Aabb::ray_intersection`
+    pub fn ray_intersection(&self, origin: &glm::Vec3, direction: &glm::Vec3) -> Option<f32> {
+        if self.is_empty() {
+            return None;
+        }
+        let sphere = self.sphere.ray_intersection(origin, direction)?;
+        let aabb = self.aabb.ray_intersection(origin, direction)?;
+        Some(sphere.max(aabb))
+    }
+}
+
+
+// The six planes around what a camera can see: left, right, bottom, top, near and far. Each is
+// stored as (a, b, c, d), where (a, b, c) is its normal, pointing inwards, and a point p is on the
+// inner side when a*p.x + b*p.y + c*p.z + d >= 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum {
+    pub planes : [glm::Vec4; 6],
+}
+
+impl Frustum {
+    // The planes of the clip volume of `view_projection`, in world coordinates, as in "Fast
+    // Extraction of Viewing Frustum Planes from the World-View-Projection Matrix" by Gribb and
+    // Hartmann (2001). A point is inside when -w <= x, y, z <= w after the transformation, and
+    // each of those six comparisons is a plane.
+    pub fn from_matrix(view_projection: &glm::Mat4) -> Frustum {
+        let row = |i: usize| glm::vec4(
+            view_projection[(i, 0)], view_projection[(i, 1)], view_projection[(i, 2)], view_projection[(i, 3)]
+        );
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let normalized = |plane: glm::Vec4| plane / glm::vec3(plane.x, plane.y, plane.z).norm();
+        Frustum {
+            planes: [
+                normalized(w + x),
+                normalized(w - x),
+                normalized(w + y),
+                normalized(w - y),
+                normalized(w + z),
+                normalized(w - z),
+            ],
+        }
+    }
+
+    // Whether all of `bounds` is certainly outside, i.e. on the outer side of one of the planes.
+    // Something near a corner of the frustum may be outside without being reported, which only
+    // means it's drawn for nothing. Empty bounds are always outside.
+    pub fn excludes(&self, bounds: &Bounds) -> bool {
+        if bounds.is_empty() {
+            return true;
+        }
+        let Bounds { aabb, sphere } = bounds;
+        self.planes.iter().any(|plane| {
+            let normal = glm::vec3(plane.x, plane.y, plane.z);
+            // The corner of the box furthest along the normal
+            let corner = glm::vec3(
+                if normal.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if normal.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if normal.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            normal.dot(&corner) + plane.w < 0.0 || normal.dot(&sphere.center) + plane.w < -sphere.radius
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The eight corners of the box from `min` to `max`
+    fn corners(min: glm::Vec3, max: glm::Vec3) -> Vec<glm::Vec3> {
+        (0..8)
+            .map(|i| glm::vec3(
+                if i & 4 != 0 { max.x } else { min.x },
+                if i & 2 != 0 { max.y } else { min.y },
+                if i & 1 != 0 { max.z } else { min.z },
+            ))
+            .collect()
+    }
+
+    fn box_bounds(min: glm::Vec3, max: glm::Vec3) -> Bounds {
+        let vertices: Vec<f32> = corners(min, max).iter().flat_map(|c| [c.x, c.y, c.z]).collect();
+        Bounds::around(&vertices)
+    }
+
+    // Looking down -Z from the origin, seeing 45 degrees to every side, from 1 to 100 away
+    fn frustum() -> Frustum {
+        let projection = glm::perspective(1.0, std::f32::consts::FRAC_PI_2, 1.0, 100.0);
+        let view = glm::look_at(&glm::vec3(0.0, 0.0, 0.0), &glm::vec3(0.0, 0.0, -1.0), &glm::vec3(0.0, 1.0, 0.0));
+        Frustum::from_matrix(&(projection * view))
+    }
+
+    #[test]
+    fn boxes_inside_are_kept() {
+        let frustum = frustum();
+        assert!(!frustum.excludes(&box_bounds(glm::vec3(-1.0, -1.0, -11.0), glm::vec3(1.0, 1.0, -9.0))));
+        assert!(!frustum.excludes(&box_bounds(glm::vec3(5.0, -6.0, -50.0), glm::vec3(8.0, 6.0, -40.0))));
+    }
+
+    #[test]
+    fn boxes_outside_are_excluded() {
+        let frustum = frustum();
+        for (min, max) in [
+            (glm::vec3(-1.0, -1.0, 9.0), glm::vec3(1.0, 1.0, 11.0)),          // Behind
+            (glm::vec3(-1.0, -1.0, -0.9), glm::vec3(1.0, 1.0, -0.1)),         // Too near
+            (glm::vec3(-1.0, -1.0, -120.0), glm::vec3(1.0, 1.0, -101.0)),     // Too far
+            (glm::vec3(12.0, -1.0, -11.0), glm::vec3(14.0, 1.0, -9.0)),       // To the right
+            (glm::vec3(-1.0, -14.0, -11.0), glm::vec3(1.0, -12.0, -9.0)),     // Below
+        ] {
+            assert!(frustum.excludes(&box_bounds(min, max)), "{:?} to {:?} is kept", min, max);
+        }
+        assert!(frustum.excludes(&Bounds::empty()));
+    }
+
+    #[test]
+    fn boxes_across_a_plane_are_kept() {
+        let frustum = frustum();
+        for (min, max) in [
+            (glm::vec3(-1.0, -1.0, -2.0), glm::vec3(1.0, 1.0, 0.0)),          // The near plane
+            (glm::vec3(-1.0, -1.0, -101.0), glm::vec3(1.0, 1.0, -99.0)),      // The far plane
+            (glm::vec3(9.0, -1.0, -11.0), glm::vec3(11.0, 1.0, -9.0)),        // The right plane
+            (glm::vec3(-1.0, 9.0, -11.0), glm::vec3(1.0, 11.0, -9.0)),        // The top plane
+        ] {
+            assert!(!frustum.excludes(&box_bounds(min, max)), "{:?} to {:?} is excluded", min, max);
+        }
+    }
+
+    #[test]
+    fn transformed_boxes_match_their_corners() {
+        let aabb = Aabb { min: glm::vec3(-1.0, -2.0, 0.5), max: glm::vec3(3.0, 1.0, 2.0) };
+        let rotation = glm::rotation(0.7, &glm::normalize(&glm::vec3(1.0, 2.0, 3.0)));
+        for matrix in [
+            glm::translation(&glm::vec3(4.0, -5.0, 6.0)) * rotation * glm::scaling(&glm::vec3(1.0, 2.0, 0.5)),
+            rotation * glm::scaling(&glm::vec3(-1.0, 1.0, 1.0)),
+            glm::rotation(1.3, &glm::vec3(0.0, 1.0, 0.0)),
+        ] {
+            let (mut min, mut max) = (glm::Vec3::repeat(f32::INFINITY), glm::Vec3::repeat(f32::NEG_INFINITY));
+            for corner in corners(aabb.min, aabb.max) {
+                let corner = (matrix * glm::vec4(corner.x, corner.y, corner.z, 1.0)).xyz();
+                min = glm::min2(&min, &corner);
+                max = glm::max2(&max, &corner);
+            }
+            let transformed = aabb.transformed(&matrix);
+            assert!(glm::distance(&transformed.min, &min) < 1e-5, "{:?} is not {:?}", transformed.min, min);
+            assert!(glm::distance(&transformed.max, &max) < 1e-5, "{:?} is not {:?}", transformed.max, max);
+        }
+        assert!(Aabb::empty().transformed(&glm::rotation(0.7, &glm::vec3(0.0, 0.0, 1.0))).is_empty());
+    }
+}
@@ -0,0 +1,616 @@
+extern crate nalgebra_glm as glm;
+
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::bounds::Bounds;
+use crate::gpu_mesh::GpuMesh;
+use crate::mesh::{Material, Mesh};
+use crate::scene_graph::{NodeId, SceneGraph, SceneNode};
+use crate::texture::{Filter, Texture, TextureOptions, Wrap};
+
+// Everything in a glTF 2.0 file (.gltf or .glb): the meshes, the hierarchy of nodes they hang
+// from, their materials and the animations of the nodes.
+//
+// The node hierarchy is kept, so the pivot of every part is wherever its node is, and nothing
+// needs a `reference_point`. Nodes with a mesh made of several primitives (one per material) get
+// a child node for each primitive, since a scene node has one mesh.
+//
+// glTF has texture coordinates with v = 0 at the top of the image, while the rest of the project
+// follows OBJ with v = 0 at the bottom, so v is flipped when loading.
+//
+// The PBR materials of glTF are approximated with the Blinn-Phong ones of `Material`: the base
+// colour becomes the diffuse colour (less of it the more metallic the material is), the specular
+// colour goes from the 4% of plastics towards the base colour for metals, and the roughness sets
+// the shininess. Only the base colour texture and the normal map are used, not the metallic and
+// roughness, occlusion or emissive textures. Textures embedded in the file have no path, so the
+// software rasterizer can only use those stored in files of their own.
+
+pub struct GltfModel {
+    pub name       : String,                    // The file name, without the extension
+    pub nodes      : Vec<GltfNode>,             // In the order of the file, which the animations refer to
+    pub roots      : Vec<usize>,                // The top nodes of the scene in the file
+    pub meshes     : Vec<Vec<GltfPrimitive>>,   // The primitives of every mesh, which nodes can share
+    pub animations : Vec<Animation>,
+    textures       : Vec<Option<(image::RgbaImage, TextureOptions)>>,   // None if the image couldn't be read
+}
+
+pub struct GltfNode {
+    pub name        : String,
+    pub translation : glm::Vec3,
+    pub rotation    : glm::Quat,
+    pub scale       : glm::Vec3,
+    pub mesh        : Option<usize>,
+    pub children    : Vec<usize>,
+}
+
+pub struct GltfPrimitive {
+    pub mesh           : Rc<Mesh>,
+    pub gpu_mesh       : Option<Rc<GpuMesh>>,   // Set by `GltfModel::upload`
+    pub texture        : Option<Rc<Texture>>,   // The base colour texture, set by `GltfModel::upload`
+    pub normal_texture : Option<Rc<Texture>>,   // Set by `GltfModel::upload`
+    base_color_index   : Option<usize>,         // Into `GltfModel::textures`
+    normal_index       : Option<usize>,
+}
+
+// The scene nodes made for a model by `GltfModel::add_to`
+pub struct GltfInstance {
+    pub root  : NodeId,                 // Named after the model, with the top nodes of the scene below it
+    pub nodes : Vec<Option<NodeId>>,    // For every node in the file, None for those not in the scene
+}
+
+// A keyframe animation of the translation, rotation and scale of some nodes
+pub struct Animation {
+    pub name     : String,
+    pub channels : Vec<Channel>,
+    pub duration : f32,   // When the last keyframe is, in seconds
+}
+
+// The keyframes of one property of one node
+pub struct Channel {
+    pub node          : usize,              // Into `GltfModel::nodes`
+    pub property      : Property,
+    pub interpolation : Interpolation,
+    pub times         : Vec<f32>,           // In seconds, increasing
+    pub values        : Vec<glm::Vec4>,     // xyz, or a quaternion for rotations. Cubic splines have an
+                                            // in-tangent, a value and an out-tangent per keyframe.
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Property {
+    Translation,
+    Rotation,
+    Scale,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline,
+}
+
+impl GltfModel {
+    // Loads the default scene of the file, or the first one if there is no default
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<GltfModel, gltf::Error> {
+        let path = path.as_ref();
+        println!("Loading {}...", path.display());
+        let before = std::time::Instant::now();
+        let (document, buffers, images) = gltf::import(path)?;
+        let buffer_data = |buffer: gltf::Buffer| buffers.get(buffer.index()).map(|data| &data.0[..]);
+
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        let textures = document.textures().map(|texture| {
+            let image = images.get(texture.source().index()).and_then(to_rgba_image);
+            if image.is_none() {
+                eprintln!("Unsupported format of image {} in {}", texture.source().index(), path.display());
+            }
+            image.map(|image| (image, texture_options(&texture.sampler())))
+        }).collect();
+
+        let meshes = document.meshes().map(|mesh| {
+            mesh.primitives().filter_map(|primitive| {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    eprintln!("Skipping a primitive of {} in {}, only triangles are supported",
+                        mesh.name().unwrap_or("a mesh"), path.display());
+                    return None;
+                }
+                let reader = primitive.reader(buffer_data);
+                let material = primitive.material();
+                let texture_path = |texture: gltf::Texture| match texture.source().source() {
+                    gltf::image::Source::Uri { uri, .. } if !uri.starts_with("data:") => Some(directory.join(uri)),
+                    _ => None,
+                };
+                let base_color = material.pbr_metallic_roughness().base_color_texture();
+                let normal = material.normal_texture();
+
+                let mut mesh = Mesh {
+                    vertices  : reader.read_positions()?.flatten().collect(),
+                    normals   : reader.read_normals().map_or(vec![], |normals| normals.flatten().collect()),
+                    texcoords : reader.read_tex_coords(0).map_or(vec![], |texcoords| {
+                        texcoords.into_f32().flat_map(|[u, v]| [u, 1.0 - v]).collect()
+                    }),
+                    tangents  : reader.read_tangents().map_or(vec![], |tangents| tangents.flatten().collect()),
+                    colors    : vec![],
+                    indices   : vec![],
+                    index_count : 0,
+                    bounds      : Bounds::empty(),
+                    material  : Material {
+                        diffuse_texture : base_color.as_ref().and_then(|info| texture_path(info.texture())),
+                        normal_texture  : normal.as_ref().and_then(|info| texture_path(info.texture())),
+                        ..to_material(&material)
+                    },
+                };
+                let vertex_count = mesh.vertex_count();
+                mesh.colors = match reader.read_colors(0) {
+                    Some(colors) => colors.into_rgba_f32().flatten().collect(),
+                    None => [1.0; 4].repeat(vertex_count),
+                };
+                mesh.indices = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..vertex_count as u32).collect(),
+                };
+                mesh.index_count = mesh.indices.len() as i32;
+                mesh.update_bounds();
+                if mesh.indices.iter().any(|&index| index as usize >= vertex_count) {
+                    eprintln!("Skipping a primitive of {} in {} with indices out of range",
+                        mesh.material.name, path.display());
+                    return None;
+                }
+
+                // Flat normals, as the specification asks for
+                if !mesh.has_normals() {
+                    mesh.compute_flat_normals();
+                }
+                if normal.is_some() && !mesh.has_tangents() {
+                    mesh.compute_tangents();
+                }
+
+                Some(GltfPrimitive {
+                    mesh             : Rc::new(mesh),
+                    gpu_mesh         : None,
+                    texture          : None,
+                    normal_texture   : None,
+                    base_color_index : base_color.map(|info| info.texture().index()),
+                    normal_index     : normal.map(|info| info.texture().index()),
+                })
+            }).collect()
+        }).collect();
+
+        let nodes = document.nodes().map(|node| {
+            let (translation, rotation, scale) = node.transform().decomposed();
+            GltfNode {
+                name        : node.name().map_or_else(|| format!("Node {}", node.index()), str::to_string),
+                translation : glm::make_vec3(&translation),
+                rotation    : glm::quat(rotation[0], rotation[1], rotation[2], rotation[3]),
+                scale       : glm::make_vec3(&scale),
+                mesh        : node.mesh().map(|mesh| mesh.index()),
+                children    : node.children().map(|child| child.index()).collect(),
+            }
+        }).collect();
+
+        let roots = document.default_scene().or_else(|| document.scenes().next())
+            .map_or(vec![], |scene| scene.nodes().map(|node| node.index()).collect());
+
+        let animations = document.animations()
+            .map(|animation| load_animation(&animation, &buffer_data))
+            .collect();
+
+        println!("Done in {:.3}ms.", before.elapsed().as_micros() as f32 / 1e3);
+        Ok(GltfModel {
+            name: path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned()),
+            nodes,
+            roots,
+            meshes,
+            animations,
+            textures,
+        })
+    }
+
+    // Uploads every primitive to the GPU along with its textures, which requires an active
+    // OpenGL context
+    pub unsafe fn upload(&mut self) {
+        let textures: Vec<Option<Rc<Texture>>> = self.textures.iter()
+            .map(|texture| texture.as_ref().map(|(image, options)| Rc::new(Texture::from_image(image, options))))
+            .collect();
+        let texture = |index: Option<usize>| index.and_then(|index| textures.get(index).cloned().flatten());
+        for primitive in self.meshes.iter_mut().flatten() {
+            primitive.gpu_mesh = Some(Rc::new(GpuMesh::new(&primitive.mesh)));
+            primitive.texture = texture(primitive.base_color_index);
+            primitive.normal_texture = texture(primitive.normal_index);
+        }
+    }
+
+    pub fn find_node(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.name == name)
+    }
+
+    // Adds the scene of the model below `parent`. Like `SceneGraph::add_model`, the meshes are
+    // shared, so a model can be added many times.
+    //
+    // The rotations of the nodes are set as their `orientation`, except for nodes which aren't
+    // rotated at all, which can then be turned with `rotation` as usual.
+    pub fn add_to(&self, scene: &mut SceneGraph, parent: NodeId) -> GltfInstance {
+        let mut group = SceneNode::new();
+        group.name = self.name.clone();
+        let root = scene.add_child(parent, group);
+        let mut instance = GltfInstance {
+            root,
+            nodes: vec![None; self.nodes.len()],
+        };
+        for &index in &self.roots {
+            self.add_node(scene, root, index, &mut instance);
+        }
+        instance
+    }
+
+    fn add_node(&self, scene: &mut SceneGraph, parent: NodeId, index: usize, instance: &mut GltfInstance) {
+        let node = &self.nodes[index];
+        // A node can only be in the scene once, so anything else is a broken file
+        if instance.nodes[index].is_some() {
+            return;
+        }
+        let primitives = node.mesh.and_then(|mesh| self.meshes.get(mesh)).map_or(&[][..], |p| &p[..]);
+        let mut scene_node = match primitives {
+            [primitive] => primitive_node(primitive, &node.name),
+            _ => SceneNode::new(),
+        };
+        scene_node.name = node.name.clone();
+        scene_node.position = node.translation;
+        scene_node.orientation = if node.rotation == glm::quat_identity() { None } else { Some(node.rotation) };
+        scene_node.scale = node.scale;
+        let id = scene.add_child(parent, scene_node);
+        instance.nodes[index] = Some(id);
+
+        if primitives.len() > 1 {
+            for (i, primitive) in primitives.iter().enumerate() {
+                scene.add_child(id, primitive_node(primitive, &format!("{}.{}", node.name, i)));
+            }
+        }
+        for &child in &node.children {
+            self.add_node(scene, id, child, instance);
+        }
+    }
+}
+
+impl Animation {
+    // Poses the nodes of `instance` as they are `time` seconds into the animation. Before the
+    // first and after the last keyframe the nodes hold still, use `time % duration` to loop.
+    pub fn apply(&self, scene: &mut SceneGraph, instance: &GltfInstance, time: f32) {
+        for channel in &self.channels {
+            let id = match instance.nodes.get(channel.node) {
+                Some(&Some(id)) => id,
+                _ => continue,
+            };
+            let value = channel.sample(time);
+            let node = &mut scene[id];
+            match channel.property {
+                Property::Translation => node.position = value.xyz(),
+                Property::Rotation    => node.orientation = Some(glm::quat_normalize(&glm::Quat::from(value))),
+                Property::Scale       => node.scale = value.xyz(),
+            }
+        }
+    }
+}
+
+impl Channel {
+    pub fn sample(&self, time: f32) -> glm::Vec4 {
+        let value = |keyframe: usize| match self.interpolation {
+            Interpolation::CubicSpline => self.values[3 * keyframe + 1],
+            _                          => self.values[keyframe],
+        };
+        let last = self.times.len() - 1;
+        if time <= self.times[0] {
+            return value(0);
+        }
+        if time >= self.times[last] {
+            return value(last);
+        }
+
+        // The keyframes on either side of `time`
+        let next = self.times.partition_point(|&t| t <= time);
+        let previous = next - 1;
+        let delta = self.times[next] - self.times[previous];
+        let t = (time - self.times[previous]) / delta;
+        match self.interpolation {
+            Interpolation::Step => value(previous),
+            Interpolation::Linear if self.property == Property::Rotation => {
+                let (from, to) = (glm::Quat::from(value(previous)), glm::Quat::from(value(next)));
+                glm::quat_slerp(&from, &to, t).coords
+            }
+            Interpolation::Linear => glm::lerp(&value(previous), &value(next), t),
+            Interpolation::CubicSpline => {
+                // Hermite spline, with the tangents scaled to the time between the keyframes
+                let out_tangent = self.values[3 * previous + 2] * delta;
+                let in_tangent = self.values[3 * next] * delta;
+                let (t2, t3) = (t * t, t * t * t);
+                value(previous) * (2.0 * t3 - 3.0 * t2 + 1.0)
+                    + out_tangent * (t3 - 2.0 * t2 + t)
+                    + value(next) * (-2.0 * t3 + 3.0 * t2)
+                    + in_tangent * (t3 - t2)
+            }
+        }
+    }
+}
+
+fn load_animation<'a, 's, F>(animation: &gltf::Animation<'a>, buffer_data: &F) -> Animation
+    where F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]>
+{
+    use gltf::animation::util::ReadOutputs;
+
+    let channels: Vec<Channel> = animation.channels().filter_map(|channel| {
+        let reader = channel.reader(buffer_data.clone());
+        let times: Vec<f32> = reader.read_inputs()?.collect();
+        let (property, values): (Property, Vec<glm::Vec4>) = match reader.read_outputs()? {
+            ReadOutputs::Translations(translations) => (Property::Translation, translations.map(|[x, y, z]| glm::vec4(x, y, z, 0.0)).collect()),
+            ReadOutputs::Rotations(rotations) => (Property::Rotation, rotations.into_f32().map(|r| glm::make_vec4(&r)).collect()),
+            ReadOutputs::Scales(scales) => (Property::Scale, scales.map(|[x, y, z]| glm::vec4(x, y, z, 0.0)).collect()),
+            // Morph targets aren't supported
+            ReadOutputs::MorphTargetWeights(_) => return None,
+        };
+        let interpolation = match channel.sampler().interpolation() {
+            gltf::animation::Interpolation::Step        => Interpolation::Step,
+            gltf::animation::Interpolation::Linear      => Interpolation::Linear,
+            gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+        };
+        let values_per_keyframe = if interpolation == Interpolation::CubicSpline { 3 } else { 1 };
+        if times.is_empty() || values.len() != times.len() * values_per_keyframe {
+            return None;
+        }
+        Some(Channel {
+            node: channel.target().node().index(),
+            property,
+            interpolation,
+            times,
+            values,
+        })
+    }).collect();
+
+    Animation {
+        name     : animation.name().unwrap_or("").to_string(),
+        duration : channels.iter().filter_map(|channel| channel.times.last()).fold(0.0, |a: f32, &b| a.max(b)),
+        channels,
+    }
+}
+
+fn primitive_node(primitive: &GltfPrimitive, name: &str) -> SceneNode {
+    let mut node = SceneNode::from_mesh(Rc::clone(&primitive.mesh), primitive.gpu_mesh.clone());
+    node.name = name.to_string();
+    node.texture = primitive.texture.clone();
+    node.normal_texture = primitive.normal_texture.clone();
+    node
+}
+
+fn to_material(material: &gltf::Material) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, a] = pbr.base_color_factor();
+    let base_color = glm::vec3(r, g, b);
+    let metallic = pbr.metallic_factor();
+    let roughness = pbr.roughness_factor().clamp(0.05, 1.0);
+
+    let diffuse = base_color * (1.0 - metallic);
+    let specular = glm::lerp(&glm::vec3(0.04, 0.04, 0.04), &base_color, metallic);
+    Material {
+        name      : material.name().unwrap_or("").to_string(),
+        diffuse   : [diffuse.x, diffuse.y, diffuse.z],
+        specular  : [specular.x, specular.y, specular.z],
+        // The usual match between a Blinn-Phong exponent and the roughness of GGX
+        shininess : 2.0 / roughness.powi(4) - 2.0,
+        dissolve  : if material.alpha_mode() == gltf::material::AlphaMode::Blend { a } else { 1.0 },
+        ..Material::default()
+    }
+}
+
+fn texture_options(sampler: &gltf::texture::Sampler) -> TextureOptions {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+    let (min_filter, mipmaps) = match sampler.min_filter() {
+        Some(MinFilter::Nearest)              => (Filter::Nearest, false),
+        Some(MinFilter::Linear)               => (Filter::Linear,  false),
+        Some(MinFilter::NearestMipmapNearest)
+        | Some(MinFilter::NearestMipmapLinear) => (Filter::Nearest, true),
+        _                                     => (Filter::Linear,  true),
+    };
+    TextureOptions {
+        wrap : match sampler.wrap_s() {
+            WrappingMode::Repeat         => Wrap::Repeat,
+            WrappingMode::MirroredRepeat => Wrap::MirroredRepeat,
+            WrappingMode::ClampToEdge    => Wrap::ClampToEdge,
+        },
+        min_filter,
+        mag_filter : match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => Filter::Nearest,
+            _                        => Filter::Linear,
+        },
+        mipmaps,
+    }
+}
+
+// The 8 bit formats, which is what PNG and JPEG images decode to in practice
+fn to_rgba_image(data: &gltf::image::Data) -> Option<image::RgbaImage> {
+    use gltf::image::Format;
+
+    let pixels: Vec<u8> = match data.format {
+        Format::R8       => data.pixels.iter().flat_map(|&r| [r, r, r, 255]).collect(),
+        Format::R8G8     => data.pixels.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        Format::R8G8B8   => data.pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        Format::R8G8B8A8 => data.pixels.clone(),
+        _ => return None,
+    };
+    image::RgbaImage::from_raw(data.width, data.height, pixels)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut encoded = String::new();
+        for chunk in bytes.chunks(3) {
+            let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+        encoded
+    }
+
+    // A triangle with texture coordinates in a buffer embedded as a data URI. Mesh 0 has two
+    // primitives with different materials, mesh 1 has one. Node 1 is both a child of node 0 and
+    // of node 2, which a valid file wouldn't do.
+    fn gltf_json() -> String {
+        let positions = [0.0f32, 0.0, 0.0,  1.0, 0.0, 0.0,  0.0, 1.0, 0.0];
+        let texcoords = [0.0f32, 0.25,  1.0, 0.0,  0.0, 1.0];
+        let buffer: Vec<u8> = positions.iter().chain(&texcoords).flat_map(|value| value.to_le_bytes()).collect();
+        format!(r#"{{
+            "asset": {{ "version": "2.0" }},
+            "scene": 0,
+            "scenes": [ {{ "nodes": [0, 2] }} ],
+            "nodes": [
+                {{ "name": "Body", "mesh": 0, "children": [1], "translation": [1, 2, 3] }},
+                {{ "name": "Rotor", "mesh": 1, "rotation": [0, 0.7071068, 0, 0.7071068] }},
+                {{ "name": "Extra", "children": [1] }}
+            ],
+            "meshes": [
+                {{ "primitives": [
+                    {{ "attributes": {{ "POSITION": 0, "TEXCOORD_0": 1 }}, "material": 0 }},
+                    {{ "attributes": {{ "POSITION": 0, "TEXCOORD_0": 1 }}, "material": 1 }}
+                ] }},
+                {{ "primitives": [ {{ "attributes": {{ "POSITION": 0 }}, "material": 1 }} ] }}
+            ],
+            "materials": [
+                {{ "name": "Plastic", "pbrMetallicRoughness": {{ "baseColorFactor": [1, 0.5, 0, 1], "metallicFactor": 0, "roughnessFactor": 1 }} }},
+                {{ "name": "Glass", "alphaMode": "BLEND",
+                   "pbrMetallicRoughness": {{ "baseColorFactor": [0.2, 0.4, 0.6, 0.5], "metallicFactor": 1, "roughnessFactor": 0.5 }} }}
+            ],
+            "accessors": [
+                {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] }},
+                {{ "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2" }}
+            ],
+            "bufferViews": [
+                {{ "buffer": 0, "byteOffset": 0, "byteLength": 36 }},
+                {{ "buffer": 0, "byteOffset": 36, "byteLength": 24 }}
+            ],
+            "buffers": [ {{ "byteLength": {}, "uri": "data:application/octet-stream;base64,{}" }} ]
+        }}"#, buffer.len(), base64(&buffer))
+    }
+
+    // Written to a file of its own for every test, since they run at the same time
+    fn load_model(name: &str) -> GltfModel {
+        let path = std::env::temp_dir().join(format!("gloom-gltf-{}-{}.gltf", std::process::id(), name));
+        std::fs::write(&path, gltf_json()).unwrap();
+        GltfModel::load(&path).unwrap()
+    }
+
+    fn assert_close(actual: glm::Vec4, expected: glm::Vec4) {
+        assert!(glm::distance(&actual, &expected) < 1e-5, "{:?} is not {:?}", actual, expected);
+    }
+
+    fn channel(property: Property, interpolation: Interpolation, times: &[f32], values: &[glm::Vec4]) -> Channel {
+        Channel { node: 0, property, interpolation, times: times.to_vec(), values: values.to_vec() }
+    }
+
+    fn along_x(x: f32) -> glm::Vec4 {
+        glm::vec4(x, 0.0, 0.0, 0.0)
+    }
+
+    #[test]
+    fn step_holds_until_the_next_keyframe() {
+        let channel = channel(Property::Translation, Interpolation::Step, &[0.0, 1.0, 2.0], &[along_x(0.0), along_x(1.0), along_x(4.0)]);
+        assert_close(channel.sample(0.5), along_x(0.0));
+        assert_close(channel.sample(1.0), along_x(1.0));
+        assert_close(channel.sample(1.99), along_x(1.0));
+    }
+
+    #[test]
+    fn linear_interpolates_and_clamps() {
+        let channel = channel(Property::Translation, Interpolation::Linear, &[1.0, 2.0, 4.0], &[along_x(0.0), along_x(1.0), along_x(5.0)]);
+        assert_close(channel.sample(1.5), along_x(0.5));
+        assert_close(channel.sample(3.0), along_x(3.0));
+        assert_close(channel.sample(-1.0), along_x(0.0));
+        assert_close(channel.sample(10.0), along_x(5.0));
+    }
+
+    #[test]
+    fn rotations_are_slerped() {
+        let z = glm::vec3(0.0, 0.0, 1.0);
+        let keyframes = [glm::quat_identity().coords, glm::quat_angle_axis(std::f32::consts::FRAC_PI_2, &z).coords];
+        let channel = channel(Property::Rotation, Interpolation::Linear, &[0.0, 1.0], &keyframes);
+        assert_close(channel.sample(0.5), glm::quat_angle_axis(std::f32::consts::FRAC_PI_4, &z).coords);
+        assert!((channel.sample(0.25).norm() - 1.0).abs() < 1e-5, "slerp keeps the length");
+    }
+
+    #[test]
+    fn cubic_splines_scale_the_tangents_by_the_time_between_keyframes() {
+        // In-tangent, value and out-tangent of each keyframe, two seconds apart
+        let values = [along_x(9.0), along_x(0.0), along_x(0.5), along_x(0.25), along_x(1.0), along_x(9.0)];
+        let channel = channel(Property::Translation, Interpolation::CubicSpline, &[0.0, 2.0], &values);
+        // p0 (2t^3 - 3t^2 + 1) + 2 m0 (t^3 - 2t^2 + t) + p1 (-2t^3 + 3t^2) + 2 m1 (t^3 - t^2)
+        assert_close(channel.sample(1.0), along_x(0.5625));
+        assert_close(channel.sample(0.5), along_x(0.2734375));
+        assert_close(channel.sample(2.0), along_x(1.0));
+        assert_close(channel.sample(-1.0), along_x(0.0));
+        assert_close(channel.sample(3.0), along_x(1.0));
+    }
+
+    #[test]
+    fn primitives_become_children() {
+        let model = load_model("children");
+        let mut scene = SceneGraph::new();
+        let root = scene.root();
+        let instance = model.add_to(&mut scene, root);
+
+        let body = instance.nodes[0].unwrap();
+        let names: Vec<&str> = scene[body].children().iter().map(|&id| scene[id].name.as_str()).collect();
+        assert_eq!(names, ["Body.0", "Body.1", "Rotor"]);
+        assert!(scene[body].mesh.is_none());
+        assert_eq!(scene[body].position, glm::vec3(1.0, 2.0, 3.0));
+        assert!(scene[(body, 0)].mesh.is_some() && scene[(body, 1)].mesh.is_some());
+
+        // The rotor has one primitive, so the node draws it itself, and it's only added once
+        let rotor = instance.nodes[1].unwrap();
+        assert_eq!(scene[rotor].parent(), Some(body));
+        assert!(scene[rotor].mesh.is_some());
+        assert!(scene[rotor].orientation.is_some());
+        let extra = instance.nodes[2].unwrap();
+        assert!(scene[extra].children().is_empty());
+        assert_eq!(scene.descendants(instance.root).len(), 6);
+    }
+
+    #[test]
+    fn texture_coordinates_are_flipped() {
+        let model = load_model("texcoords");
+        assert_eq!(model.meshes[0][0].mesh.texcoords, [0.0, 0.75, 1.0, 1.0, 0.0, 0.0]);
+        assert!(model.meshes[1][0].mesh.texcoords.is_empty());
+    }
+
+    #[test]
+    fn materials_follow_metallic_and_roughness() {
+        let document = gltf::Gltf::from_slice(gltf_json().as_bytes()).unwrap();
+        let materials: Vec<Material> = document.materials().map(|material| to_material(&material)).collect();
+
+        // Rough plastic: the whole base colour is diffuse, with a faint white highlight
+        let plastic = &materials[0];
+        assert_eq!(plastic.name, "Plastic");
+        assert_eq!(plastic.diffuse, [1.0, 0.5, 0.0]);
+        assert_eq!(plastic.specular, [0.04, 0.04, 0.04]);
+        assert_eq!(plastic.shininess, 0.0);
+        assert_eq!(plastic.dissolve, 1.0);
+
+        // Metal: no diffuse, a highlight in the base colour, sharper the smoother it is
+        let glass = &materials[1];
+        assert_eq!(glass.diffuse, [0.0, 0.0, 0.0]);
+        assert_eq!(glass.specular, [0.2, 0.4, 0.6]);
+        assert_eq!(glass.shininess, 30.0);
+        assert_eq!(glass.dissolve, 0.5);
+
+        let model = load_model("materials");
+        assert_eq!(model.meshes[0][1].mesh.material, *glass);
+    }
+}
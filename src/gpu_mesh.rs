@@ -0,0 +1,319 @@
+extern crate nalgebra_glm as glm;
+
+use std::cell::{Cell, RefCell};
+
+use crate::bounds::Bounds;
+use crate::mesh::Mesh;
+use crate::shader::Shader;
+use crate::{offset, size_of};
+
+// How the attributes of a vertex are laid out in the vertex buffer. All attributes of a vertex
+// are stored next to each other (interleaved), in the order they are listed.
+//
+// The attributes are matched with the inputs of the shader by name, so a vertex shader using
+// the standard format declares
+//     in vec3 position;
+//     in vec4 color;
+//     in vec3 normal;
+//     in vec2 texcoord;
+//     in vec4 tangent;
+#[derive(Clone, Debug)]
+pub struct VertexFormat {
+    pub attributes : Vec<VertexAttribute>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VertexAttribute {
+    pub name       : &'static str,      // The name of the shader input, and which data of the mesh to use
+    pub components : i32,               // e.g. 3 for a vec3
+    pub gl_type    : gl::types::GLenum, // How each component is stored, e.g. gl::FLOAT
+    pub normalized : bool,              // Whether integers are mapped to [0, 1] (or [-1, 1] if signed)
+}
+
+impl VertexAttribute {
+    pub fn new(name: &'static str, components: i32, gl_type: gl::types::GLenum, normalized: bool) -> VertexAttribute {
+        VertexAttribute { name, components, gl_type, normalized }
+    }
+
+    pub fn float(name: &'static str, components: i32) -> VertexAttribute {
+        VertexAttribute::new(name, components, gl::FLOAT, false)
+    }
+
+    fn component_size(&self) -> i32 {
+        match self.gl_type {
+            gl::FLOAT                     => size_of::<f32>(),
+            gl::BYTE | gl::UNSIGNED_BYTE  => size_of::<u8>(),
+            gl::SHORT | gl::UNSIGNED_SHORT => size_of::<u16>(),
+            t => panic!("Unsupported vertex attribute type 0x{:X}", t),
+        }
+    }
+
+    // Rounded up to whole 4 bytes, so that every attribute is aligned
+    fn size(&self) -> i32 {
+        (self.components * self.component_size() + 3) / 4 * 4
+    }
+
+    // Writes one component, converted from a float to the attribute type
+    fn write_component(&self, bytes: &mut [u8], value: f32) {
+        let scale = |max: f32| if self.normalized { (value.clamp(-1.0, 1.0) * max).round() } else { value };
+        match self.gl_type {
+            gl::FLOAT          => bytes.copy_from_slice(&value.to_ne_bytes()),
+            gl::UNSIGNED_BYTE  => bytes.copy_from_slice(&[scale(255.0) as u8]),
+            gl::BYTE           => bytes.copy_from_slice(&(scale(127.0) as i8).to_ne_bytes()),
+            gl::UNSIGNED_SHORT => bytes.copy_from_slice(&(scale(65535.0) as u16).to_ne_bytes()),
+            gl::SHORT          => bytes.copy_from_slice(&(scale(32767.0) as i16).to_ne_bytes()),
+            t => panic!("Unsupported vertex attribute type 0x{:X}", t),
+        }
+    }
+}
+
+impl VertexFormat {
+    // The size of a vertex in bytes
+    pub fn stride(&self) -> i32 {
+        self.attributes.iter().map(VertexAttribute::size).sum()
+    }
+
+    // Where each attribute starts within a vertex, in bytes
+    pub fn offsets(&self) -> Vec<u32> {
+        self.attributes.iter()
+            .scan(0, |offset, attribute| {
+                let start = *offset;
+                *offset += attribute.size() as u32;
+                Some(start)
+            })
+            .collect()
+    }
+
+    // Lays out the vertices of `mesh` in this format. Data the mesh doesn't have is left as 0.
+    pub fn interleave(&self, mesh: &Mesh) -> Vec<u8> {
+        let stride = self.stride() as usize;
+        let vertex_count = mesh.vertices.len() / 3;
+        let mut bytes = vec![0u8; stride * vertex_count];
+
+        for (attribute, offset) in self.attributes.iter().zip(self.offsets()) {
+            let (data, data_components) = mesh_attribute(mesh, attribute.name);
+            let component_size = attribute.component_size() as usize;
+            for vertex in 0..vertex_count {
+                for component in 0..(attribute.components as usize).min(data_components) {
+                    let value = match data.get(vertex * data_components + component) {
+                        Some(&value) => value,
+                        None => continue,
+                    };
+                    let start = vertex * stride + offset as usize + component * component_size;
+                    attribute.write_component(&mut bytes[start..start + component_size], value);
+                }
+            }
+        }
+        bytes
+    }
+}
+
+impl Default for VertexFormat {
+    // Everything a `Mesh` has, as floats
+    fn default() -> VertexFormat {
+        VertexFormat {
+            attributes: vec![
+                VertexAttribute::float("position", 3),
+                VertexAttribute::float("color",    4),
+                VertexAttribute::float("normal",   3),
+                VertexAttribute::float("texcoord", 2),
+                VertexAttribute::float("tangent",  4),
+            ],
+        }
+    }
+}
+
+// The data of a mesh belonging to an attribute, and how many components it has per vertex
+fn mesh_attribute<'a>(mesh: &'a Mesh, name: &str) -> (&'a [f32], usize) {
+    match name {
+        "position" => (&mesh.vertices,  3),
+        "color"    => (&mesh.colors,    4),
+        "normal"   => (&mesh.normals,   3),
+        "texcoord" => (&mesh.texcoords, 2),
+        "tangent"  => (&mesh.tangents,  4),
+        _ => panic!("Meshes have no vertex attribute called {}", name),
+    }
+}
+
+
+// A mesh uploaded to the GPU. It owns its vertex array and buffers, and deletes them when
+// dropped, so it must be dropped while the OpenGL context it was created in is still current.
+//
+// The attributes are pointed to the inputs of the shader it is drawn with, and pointed again if
+// it is drawn with a shader which has them at other locations, e.g. after the shader has been
+// reloaded.
+pub struct GpuMesh {
+    vao_id             : u32,
+    vertex_buffer_id   : u32,
+    index_buffer_id    : u32,
+    format             : VertexFormat,
+    locations          : RefCell<Vec<i32>>,   // Where each attribute is currently enabled, or -1
+    index_count        : i32,
+    primitive          : gl::types::GLenum,
+    bounds             : Bounds,              // Those of the mesh it was uploaded from
+    instance_buffer_id : Cell<u32>,           // The model matrices for `draw_instanced`, 0 until then
+}
+
+// The per-instance attribute of the shaders `draw_instanced` puts the model matrices in
+const INSTANCE_ATTRIBUTE : &str = "model_mat";
+
+impl GpuMesh {
+    // Uploads a mesh of triangles in the default vertex format
+    pub unsafe fn new(mesh: &Mesh) -> GpuMesh {
+        GpuMesh::with_format(mesh, VertexFormat::default(), gl::TRIANGLES)
+    }
+
+    // Uploads a mesh in the given format, whose indices describe `primitive`s, e.g. `gl::LINES`
+    pub unsafe fn with_format(mesh: &Mesh, format: VertexFormat, primitive: gl::types::GLenum) -> GpuMesh {
+        let mut vao_id = 0;
+        gl::GenVertexArrays(1, &mut vao_id);
+        gl::BindVertexArray(vao_id);
+
+        let vertices = format.interleave(mesh);
+        let mut vertex_buffer_id = 0;
+        gl::GenBuffers(1, &mut vertex_buffer_id);
+        gl::BindBuffer(gl::ARRAY_BUFFER, vertex_buffer_id);
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            vertices.len() as isize,
+            vertices.as_ptr().cast(),
+            gl::STATIC_DRAW
+        );
+
+        // The index buffer is part of the vertex array state
+        let mut index_buffer_id = 0;
+        gl::GenBuffers(1, &mut index_buffer_id);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, index_buffer_id);
+        gl::BufferData(
+            gl::ELEMENT_ARRAY_BUFFER,
+            std::mem::size_of_val(mesh.indices.as_slice()) as isize,
+            mesh.indices.as_ptr().cast(),
+            gl::STATIC_DRAW
+        );
+
+        // Leave no VAO bound, so nothing else changes this one by accident
+        gl::BindVertexArray(0);
+
+        GpuMesh {
+            vao_id,
+            vertex_buffer_id,
+            index_buffer_id,
+            locations: RefCell::new(vec![-1; format.attributes.len()]),
+            format,
+            index_count: mesh.indices.len() as i32,
+            primitive,
+            bounds: mesh.bounds,
+            instance_buffer_id: Cell::new(0),
+        }
+    }
+
+    pub fn vao_id(&self) -> u32 {
+        self.vao_id
+    }
+
+    pub fn index_count(&self) -> i32 {
+        self.index_count
+    }
+
+    pub fn primitive(&self) -> gl::types::GLenum {
+        self.primitive
+    }
+
+    pub fn format(&self) -> &VertexFormat {
+        &self.format
+    }
+
+    pub fn bounds(&self) -> &Bounds {
+        &self.bounds
+    }
+
+    // Draws the mesh with `shader`, which should be active
+    pub unsafe fn draw(&self, shader: &Shader) {
+        gl::BindVertexArray(self.vao_id);
+        self.match_attributes(shader);
+        gl::DrawElements(self.primitive, self.index_count, gl::UNSIGNED_INT, std::ptr::null());
+    }
+
+    // Draws the mesh once for each of `model_matrices`, with one draw call. The shader should be
+    // active and built with INSTANCED defined, so that it takes the model matrix as the
+    // attribute `model_mat` instead of as a uniform. The matrices are uploaded on every call.
+    pub unsafe fn draw_instanced(&self, shader: &Shader, model_matrices: &[glm::Mat4]) {
+        gl::BindVertexArray(self.vao_id);
+        self.match_attributes(shader);
+
+        if self.instance_buffer_id.get() == 0 {
+            let mut instance_buffer_id = 0;
+            gl::GenBuffers(1, &mut instance_buffer_id);
+            self.instance_buffer_id.set(instance_buffer_id);
+        }
+        gl::BindBuffer(gl::ARRAY_BUFFER, self.instance_buffer_id.get());
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            std::mem::size_of_val(model_matrices) as isize,
+            model_matrices.as_ptr().cast(),
+            gl::STREAM_DRAW
+        );
+
+        // A mat4 attribute takes up four locations, one for each column, and moves on to the
+        // next matrix for every instance instead of for every vertex
+        let location = shader.get_attribute_location(INSTANCE_ATTRIBUTE);
+        let columns = if location < 0 { 0..0 } else { location as u32..location as u32 + 4 };
+        for (column, location) in columns.clone().enumerate() {
+            gl::VertexAttribPointer(location, 4, gl::FLOAT, gl::FALSE, size_of::<glm::Mat4>(), offset::<glm::Vec4>(column as u32));
+            gl::EnableVertexAttribArray(location);
+            gl::VertexAttribDivisor(location, 1);
+        }
+
+        gl::DrawElementsInstanced(self.primitive, self.index_count, gl::UNSIGNED_INT, std::ptr::null(), model_matrices.len() as i32);
+
+        // Leave them as `match_attributes` expects, in case another shader has a vertex attribute there
+        for location in columns {
+            gl::VertexAttribDivisor(location, 0);
+            gl::DisableVertexAttribArray(location);
+        }
+    }
+
+    // Points every attribute to the input of the same name in the shader, if it has one.
+    // Expects the vertex array to be bound.
+    unsafe fn match_attributes(&self, shader: &Shader) {
+        let wanted: Vec<i32> = self.format.attributes.iter()
+            .map(|attribute| shader.get_attribute_location(attribute.name))
+            .collect();
+        let mut locations = self.locations.borrow_mut();
+        if *locations == wanted {
+            return;
+        }
+
+        for &location in locations.iter().filter(|&&l| l >= 0) {
+            gl::DisableVertexAttribArray(location as u32);
+        }
+        gl::BindBuffer(gl::ARRAY_BUFFER, self.vertex_buffer_id);
+        let stride = self.format.stride();
+        for ((attribute, byte_offset), &location) in self.format.attributes.iter().zip(self.format.offsets()).zip(&wanted) {
+            if location < 0 {
+                continue;
+            }
+            gl::VertexAttribPointer(
+                location as u32,
+                attribute.components,
+                attribute.gl_type,
+                if attribute.normalized { gl::TRUE } else { gl::FALSE },
+                stride,
+                offset::<u8>(byte_offset)
+            );
+            gl::EnableVertexAttribArray(location as u32);
+        }
+        *locations = wanted;
+    }
+}
+
+impl Drop for GpuMesh {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteVertexArrays(1, &self.vao_id);
+            gl::DeleteBuffers(1, &self.vertex_buffer_id);
+            gl::DeleteBuffers(1, &self.index_buffer_id);
+            gl::DeleteBuffers(1, &self.instance_buffer_id.get());
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::path::PathBuf;
+
+// Rendering without a window, for machines without a display such as our CI boxes.
+//
+// On Linux we ask EGL for a "surfaceless" display, which Mesa provides even when there is no
+// GPU or X server at all by falling back on its software rasterizer (llvmpipe). Since there is no
+// window to draw into, the scene is rendered into a framebuffer object which is read back and
+// written to a PNG.
+
+pub const USAGE: &str = "\
+Usage: gloom-rs [--render-frame t=<seconds> --out <file.png> [--size <width>x<height>] [--backend <gl|cpu>]
+                [--helicopters <count>]]
+
+    --render-frame t=<seconds>   Render a single frame at the given time, without a window
+    --out <file.png>             Where to write the rendered frame
+    --size <width>x<height>      Size of the rendered frame (default 800x600)
+    --backend <gl|cpu>           Render with OpenGL (default), or with the software rasterizer
+    --helicopters <count>        How many helicopters to fly (default 5)";
+
+// Command line options
+
+pub enum Backend {
+    OpenGl,     // Through an EGL surfaceless context
+    Software,   // Through the CPU rasterizer in `software.rs`, needs no OpenGL at all
+}
+
+pub struct RenderOptions {
+    pub time        : f32,
+    pub output      : PathBuf,
+    pub width       : u32,
+    pub height      : u32,
+    pub backend     : Backend,
+    pub helicopters : usize,
+}
+
+impl RenderOptions {
+    // Returns `Ok(None)` if no headless rendering was asked for, in which case we open a window
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<RenderOptions>, String> {
+        let mut time = None;
+        let mut output = None;
+        let mut size = (crate::INITIAL_SCREEN_W, crate::INITIAL_SCREEN_H);
+        let mut backend = Backend::OpenGl;
+        let mut helicopters = crate::HELICOPTER_COUNT;
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("Missing value for {}", name));
+            match arg.as_str() {
+                "--render-frame" => {
+                    let t = value("--render-frame")?;
+                    let t = t.strip_prefix("t=").unwrap_or(&t);
+                    time = Some(t.parse::<f32>().map_err(|_| format!("Invalid time: {}", t))?);
+                }
+                "--out" => {
+                    output = Some(PathBuf::from(value("--out")?));
+                }
+                "--size" => {
+                    let s = value("--size")?;
+                    size = parse_size(&s).ok_or(format!("Invalid size: {}, expected e.g. 800x600", s))?;
+                }
+                "--backend" => {
+                    backend = match value("--backend")?.as_str() {
+                        "gl"  => Backend::OpenGl,
+                        "cpu" => Backend::Software,
+                        b     => return Err(format!("Unknown backend: {}, expected gl or cpu", b)),
+                    };
+                }
+                "--helicopters" => {
+                    let count = value("--helicopters")?;
+                    helicopters = count.parse().map_err(|_| format!("Invalid helicopter count: {}", count))?;
+                }
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+
+        match (time, output) {
+            (Some(time), Some(output)) => Ok(Some(RenderOptions {
+                time,
+                output,
+                width  : size.0,
+                height : size.1,
+                backend,
+                helicopters,
+            })),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err("--render-frame requires --out".to_string()),
+            (None, Some(_)) => Err("--out is only used together with --render-frame".to_string()),
+        }
+    }
+}
+
+fn parse_size(s: &str) -> Option<(u32, u32)> {
+    let (w, h) = s.split_once('x')?;
+    let (w, h) = (w.parse().ok()?, h.parse().ok()?);
+    if w == 0 || h == 0 {
+        return None;
+    }
+    Some((w, h))
+}
+
+
+// OpenGL context without a window
+
+#[cfg(target_os = "linux")]
+pub struct HeadlessContext {
+    egl     : khronos_egl::DynamicInstance<khronos_egl::EGL1_5>,
+    display : khronos_egl::Display,
+    context : khronos_egl::Context,
+}
+
+#[cfg(target_os = "linux")]
+impl HeadlessContext {
+    // Creates an OpenGL 4.3 core context, makes it current and loads the function pointers
+    pub fn new() -> Result<HeadlessContext, String> {
+        use khronos_egl as egl;
+        // From EGL_MESA_platform_surfaceless, which the bindings don't know about
+        const PLATFORM_SURFACELESS_MESA: egl::Enum = 0x31DD;
+
+        let egl = unsafe { egl::DynamicInstance::<egl::EGL1_5>::load_required() }
+            .map_err(|e| format!("Failed to load libEGL: {}", e))?;
+
+        let display = egl.get_platform_display(PLATFORM_SURFACELESS_MESA, egl::DEFAULT_DISPLAY, &[egl::ATTRIB_NONE])
+            .map_err(|e| format!("Failed to get a surfaceless EGL display: {}", e))?;
+        egl.initialize(display)
+            .map_err(|e| format!("Failed to initialize EGL: {}", e))?;
+        egl.bind_api(egl::OPENGL_API)
+            .map_err(|e| format!("Failed to bind the OpenGL API: {}", e))?;
+
+        let config = egl.choose_first_config(display, &[
+                egl::SURFACE_TYPE,    egl::PBUFFER_BIT,
+                egl::RENDERABLE_TYPE, egl::OPENGL_BIT,
+                egl::NONE,
+            ])
+            .map_err(|e| format!("Failed to choose an EGL config: {}", e))?
+            .ok_or("No EGL config supports OpenGL")?;
+
+        let context = egl.create_context(display, config, None, &[
+                egl::CONTEXT_MAJOR_VERSION,       4,
+                egl::CONTEXT_MINOR_VERSION,       3,
+                egl::CONTEXT_OPENGL_PROFILE_MASK, egl::CONTEXT_OPENGL_CORE_PROFILE_BIT,
+                egl::NONE,
+            ])
+            .map_err(|e| format!("Failed to create an OpenGL 4.3 context: {}", e))?;
+        egl.make_current(display, None, None, Some(context))
+            .map_err(|e| format!("Failed to make the context current: {}", e))?;
+
+        gl::load_with(|symbol| {
+            egl.get_proc_address(symbol).map_or(std::ptr::null(), |f| f as *const _)
+        });
+
+        Ok(HeadlessContext { egl, display, context })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for HeadlessContext {
+    fn drop(&mut self) {
+        let _ = self.egl.make_current(self.display, None, None, None);
+        let _ = self.egl.destroy_context(self.display, self.context);
+        let _ = self.egl.terminate(self.display);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct HeadlessContext;
+
+#[cfg(not(target_os = "linux"))]
+impl HeadlessContext {
+    pub fn new() -> Result<HeadlessContext, String> {
+        Err("Headless rendering is only supported on Linux".to_string())
+    }
+}
+
+
+// Framebuffer object to render into, in place of the window
+
+pub struct Framebuffer {
+    fbo_id   : u32,
+    color_id : u32,
+    depth_id : u32,
+    width    : u32,
+    height   : u32,
+}
+
+impl Framebuffer {
+    pub unsafe fn new(width: u32, height: u32) -> Result<Framebuffer, String> {
+        let mut fbo_id = 0;
+        gl::GenFramebuffers(1, &mut fbo_id);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, fbo_id);
+
+        let mut color_id = 0;
+        gl::GenRenderbuffers(1, &mut color_id);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, color_id);
+        gl::RenderbufferStorage(gl::RENDERBUFFER, gl::RGBA8, width as i32, height as i32);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, color_id);
+
+        let mut depth_id = 0;
+        gl::GenRenderbuffers(1, &mut depth_id);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, depth_id);
+        gl::RenderbufferStorage(gl::RENDERBUFFER, gl::DEPTH_COMPONENT24, width as i32, height as i32);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, depth_id);
+
+        let framebuffer = Framebuffer { fbo_id, color_id, depth_id, width, height };
+        let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(format!("Framebuffer is incomplete, status 0x{:X}", status));
+        }
+        Ok(framebuffer)
+    }
+
+    // Direct all following draw calls into this framebuffer
+    pub unsafe fn bind(&self) {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, self.fbo_id);
+        gl::Viewport(0, 0, self.width as i32, self.height as i32);
+    }
+
+    // Copies the rendered frame back from the GPU. OpenGL stores the bottom row first, while
+    // images store the top row first, so the rows are flipped on the way.
+    pub unsafe fn read_pixels(&self) -> image::RgbaImage {
+        let mut pixels = vec![0u8; (self.width * self.height * 4) as usize];
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo_id);
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        gl::ReadPixels(
+            0, 0,
+            self.width as i32, self.height as i32,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            pixels.as_mut_ptr() as *mut std::ffi::c_void,
+        );
+
+        let image = image::RgbaImage::from_raw(self.width, self.height, pixels)
+            .expect("Pixel buffer has the wrong size");
+        image::imageops::flip_vertical(&image)
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteRenderbuffers(1, &self.depth_id);
+            gl::DeleteRenderbuffers(1, &self.color_id);
+            gl::DeleteFramebuffers(1, &self.fbo_id);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_for(args: Vec<&str>) -> String {
+        match RenderOptions::from_args(args.into_iter().map(String::from)) {
+            Ok(_)      => panic!("The arguments should have been refused"),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn unknown_flags_are_errors() {
+        assert_eq!(error_for(vec!["--width", "x"]), "Unknown argument: --width");
+        assert_eq!(error_for(vec!["--render-frame", "t=1", "--out", "a.png", "-v"]), "Unknown argument: -v");
+    }
+
+    #[test]
+    fn missing_values_are_errors() {
+        assert_eq!(error_for(vec!["--render-frame", "t=1", "--out"]), "Missing value for --out");
+        assert_eq!(error_for(vec!["--size"]), "Missing value for --size");
+    }
+
+    #[test]
+    fn bad_numbers_are_errors() {
+        assert_eq!(error_for(vec!["--render-frame", "t=soon"]), "Invalid time: soon");
+        assert_eq!(error_for(vec!["--size", "800xx"]), "Invalid size: 800xx, expected e.g. 800x600");
+        assert_eq!(error_for(vec!["--size", "0x600"]), "Invalid size: 0x600, expected e.g. 800x600");
+        assert_eq!(error_for(vec!["--helicopters", "-1"]), "Invalid helicopter count: -1");
+    }
+
+    #[test]
+    fn good_arguments_are_parsed() {
+        let args = vec!["--render-frame", "t=2.5", "--out", "a.png", "--size", "64x48", "--helicopters", "12"];
+        let options = RenderOptions::from_args(args.into_iter().map(String::from)).unwrap().unwrap();
+        assert_eq!((options.time, options.width, options.height, options.helicopters), (2.5, 64, 48, 12));
+        assert_eq!(options.output, PathBuf::from("a.png"));
+        assert!(RenderOptions::from_args(std::iter::empty()).unwrap().is_none());
+    }
+}
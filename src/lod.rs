@@ -0,0 +1,595 @@
+extern crate nalgebra_glm as glm;
+
+use std::cell::{Cell, OnceCell};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::AddAssign;
+use std::rc::Rc;
+
+use crate::bounds::Bounds;
+use crate::gpu_mesh::GpuMesh;
+use crate::mesh::Mesh;
+
+// Levels of detail: coarser versions of a mesh, drawn instead of it when it only covers a small
+// part of the screen.
+//
+// Meshes are simplified by collapsing edges one at a time, cheapest first, as in "Surface
+// Simplification Using Quadric Error Metrics" by Garland and Heckbert (1997). Every point of the
+// surface has a quadric, which measures the summed squared distance to the planes of the
+// triangles around it, and the cost of moving a point is what its quadric and that of the point
+// it moves onto say about the new position.
+//
+// An edge is collapsed by moving one end onto the other (a half-edge collapse), so the vertices
+// of a simplified mesh are a subset of the original ones, with their texture coordinates, colours
+// and so on unchanged. Vertices at the same position, e.g. along a texture seam, move together,
+// and only along the seam. Collapses which would turn triangles over are skipped, and points on
+// the border of the mesh only move along the border.
+
+// How much more moving a point away from the border costs than moving it off the surface
+const BORDER_WEIGHT : f64 = 10.0;
+
+// Collapses may tilt the triangles around a point by at most this much, as the cosine of the angle
+const MIN_NORMAL_COS : f32 = 0.2;
+
+// A copy of `mesh` with at most `triangle_count` triangles, or as close to it as the mesh can get
+// without changing shape too much. Vertices which are no longer used are left out.
+pub fn simplify(mesh: &Mesh, triangle_count: usize) -> Mesh {
+    let mut simplifier = Simplifier::new(mesh);
+    simplifier.run(triangle_count);
+    simplifier.into_mesh()
+}
+
+
+// LOD chains
+
+// How to build a LOD chain, and when to draw each level
+#[derive(Clone, Debug)]
+pub struct LodSettings {
+    pub levels : Vec<LodLevelSettings>,  // From the finest to the coarsest
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LodLevelSettings {
+    pub triangle_ratio  : f32,   // How many of the triangles of the full mesh to keep, 1 for all of them
+    pub min_screen_size : f32,   // Drawn while the mesh is at least this tall, as a fraction of the screen height
+}
+
+impl Default for LodSettings {
+    fn default() -> LodSettings {
+        let level = |triangle_ratio, min_screen_size| LodLevelSettings { triangle_ratio, min_screen_size };
+        LodSettings {
+            levels: vec![
+                level(1.0,  0.5),
+                level(0.5,  0.2),
+                level(0.25, 0.08),
+                level(0.1,  0.0),
+            ],
+        }
+    }
+}
+
+// A mesh along with simplified versions of it. The levels are only simplified the first time
+// they are asked for, see `mesh`, so that levels which are never drawn cost nothing.
+pub struct LodChain {
+    mesh   : Rc<Mesh>,          // The full mesh
+    levels : Vec<LodLevel>,     // From the finest to the coarsest
+    upload : Cell<bool>,        // Whether levels are uploaded as they are asked for, see `upload`
+}
+
+struct LodLevel {
+    triangle_count  : usize,
+    min_screen_size : f32,                      // See `LodLevelSettings`
+    mesh            : OnceCell<Rc<Mesh>>,       // Set by `LodChain::mesh`
+    gpu_mesh        : OnceCell<Rc<GpuMesh>>,    // Set by `LodChain::gpu_mesh`
+}
+
+impl LodChain {
+    pub fn new(mesh: &Rc<Mesh>, settings: &LodSettings) -> LodChain {
+        assert!(!settings.levels.is_empty(), "A LOD chain needs at least one level");
+        let full_count = mesh.indices.len() / 3;
+        let levels = settings.levels.iter()
+            .map(|level| LodLevel {
+                triangle_count  : (full_count as f32 * level.triangle_ratio).round() as usize,
+                min_screen_size : level.min_screen_size,
+                mesh            : OnceCell::new(),
+                gpu_mesh        : OnceCell::new(),
+            })
+            .collect();
+        LodChain { mesh: Rc::clone(mesh), levels, upload: Cell::new(false) }
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.levels.len()
+    }
+
+    // The mesh of `level`, simplified from the level before it the first time it is asked for.
+    // A level with at least as many triangles as the one before it, e.g. one with a ratio of 1,
+    // shares its mesh rather than copying it.
+    pub fn mesh(&self, level: usize) -> &Rc<Mesh> {
+        let lod = &self.levels[level];
+        lod.mesh.get_or_init(|| {
+            let previous = if level == 0 { &self.mesh } else { self.mesh(level - 1) };
+            if lod.triangle_count >= previous.indices.len() / 3 {
+                Rc::clone(previous)
+            } else {
+                Rc::new(simplify(previous, lod.triangle_count))
+            }
+        })
+    }
+
+    // Whether `level` has been simplified yet
+    pub fn is_built(&self, level: usize) -> bool {
+        self.levels[level].mesh.get().is_some()
+    }
+
+    // The mesh of `level` on the GPU, or None before `upload`. Levels sharing a mesh share the
+    // upload as well.
+    pub fn gpu_mesh(&self, level: usize) -> Option<&Rc<GpuMesh>> {
+        if !self.upload.get() {
+            return None;
+        }
+        let mesh = self.mesh(level);
+        Some(self.levels[level].gpu_mesh.get_or_init(|| {
+            if level > 0 && Rc::ptr_eq(mesh, self.mesh(level - 1)) {
+                Rc::clone(self.gpu_mesh(level - 1).unwrap())
+            } else {
+                // Safety: `upload` requires an active OpenGL context whenever levels are asked for
+                Rc::new(unsafe { GpuMesh::new(mesh) })
+            }
+        }))
+    }
+
+    // Uploads every level to the GPU the first time it is asked for from now on, which requires
+    // an active OpenGL context whenever that happens
+    pub unsafe fn upload(&self) {
+        self.upload.set(true);
+    }
+
+    // How tall the mesh looks through `view_projection` when placed by `model_matrix`, as a
+    // fraction of the screen height, measured by the sphere around it. Infinite when the camera
+    // is inside the sphere.
+    pub fn screen_size(&self, model_matrix: &glm::Mat4, view_projection: &glm::Mat4) -> f32 {
+        let sphere = self.mesh.bounds.sphere.transformed(model_matrix);
+        let center = glm::vec4(sphere.center.x, sphere.center.y, sphere.center.z, 1.0);
+        let radius = sphere.radius;
+        // w is the distance in front of the camera, and the Y row scales heights onto the screen
+        let distance = (view_projection * center).w;
+        let vertical_scale = glm::vec3(view_projection[(1, 0)], view_projection[(1, 1)], view_projection[(1, 2)]).norm();
+        if distance <= radius {
+            f32::INFINITY
+        } else {
+            radius * vertical_scale / distance
+        }
+    }
+
+    // The coarsest level meant for a mesh of this size on screen
+    pub fn select(&self, screen_size: f32) -> usize {
+        self.levels.iter()
+            .position(|level| screen_size >= level.min_screen_size)
+            .unwrap_or(self.levels.len() - 1)
+    }
+}
+
+
+// Simplification
+
+// The summed squared distance to a set of planes, as a symmetric 4x4 matrix of which the upper
+// triangle is stored, row by row
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    // For the plane through `point` with the unit normal `normal`
+    fn plane(normal: &glm::Vec3, point: &glm::Vec3, weight: f64) -> Quadric {
+        let (a, b, c) = (normal.x as f64, normal.y as f64, normal.z as f64);
+        let d = -(a * point.x as f64 + b * point.y as f64 + c * point.z as f64);
+        Quadric([a * a, a * b, a * c, a * d, b * b, b * c, b * d, c * c, c * d, d * d].map(|value| value * weight))
+    }
+
+    fn error(&self, point: &glm::Vec3) -> f64 {
+        let [aa, ab, ac, ad, bb, bc, bd, cc, cd, dd] = self.0;
+        let (x, y, z) = (point.x as f64, point.y as f64, point.z as f64);
+        aa * x * x + bb * y * y + cc * z * z + dd
+            + 2.0 * (ab * x * y + ac * x * z + bc * y * z + ad * x + bd * y + cd * z)
+    }
+}
+
+impl AddAssign for Quadric {
+    fn add_assign(&mut self, other: Quadric) {
+        self.0.iter_mut().zip(other.0.iter()).for_each(|(value, other)| *value += other);
+    }
+}
+
+// A position on the surface, shared by all vertices there
+struct Point {
+    position  : glm::Vec3,
+    vertices  : Vec<u32>,    // The vertices at this position
+    triangles : Vec<usize>,  // The triangles around it, including some which have been removed since
+    quadric   : Quadric,
+    on_border : bool,        // Whether it's at the end of an edge with only one triangle, or more than two
+    removed   : bool,        // Moved onto another point
+}
+
+struct Simplifier<'a> {
+    mesh           : &'a Mesh,
+    triangles      : Vec<[u32; 3]>,
+    alive          : Vec<bool>,
+    triangle_count : usize,       // How many are still alive
+    point_of       : Vec<usize>,  // The point of every vertex
+    points         : Vec<Point>,
+}
+
+impl<'a> Simplifier<'a> {
+    fn new(mesh: &'a Mesh) -> Simplifier<'a> {
+        let vertex_count = mesh.vertex_count();
+        let position = |vertex: u32| glm::make_vec3(&mesh.vertices[3 * vertex as usize..3 * vertex as usize + 3]);
+
+        // + 0.0 turns -0.0 into 0.0
+        let mut point_at: HashMap<[u32; 3], usize> = HashMap::new();
+        let mut points: Vec<Point> = vec![];
+        let mut point_of = Vec::with_capacity(vertex_count);
+        for vertex in 0..vertex_count as u32 {
+            let p = position(vertex);
+            let key = [(p.x + 0.0).to_bits(), (p.y + 0.0).to_bits(), (p.z + 0.0).to_bits()];
+            let point = *point_at.entry(key).or_insert_with(|| {
+                points.push(Point {
+                    position  : p,
+                    vertices  : vec![],
+                    triangles : vec![],
+                    quadric   : Quadric::default(),
+                    on_border : false,
+                    removed   : false,
+                });
+                points.len() - 1
+            });
+            points[point].vertices.push(vertex);
+            point_of.push(point);
+        }
+
+        // Triangles without an area have no plane, and only get in the way
+        let triangles: Vec<[u32; 3]> = mesh.indices.chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .filter(|&[a, b, c]| {
+                let (pa, pb, pc) = (point_of[a as usize], point_of[b as usize], point_of[c as usize]);
+                pa != pb && pb != pc && pc != pa
+                    && (position(b) - position(a)).cross(&(position(c) - position(a))).norm() > 0.0
+            })
+            .collect();
+
+        let mut edges: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for (t, triangle) in triangles.iter().enumerate() {
+            let normal = (position(triangle[1]) - position(triangle[0])).cross(&(position(triangle[2]) - position(triangle[0])));
+            let area = normal.norm() as f64 / 2.0;
+            let quadric = Quadric::plane(&normal.normalize(), &position(triangle[0]), area);
+            for corner in 0..3 {
+                let (a, b) = (point_of[triangle[corner] as usize], point_of[triangle[(corner + 1) % 3] as usize]);
+                points[a].triangles.push(t);
+                points[a].quadric += quadric;
+                edges.entry((a.min(b), a.max(b))).or_default().push(t);
+            }
+        }
+
+        // Planes through the border edges, at right angles to the surface, to keep the border where it is
+        for (&(a, b), edge_triangles) in &edges {
+            if edge_triangles.len() == 2 {
+                continue;
+            }
+            points[a].on_border = true;
+            points[b].on_border = true;
+            if let &[t] = edge_triangles.as_slice() {
+                let [v0, v1, v2] = triangles[t];
+                let face_normal = (position(v1) - position(v0)).cross(&(position(v2) - position(v0))).normalize();
+                let along = points[b].position - points[a].position;
+                let normal = along.cross(&face_normal).normalize();
+                let quadric = Quadric::plane(&normal, &points[a].position, BORDER_WEIGHT * along.norm_squared() as f64);
+                points[a].quadric += quadric;
+                points[b].quadric += quadric;
+            }
+        }
+
+        Simplifier {
+            mesh,
+            alive          : vec![true; triangles.len()],
+            triangle_count : triangles.len(),
+            triangles,
+            point_of,
+            points,
+        }
+    }
+
+    fn run(&mut self, target: usize) {
+        // Cheapest first
+        let mut queue = BinaryHeap::new();
+        for point in 0..self.points.len() {
+            self.push_collapses(&mut queue, point, |neighbour| neighbour > point);
+        }
+
+        while self.triangle_count > target {
+            let Reverse((cost_bits, from, to)) = match queue.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            // If the cost has changed since, an entry with the new cost has been queued as well
+            if self.points[from].removed || self.points[to].removed || self.cost(from, to) != cost_bits {
+                continue;
+            }
+            // The points around may have changed since the collapse was queued, so whether it
+            // can be done is only decided now
+            if self.can_collapse(from, to) {
+                self.collapse(from, to, &mut queue);
+            }
+        }
+    }
+
+    // Queues moving `point` onto each of its neighbours which `include`s, and them onto it
+    fn push_collapses<F: Fn(usize) -> bool>(&self, queue: &mut BinaryHeap<Reverse<(u64, usize, usize)>>, point: usize, include: F) {
+        for neighbour in self.neighbours(point).into_iter().filter(|&n| include(n)) {
+            for (from, to) in [(point, neighbour), (neighbour, point)] {
+                if !self.points[from].on_border || self.points[to].on_border {
+                    queue.push(Reverse((self.cost(from, to), from, to)));
+                }
+            }
+        }
+    }
+
+    fn live_triangles(&self, point: usize) -> impl Iterator<Item = usize> + '_ {
+        self.points[point].triangles.iter().copied().filter(move |&t| self.alive[t])
+    }
+
+    // Sorted, so that two lists can be compared quickly
+    fn neighbours(&self, point: usize) -> Vec<usize> {
+        let mut neighbours: Vec<usize> = self.live_triangles(point)
+            .flat_map(|t| self.triangles[t])
+            .map(|vertex| self.point_of[vertex as usize])
+            .filter(|&other| other != point)
+            .collect();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        neighbours
+    }
+
+    // For every vertex of `from`, the vertex of `to` it becomes: one it shares a triangle with.
+    // None if a vertex has no such partner, e.g. because `from` is on a seam and `to` isn't.
+    fn partners(&self, from: usize, to: usize) -> Option<Vec<(u32, u32)>> {
+        let mut partners: Vec<(u32, u32)> = vec![];
+        for t in self.live_triangles(from) {
+            let triangle = self.triangles[t];
+            let vertex = triangle.iter().find(|&&v| self.point_of[v as usize] == from);
+            let partner = triangle.iter().find(|&&v| self.point_of[v as usize] == to);
+            if let (Some(&vertex), Some(&partner)) = (vertex, partner) {
+                if !partners.iter().any(|&(v, _)| v == vertex) {
+                    partners.push((vertex, partner));
+                }
+            }
+        }
+        let all_used = self.live_triangles(from).all(|t| {
+            self.triangles[t].iter().all(|&v| self.point_of[v as usize] != from || partners.iter().any(|&(p, _)| p == v))
+        });
+        if all_used { Some(partners) } else { None }
+    }
+
+    // The error of moving `from` onto `to`, as the bits of the float. Errors are never negative,
+    // so the bits sort the same way as the floats.
+    fn cost(&self, from: usize, to: usize) -> u64 {
+        let mut quadric = self.points[from].quadric;
+        quadric += self.points[to].quadric;
+        quadric.error(&self.points[to].position).max(0.0).to_bits()
+    }
+
+    // Whether moving `from` onto `to` keeps the mesh intact
+    fn can_collapse(&self, from: usize, to: usize) -> bool {
+        let shared = self.live_triangles(from)
+            .filter(|&t| self.triangles[t].iter().any(|&v| self.point_of[v as usize] == to))
+            .count();
+        if shared == 0 || (self.points[from].on_border && shared != 1) {
+            return false;
+        }
+        // Two points may only share the neighbours across the triangles along their edge, or
+        // the surface would fold onto itself
+        let to_neighbours = self.neighbours(to);
+        let common = self.neighbours(from).iter().filter(|n| to_neighbours.binary_search(n).is_ok()).count();
+        if common > shared {
+            return false;
+        }
+        // Must not turn over or squash the triangles which move along
+        let target = self.points[to].position;
+        for t in self.live_triangles(from) {
+            let positions = self.triangles[t].map(|v| self.points[self.point_of[v as usize]].position);
+            if self.triangles[t].iter().any(|&v| self.point_of[v as usize] == to) {
+                continue;
+            }
+            let moved = self.triangles[t].map(|v| {
+                if self.point_of[v as usize] == from { target } else { self.points[self.point_of[v as usize]].position }
+            });
+            let before = (positions[1] - positions[0]).cross(&(positions[2] - positions[0]));
+            let after = (moved[1] - moved[0]).cross(&(moved[2] - moved[0]));
+            if after.norm() == 0.0 || before.normalize().dot(&after.normalize()) < MIN_NORMAL_COS {
+                return false;
+            }
+        }
+        self.partners(from, to).is_some()
+    }
+
+    fn collapse(&mut self, from: usize, to: usize, queue: &mut BinaryHeap<Reverse<(u64, usize, usize)>>) {
+        let partners = self.partners(from, to).expect("Collapse without partners");
+        let triangles: Vec<usize> = self.live_triangles(from).collect();
+        for t in triangles {
+            if self.triangles[t].iter().any(|&v| self.point_of[v as usize] == to) {
+                self.alive[t] = false;
+                self.triangle_count -= 1;
+            } else {
+                for vertex in self.triangles[t].iter_mut() {
+                    if let Some(&(_, partner)) = partners.iter().find(|&&(v, _)| v == *vertex) {
+                        *vertex = partner;
+                    }
+                }
+                self.points[to].triangles.push(t);
+            }
+        }
+        let quadric = self.points[from].quadric;
+        self.points[to].quadric += quadric;
+        self.points[from].removed = true;
+        let alive = &self.alive;
+        self.points[to].triangles.retain(|&t| alive[t]);
+
+        // Only the costs of moving `to` or moving onto it have changed
+        self.push_collapses(queue, to, |_| true);
+    }
+
+    // The remaining triangles, with the vertices they use
+    fn into_mesh(self) -> Mesh {
+        let mesh = self.mesh;
+        let vertex_count = mesh.vertex_count();
+        let mut new_index = vec![None; vertex_count];
+        let mut kept = vec![];
+        let indices: Vec<u32> = self.triangles.iter().zip(&self.alive)
+            .filter(|(_, &alive)| alive)
+            .flat_map(|(triangle, _)| *triangle)
+            .map(|v| *new_index[v as usize].get_or_insert_with(|| {
+                kept.push(v as usize);
+                (kept.len() - 1) as u32
+            }))
+            .collect();
+
+        // Attributes without one value per vertex are left out
+        let gather = |values: &[f32], n: usize| -> Vec<f32> {
+            if values.len() != vertex_count * n {
+                return vec![];
+            }
+            kept.iter().flat_map(|&v| values[n * v..n * v + n].iter().copied()).collect()
+        };
+        let vertices = gather(&mesh.vertices, 3);
+        Mesh {
+            bounds      : Bounds::around(&vertices),
+            vertices,
+            normals     : gather(&mesh.normals, 3),
+            texcoords   : gather(&mesh.texcoords, 2),
+            tangents    : gather(&mesh.tangents, 4),
+            colors      : gather(&mesh.colors, 4),
+            index_count : indices.len() as i32,
+            indices,
+            material    : mesh.material.clone(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A grid of `size` by `size` squares from (0, 0) to (size, size), two triangles each facing
+    // +Z, raised by `height(x, y)`
+    fn grid(size: u32, height: impl Fn(f32, f32) -> f32) -> Mesh {
+        let mut vertices = vec![];
+        for y in 0..=size {
+            for x in 0..=size {
+                let (x, y) = (x as f32, y as f32);
+                vertices.extend_from_slice(&[x, y, height(x, y)]);
+            }
+        }
+        let mut indices = vec![];
+        let vertex = |x: u32, y: u32| y * (size + 1) + x;
+        for y in 0..size {
+            for x in 0..size {
+                let (a, b, c, d) = (vertex(x, y), vertex(x + 1, y), vertex(x + 1, y + 1), vertex(x, y + 1));
+                indices.extend_from_slice(&[a, b, c, a, c, d]);
+            }
+        }
+        Mesh::from_positions(vertices, indices)
+    }
+
+    fn bumps(x: f32, y: f32) -> f32 {
+        0.2 * (x * 0.7).sin() * (y * 0.5).cos()
+    }
+
+    fn corners(mesh: &Mesh, triangle: &[u32]) -> [glm::Vec3; 3] {
+        let position = |v: u32| glm::make_vec3(&mesh.vertices[v as usize * 3..v as usize * 3 + 3]);
+        [position(triangle[0]), position(triangle[1]), position(triangle[2])]
+    }
+
+    #[test]
+    fn reaches_the_triangle_count() {
+        let mesh = grid(16, |_, _| 0.0);
+        for &ratio in &[0.5, 0.25, 0.1] {
+            let target = (512.0 * ratio) as usize;
+            let simplified = simplify(&mesh, target);
+            let count = simplified.indices.len() / 3;
+            assert!(count <= target && count > 0, "{} triangles instead of {}", count, target);
+        }
+    }
+
+    #[test]
+    fn the_border_stays_put() {
+        let size = 16;
+        let simplified = simplify(&grid(size, |_, _| 0.0), 50);
+        let on_border = |p: &glm::Vec3| p.x == 0.0 || p.y == 0.0 || p.x == size as f32 || p.y == size as f32;
+
+        // Every corner is still there, and the triangles cover the whole square without overlapping
+        let positions: Vec<glm::Vec3> = simplified.vertices.chunks_exact(3).map(glm::make_vec3).collect();
+        for corner in &[glm::vec2(0.0, 0.0), glm::vec2(16.0, 0.0), glm::vec2(0.0, 16.0), glm::vec2(16.0, 16.0)] {
+            assert!(positions.iter().any(|p| p.xy() == *corner), "the corner at {:?} is gone", corner);
+        }
+        let area: f32 = simplified.indices.chunks_exact(3)
+            .map(|triangle| {
+                let [a, b, c] = corners(&simplified, triangle);
+                (b - a).cross(&(c - a)).z / 2.0
+            })
+            .sum();
+        assert!((area - (size * size) as f32).abs() < 1e-3, "the area is {}", area);
+
+        // Edges with a triangle on one side only are along the border
+        let mut edges: HashMap<(u32, u32), i32> = HashMap::new();
+        for triangle in simplified.indices.chunks_exact(3) {
+            for i in 0..3 {
+                let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+                *edges.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+        for (&(a, b), _) in edges.iter().filter(|(_, &count)| count == 1) {
+            let [a, b, _] = corners(&simplified, &[a, b, b]);
+            assert!(on_border(&a) && on_border(&b), "{:?} to {:?} is not on the border", a, b);
+            assert!(a.x == b.x || a.y == b.y, "{:?} to {:?} cuts a corner", a, b);
+        }
+    }
+
+    #[test]
+    fn triangles_do_not_turn_over() {
+        let mesh = grid(24, bumps);
+        for &target in &[576, 200, 60, 20] {
+            let simplified = simplify(&mesh, target);
+            for triangle in simplified.indices.chunks_exact(3) {
+                let [a, b, c] = corners(&simplified, triangle);
+                let normal = (b - a).cross(&(c - a)).normalize();
+                assert!(normal.z >= MIN_NORMAL_COS, "a triangle faces {:?} at {} triangles", normal, target);
+            }
+        }
+    }
+
+    #[test]
+    fn coarser_levels_for_smaller_sizes() {
+        let chain = LodChain::new(&Rc::new(grid(8, bumps)), &LodSettings::default());
+        assert_eq!(chain.select(f32::INFINITY), 0);
+        assert_eq!(chain.select(0.0), chain.level_count() - 1);
+        let mut previous = 0;
+        for step in (0..=100).rev() {
+            let level = chain.select(step as f32 / 100.0);
+            assert!(level >= previous, "level {} at {} after level {}", level, step, previous);
+            previous = level;
+        }
+        assert_eq!(previous, chain.level_count() - 1);
+    }
+
+    #[test]
+    fn levels_are_built_when_asked_for() {
+        let mesh = Rc::new(grid(8, bumps));
+        let chain = LodChain::new(&mesh, &LodSettings::default());
+        assert!((0..chain.level_count()).all(|level| !chain.is_built(level)));
+
+        assert!(chain.mesh(2).indices.len() / 3 <= 32);
+        assert!(chain.is_built(1) && chain.is_built(2) && !chain.is_built(3));
+        assert!(Rc::ptr_eq(chain.mesh(0), &mesh));
+        assert!(chain.mesh(1).indices.len() / 3 <= 64);
+        assert!(chain.gpu_mesh(0).is_none(), "nothing is uploaded before `upload`");
+    }
+}
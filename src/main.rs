@@ -10,31 +10,55 @@
 extern crate nalgebra_glm as glm;
 use std::{ mem, ptr, os::raw::c_void };
 use std::thread;
+use std::rc::Rc;
+use std::collections::HashMap;
 use std::sync::{Mutex, Arc, RwLock};
 
 mod shader;
 mod util;
+mod bounds;
 mod mesh;
+mod mesh_cache;
+mod mesh_export;
+mod gltf_model;
+mod lod;
+mod gpu_mesh;
+mod texture;
 mod scene_graph;
 mod toolbox;
+mod headless;
+mod software;
 
-use glm::{Vec3, vec4, vec3, Mat4};
+use glm::vec3;
 use glutin::event::{Event, WindowEvent, DeviceEvent, KeyboardInput, ElementState::{Pressed, Released}, VirtualKeyCode::{self, *}};
 use glutin::event_loop::ControlFlow;
-use mesh::Helicopter;
-use scene_graph::SceneNode;
-use toolbox::Heading;
+use scene_graph::{DrawStats, SceneGraph};
+use bounds::Frustum;
 
 // initial window size
 const INITIAL_SCREEN_W: u32 = 800;
 const INITIAL_SCREEN_H: u32 = 600;
 
+const CLEAR_COLOR: [f32; 4] = [0.035, 0.046, 0.078, 1.0]; // night sky, full opacity
+
+// How many helicopters fly above the terrain, unless told otherwise with --helicopters
+const HELICOPTER_COUNT: usize = 5;
+
+// How many helicopters follow the same path together
+const FLOCK_SIZE: usize = 5;
+// How many flocks fly side by side before another row starts behind them
+const FLOCKS_PER_ROW: usize = 16;
+// How far apart flocks fly beside each other, along X
+const FLOCK_SPACING_BESIDE: f32 = 40.0;
+// How far apart rows of flocks fly behind each other, along Z
+const FLOCK_SPACING_BEHIND: f32 = -100.0;
+
 // == // Helper functions to make interacting with OpenGL a little bit prettier. You *WILL* need these! // == //
 
 // Get the size of an arbitrary array of numbers measured in bytes
 // Example usage:  pointer_to_array(my_array)
 fn byte_size_of_array<T>(val: &[T]) -> isize {
-    std::mem::size_of_val(&val[..]) as isize
+    std::mem::size_of_val(val) as isize
 }
 
 // Get the OpenGL-compatible pointer to an arbitrary array of numbers
@@ -59,174 +83,405 @@ fn offset<T>(n: u32) -> *const c_void {
 // ptr::null()
 
 
-// == // Generate your VAO here
-unsafe fn create_vao(vertices: &Vec<f32>, indices: &Vec<u32>, color: &Vec<f32>, normal: &Vec<f32>) -> u32 {
-
-    // This should:
-    // * Generate a VAO (vertex array object) and bind it
-    let mut vao_id:u32 =0;
-    gl::GenVertexArrays(1, &mut vao_id);
-    gl::BindVertexArray(vao_id);
-
-    // * Generate a VBO (Vertex buffer object) and bind it
-    let mut vbo_id:u32=0;
-    gl::GenBuffers(1, &mut vbo_id);
-    gl::BindBuffer(gl::ARRAY_BUFFER, vbo_id);
-    // * Fill it with data
-    gl::BufferData(
-        gl::ARRAY_BUFFER, 
-        byte_size_of_array(&vertices) as isize, // vert?
-        vertices.as_ptr().cast(),     
-        gl::STATIC_DRAW
-    );
-
-    // * Configure a VAP for the data and enable it
-    gl::VertexAttribPointer(
-        0, // 0 or add u32?
-        3,
-        gl::FLOAT,  //should match data type from buffer data
-        gl::FALSE,
-        0,
-        std::ptr::null()
-    );
-    gl::EnableVertexAttribArray(0);
-
-    // * Generate a IBO and bind it
-    let mut ibo_id:u32=0;
-    gl::GenBuffers(1, &mut ibo_id);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ibo_id);
-    //* fill IBO with data
-    gl::BufferData(
-        gl::ELEMENT_ARRAY_BUFFER,
-        byte_size_of_array(&indices),
-        indices.as_ptr().cast(),
-        gl::STATIC_DRAW
-    );
-
-    //Color Generate, bind and fill with data, VBO for color
-    let mut color_id:u32=0;
-    gl::GenBuffers(1, &mut color_id);
-    gl::BindBuffer(gl::ARRAY_BUFFER, color_id);
-    gl::BufferData(
-        gl::ARRAY_BUFFER,
-        byte_size_of_array(&color),
-        color.as_ptr().cast(),
-        gl::STATIC_DRAW
-    );
-
-    gl::VertexAttribPointer(
-        1,
-        4,
-        gl::FLOAT,
-        gl::FALSE, // setter normalised, skal kanskje være true
-        0,
-        std::ptr::null()
-    );
-
-    gl::EnableVertexAttribArray(1);
-
-
-    // normals:
-    let mut normals_id:u32=0;
-    gl::GenBuffers(1, &mut normals_id);
-    gl::BindBuffer(gl::ARRAY_BUFFER, normals_id);
-    gl::BufferData(
-        gl::ARRAY_BUFFER,
-        byte_size_of_array(&normal),
-        normal.as_ptr().cast(),
-        gl::STATIC_DRAW
-    );
-    //println!("normals_id\t: {:?}",&normal);
-
-    gl::VertexAttribPointer(
-        2,
-        3,
-        gl::FLOAT,
-        gl::FALSE, // setter normalised til true
-        0,
-        std::ptr::null()
-    );
-
-
-    gl::EnableVertexAttribArray(2);
-
-
-
-
-
-
-
-    // * Return the ID of the VAO
-    return vao_id;
+// draw scene ---------------------------------------------------------------
+// The world matrices and bounds are cached in the scene graph, so remember to call
+// `SceneGraph::update_transforms` before drawing. The view matrix is shared by all nodes, so it
+// is set once in `render_frame`. Nodes outside `frustum` are skipped, along with the whole
+// subtree if all of it is outside, and counted in `stats`.
+//
+// Nodes drawing the same mesh with the same textures, like the parts of the helicopters, are
+// gathered first and then drawn together with one instanced draw call. Translucent nodes are
+// drawn on their own, in the order they are found.
+unsafe fn draw_scene(
+    shaders: &Shaders,
+    scene: &SceneGraph,
+    node_id: scene_graph::NodeId,
+    view_projection_matrix: &glm::Mat4,
+    frustum: &Frustum,
+    stats: &mut DrawStats) {
+    let mut batches: Vec<Batch> = vec![];
+    let mut batch_of: HashMap<BatchKey, usize> = HashMap::new();
+    let mut stack = vec![node_id];
+    while let Some(id) = stack.pop() {
+        let node = &scene[id];
+        if frustum.excludes(node.subtree_bounds()) {
+            stats.culled += scene.drawable_count(id);
+            continue;
+        }
+        stack.extend(node.children().iter().rev());
+
+        // Check if node is drawable and on screen, if so: add it to the batch of its mesh
+        if !node.is_drawable() {
+            continue;
+        }
+        if frustum.excludes(node.bounds()) {
+            stats.culled += 1;
+            continue;
+        }
+        let (mesh, gpu_mesh) = node.lod(view_projection_matrix);
+        let gpu_mesh = match gpu_mesh {
+            Some(gpu_mesh) => gpu_mesh,
+            None => continue,   // Never uploaded, so there is nothing to draw
+        };
+        stats.drawn += 1;
+        let key = batch_key(node, mesh, gpu_mesh);
+        let index = *batch_of.entry(key).or_insert_with(|| {
+            batches.push(Batch { node, mesh, gpu_mesh, model_matrices: vec![] });
+            batches.len() - 1
+        });
+        batches[index].model_matrices.push(*node.world_matrix());
+    }
+
+    for batch in &batches {
+        let instanced = batch.model_matrices.len() > 1;
+        let shader = shaders.for_node(batch.node, batch.mesh, instanced);
+        shader.activate();
+
+        //uniforms:
+        match batch.mesh {
+            Some(mesh) => set_material(shader, &mesh.material),
+            None       => set_material(shader, &mesh::Material::default()),
+        }
+        match &batch.node.texture {
+            Some(texture) => {
+                texture.bind(0);
+                shader.set_i32("has_diffuse_texture", 1);
+            }
+            None => shader.set_i32("has_diffuse_texture", 0),
+        }
+        if let Some(normal_texture) = &batch.node.normal_texture {
+            normal_texture.bind(1);
+        }
+
+        if instanced {
+            batch.gpu_mesh.draw_instanced(shader, &batch.model_matrices);
+        } else {
+            shader.set_mat4("model_mat", &batch.model_matrices[0]);
+            batch.gpu_mesh.draw(shader);
+        }
+        stats.draw_calls += 1;
+    }
 }
 
+// Nodes drawing the same mesh, in the same way
+struct Batch<'a> {
+    node           : &'a scene_graph::SceneNode,     // The first of them, for the textures and the shader
+    mesh           : Option<&'a Rc<mesh::Mesh>>,     // For the material
+    gpu_mesh       : &'a Rc<gpu_mesh::GpuMesh>,
+    model_matrices : Vec<glm::Mat4>,                 // One for each node
+}
 
-// draw scene ---------------------------------------------------------------
-unsafe fn draw_scene(
+// Nodes which are drawn the same way share the mesh, the material (which is part of the mesh)
+// and the textures. Translucent nodes are never batched, since they have to be blended in the
+// order they are drawn, so the last part is the node itself for them and null otherwise.
+type BatchKey = (
+    *const gpu_mesh::GpuMesh,
+    *const mesh::Mesh,
+    *const texture::Texture,
+    *const texture::Texture,
+    *const scene_graph::SceneNode,
+);
+
+fn batch_key(node: &scene_graph::SceneNode, mesh: Option<&Rc<mesh::Mesh>>, gpu_mesh: &Rc<gpu_mesh::GpuMesh>) -> BatchKey {
+    let translucent = mesh.is_some_and(|mesh| mesh.material.dissolve < 1.0);
+    (
+        Rc::as_ptr(gpu_mesh),
+        mesh.map_or(ptr::null(), Rc::as_ptr),
+        node.texture.as_ref().map_or(ptr::null(), Rc::as_ptr),
+        node.normal_texture.as_ref().map_or(ptr::null(), Rc::as_ptr),
+        if translucent { node } else { ptr::null() },
+    )
+}
 
-    node: &scene_graph::SceneNode,
-    view_projection_matrix: &glm::Mat4,
-    transformation_so_far: &glm::Mat4) {
-    // Perform any logic needed before drawing the node
-    //the translations and rotations are not yet foolproof, might have done it wrong, the angle is probably way off
-    
-    let mut transformation: glm::Mat4 = glm::identity();
-    //translate
-    //node.reference_point = transformation_so_far.dot(node.reference_point);
-
-    transformation =  glm::translation(&(-node.reference_point)) * transformation;
-    //rotates if there is a defined matrix (hopefully)
-    transformation = glm::rotation(node.rotation.z,&glm::vec3(0.0, 0.0, 1.0)) * transformation;
-    transformation = glm::rotation(node.rotation.y,&glm::vec3(0.0, 1.0, 0.0)) * transformation;
-    transformation = glm::rotation(node.rotation.x,&glm::vec3(1.0, 0.0, 0.0)) * transformation; 
-    //scale?
-    
-    //translate back 
-    transformation =  glm::translation(&(node.reference_point)) * transformation;
 
-    transformation =  glm::translation(&(node.position)) * transformation;
+unsafe fn set_material(shader: &shader::Shader, material: &mesh::Material) {
+    shader.set_vec3("material.ambient", &glm::make_vec3(&material.ambient));
+    shader.set_vec3("material.diffuse", &glm::make_vec3(&material.diffuse));
+    shader.set_vec3("material.specular", &glm::make_vec3(&material.specular));
+    shader.set_f32("material.shininess", material.shininess);
+    shader.set_f32("material.dissolve", material.dissolve);
+}
 
-    //let mut holder = transformation * vec4 (node.reference_point.x, node.reference_point.y,node.reference_point.z,1.0);
-    //transformation_so_far = vec3()
 
+// Set up the global OpenGL state, shared by the window and the headless renderer
+unsafe fn setup_gl() {
+    gl::Enable(gl::DEPTH_TEST);
+    gl::DepthFunc(gl::LESS);
+    //gl::Enable(gl::CULL_FACE);
+    gl::Disable(gl::MULTISAMPLE);
+    gl::Enable(gl::BLEND);
+    gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+    gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+    gl::DebugMessageCallback(Some(util::debug_callback), ptr::null());
+
+    // Print some diagnostics
+    println!("{}: {}", util::get_gl_string(gl::VENDOR), util::get_gl_string(gl::RENDERER));
+    println!("OpenGL\t: {}", util::get_gl_string(gl::VERSION));
+    println!("GLSL\t: {}", util::get_gl_string(gl::SHADING_LANGUAGE_VERSION));
+}
 
-    //add to transformation so far:
-    let model_matrix: glm::Mat4 = transformation_so_far * transformation;
-    // let MVP: glm::Mat4 =  view_projection_matrix * model_matrix; //redundant
-    //let _ = transformation_so_far * model_matrix;
+const SHADER_FILES: [&str; 2] = ["./shaders/simple.frag", "./shaders/simple.vert"];
 
+// The files to watch after the shaders failed to load: the shader files, and everything they got
+// to include before failing
+fn shader_files_to_watch(error: &shader::ShaderError) -> Vec<std::path::PathBuf> {
+    let mut files: Vec<std::path::PathBuf> = SHADER_FILES.iter().map(Into::into).collect();
+    files.extend(error.dependencies.iter().cloned());
+    files.sort();
+    files.dedup();
+    files
+}
 
-    // Check if node is drawable, if so: set uniforms, bind VAO and draw VAO
-    if node.index_count != -1{ // this might be 2 or three
+unsafe fn load_shader(defines: &[(&str, &str)]) -> Result<shader::Shader, shader::ShaderError> {
+    let mut builder = shader::ShaderBuilder::new();
+    for (name, value) in defines {
+        builder = builder.define(name, value);
+    }
+    for file in SHADER_FILES {
+        builder = builder.attach_file(file)?;
+    }
+    builder.link()
+}
 
-        //uniforms:
+// The shader, and variants of it for meshes with a normal map and for drawing many copies of a
+// mesh at once. All are built from the same files, so they are loaded and reloaded together.
+struct Shaders {
+    simple                  : shader::Shader,
+    normal_mapped           : shader::Shader,   // With NORMAL_MAP defined
+    instanced               : shader::Shader,   // With INSTANCED defined
+    instanced_normal_mapped : shader::Shader,   // With both
+}
+
+impl Shaders {
+    unsafe fn load() -> Result<Shaders, shader::ShaderError> {
+        Ok(Shaders {
+            simple                  : load_shader(&[])?,
+            normal_mapped           : load_shader(&[("NORMAL_MAP", "1")])?,
+            instanced               : load_shader(&[("INSTANCED", "1")])?,
+            instanced_normal_mapped : load_shader(&[("INSTANCED", "1"), ("NORMAL_MAP", "1")])?,
+        })
+    }
 
-        unsafe{
-            // sending the matrix to vertex shader
-            //gl::UseProgram(shader.program_id);
-            gl::UniformMatrix4fv(10, 1,0, model_matrix.as_ptr());
-            gl::UniformMatrix4fv(26, 1, 0, view_projection_matrix.as_ptr());
+    // All are checked even if the first fails, so none retries on every frame
+    unsafe fn reload_if_changed(&mut self) -> Result<bool, shader::ShaderError> {
+        let results = [
+            self.simple.reload_if_changed(),
+            self.normal_mapped.reload_if_changed(),
+            self.instanced.reload_if_changed(),
+            self.instanced_normal_mapped.reload_if_changed(),
+        ];
+        let mut reloaded = false;
+        for result in results {
+            reloaded |= result?;
         }
+        Ok(reloaded)
+    }
 
-        //bind and draw VAO
-        gl::BindVertexArray(node.vao_id);
-        gl::DrawElements(
-        gl::TRIANGLES,
-        node.index_count,
-        gl::UNSIGNED_INT,
-        std::ptr::null()
-        );
+    // The normal map needs tangents, which only the meshes on the CPU side tell whether there are.
+    // `mesh` is the one drawn, which may be a level of the node's LOD chain rather than its own.
+    // An `instanced` shader is for `GpuMesh::draw_instanced`.
+    fn for_node(&self, node: &scene_graph::SceneNode, mesh: Option<&Rc<mesh::Mesh>>, instanced: bool) -> &shader::Shader {
+        let has_tangents = mesh.is_some_and(|mesh| mesh.has_tangents());
+        match (node.normal_texture.is_some() && has_tangents, instanced) {
+            (false, false) => &self.simple,
+            (true, false)  => &self.normal_mapped,
+            (false, true)  => &self.instanced,
+            (true, true)   => &self.instanced_normal_mapped,
+        }
     }
-    // Recurse
-    for &child in &node.children {
-    draw_scene(&*child, view_projection_matrix, &model_matrix);
+
+    fn all(&self) -> [&shader::Shader; 4] {
+        [&self.simple, &self.normal_mapped, &self.instanced, &self.instanced_normal_mapped]
     }
+}
+
+// The nodes of a helicopter in the scene that are animated
+struct Helicopter {
+    body       : scene_graph::NodeId,   // The whole helicopter, moved along its path
+    main_rotor : scene_graph::NodeId,
+    tail_rotor : scene_graph::NodeId,
+}
+
+// Load the models and build the scene graph: the terrain, with `helicopter_count` helicopters
+// flying above it. The meshes are only uploaded to the GPU when `upload_to_gpu` is set, which
+// requires an active OpenGL context. Returns the scene along with the nodes of every helicopter.
+fn load_scene(upload_to_gpu: bool, helicopter_count: usize) -> (SceneGraph, Vec<Helicopter>) {
+    // The terrain is a single mesh, in case the file has several
+    let mut terrain = mesh::Model::load("./resources/lunarsurface.obj")
+        .expect("Failed to load terrain model")
+        .merge();
+    // Drawn coarser when it's far away
+    let terrain_lods = lod::LodChain::new(&terrain.objects[0].mesh, &lod::LodSettings::default());
+
+    // A glTF helicopter knows where the pivots of its rotors are, while they have to be given for
+    // the OBJ one. The parts are expected to have the same names in both. The colours of the
+    // parts of the OBJ one are in `helicopter.mtl`.
+    let gltf_path = ["./resources/helicopter.glb", "./resources/helicopter.gltf"].iter()
+        .find(|path| std::path::Path::new(path).exists());
+    let mut gltf_helicopter = gltf_path.map(|path| {
+        gltf_model::GltfModel::load(path).expect("Failed to load helicopter model")
+    });
+    let mut obj_helicopter = match gltf_helicopter {
+        Some(_) => None,
+        None => Some(mesh::Model::load("./resources/helicopter.obj").expect("Failed to load helicopter model")),
+    };
+
+    if upload_to_gpu {
+        unsafe {
+            terrain.upload();
+            terrain_lods.upload();
+            gltf_helicopter.iter_mut().for_each(|helicopter| helicopter.upload());
+            obj_helicopter.iter_mut().for_each(|helicopter| helicopter.upload());
+        }
+    }
+
+    let mut scene = SceneGraph::new();
+    let terrain_node = scene.add_model(scene.root(), &terrain);
+    scene[(terrain_node, 0)].lods = Some(Rc::new(terrain_lods));
+
+    let mut helicopters = Vec::new();
+    for _ in 0..helicopter_count {
+        let body = match (&gltf_helicopter, &obj_helicopter) {
+            (Some(helicopter), _)    => helicopter.add_to(&mut scene, terrain_node).root,
+            (None, Some(helicopter)) => scene.add_model(terrain_node, helicopter),
+            (None, None)             => unreachable!(),
+        };
+        let main_rotor = scene.find(body, "Main_Rotor_main_rotor").unwrap();
+        let tail_rotor = scene.find(body, "Tail_Rotor_tail_rotor").unwrap();
+        if obj_helicopter.is_some() {
+            scene[main_rotor].reference_point = glm::vec3(0.0, 2.3, 0.0);
+            scene[tail_rotor].reference_point = glm::vec3(0.35, 2.3, 10.4);
+        }
+        helicopters.push(Helicopter { body, main_rotor, tail_rotor });
+    }
+
+    (scene, helicopters)
+}
+
+// Move the helicopters along their paths and spin their rotors, for a given time in seconds.
+// Every `FLOCK_SIZE` helicopters are a flock following the same path, and further flocks fly
+// beside and behind the first one.
+fn animate_scene(scene: &mut SceneGraph, helicopters: &[Helicopter], elapsed: f32) {
+    // The rotor speeds have always been scaled by 3.14 rather than by pi, keep them that way
+    #[allow(clippy::approx_constant)]
+    let full_turn = 2.0 * 3.14;
+
+    let rps_tail = 2.0;
+    let rotation_tail = rps_tail * full_turn * elapsed;
+
+    let rps_main = 1.0;
+    let rotation_main = rps_main * full_turn * elapsed;
+
+    let offset = 3.2;
+    for (i, helicopter) in helicopters.iter().enumerate() {
+        let heading = toolbox::simple_heading_animation(elapsed + offset * i as f32);
+        let flock = i / FLOCK_SIZE;
+        let flock_offset = vec3(
+            FLOCK_SPACING_BESIDE * (flock % FLOCKS_PER_ROW) as f32,
+            0.0,
+            FLOCK_SPACING_BEHIND * (flock / FLOCKS_PER_ROW) as f32,
+        );
+        scene[helicopter.body].position = vec3(heading.x, 20.0, heading.z) + flock_offset;
+        scene[helicopter.body].orientation = Some(heading.orientation());
+        scene[helicopter.main_rotor].rotation = glm::vec3(0.0, rotation_main, 0.0);
+        scene[helicopter.tail_rotor].rotation = glm::vec3(rotation_tail, 0.0, 0.0);
     }
+}
+
+// The view-projection matrix for a camera moved by `translation` and then rotated about Y and X
+fn camera_transform(aspect_ratio: f32, translation: &glm::Vec3, rot_x: f32, rot_y: f32) -> glm::Mat4 {
+    let projection: glm::Mat4 = glm::perspective(aspect_ratio, 90.0, 1.0, 1000.0); // increased far to 1000
+    let rot_y: glm::Mat4 = glm::rotation(rot_y, &glm::vec3(0.0, 1.0, 0.0));
+    let rot_x: glm::Mat4 = glm::rotation(rot_x, &glm::vec3(1.0, 0.0, 0.0));
+
+    let mut trans: glm::Mat4 = glm::identity(); //final computed matrix
+    trans = glm::translation(&glm::vec3(0.0, 0.0, -2.0)) * trans;
+    trans = glm::translation(translation) * trans;
+    trans = rot_y * trans;
+    trans = rot_x * trans;
+
+    //this must always be last!
+    projection * trans
+}
+
+// Clear the bound framebuffer and draw the whole scene into it, except what is off screen
+unsafe fn render_frame(shaders: &Shaders, scene: &mut SceneGraph, view_projection_matrix: &glm::Mat4) -> DrawStats {
+    // Clear the color and depth buffers
+    gl::ClearColor(CLEAR_COLOR[0], CLEAR_COLOR[1], CLEAR_COLOR[2], CLEAR_COLOR[3]);
+    gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+    for shader in shaders.all() {
+        shader.set_mat4("view_mat", view_projection_matrix);
+        shader.set_vec3("camera_position", &toolbox::camera_position(view_projection_matrix));
+        // The texture units `draw_scene` binds textures to
+        shader.set_i32("diffuse_texture", 0);
+        shader.set_i32("normal_texture", 1);
+    }
+
+    scene.update_transforms();
+    let frustum = Frustum::from_matrix(view_projection_matrix);
+    let mut stats = DrawStats::default();
+    draw_scene(shaders, scene, scene.root(), view_projection_matrix, &frustum, &mut stats);
+    stats
+}
+
+// Render a single frame at time `t` without opening a window, and save it as a PNG
+fn render_headless(options: &headless::RenderOptions) -> Result<(), String> {
+    let aspect_ratio = options.width as f32 / options.height as f32;
+    let trans = camera_transform(aspect_ratio, &glm::zero(), 0.0, 0.0);
+
+    let (image, stats) = match options.backend {
+        headless::Backend::OpenGl => {
+            let _context = headless::HeadlessContext::new()?;
+            unsafe { setup_gl(); }
+
+            let framebuffer = unsafe { headless::Framebuffer::new(options.width, options.height)? };
+            let shaders = unsafe { Shaders::load() }.map_err(|e| e.to_string())?;
+            let (mut scene, helicopters) = load_scene(true, options.helicopters);
+            animate_scene(&mut scene, &helicopters, options.time);
+            unsafe {
+                framebuffer.bind();
+                let stats = render_frame(&shaders, &mut scene, &trans);
+                (framebuffer.read_pixels(), stats)
+            }
+        }
+        headless::Backend::Software => {
+            let (mut scene, helicopters) = load_scene(false, options.helicopters);
+            animate_scene(&mut scene, &helicopters, options.time);
+            scene.update_transforms();
+
+            let mut renderer = software::SoftwareRenderer::new(options.width, options.height);
+            let stats = renderer.draw_scene(&scene, scene.root(), &trans);
+            (renderer.to_image(), stats)
+        }
+    };
+
+    image.save(&options.output)
+        .map_err(|e| format!("Failed to write {}: {}", options.output.display(), e))?;
+    println!("Rendered t = {} to {} ({})", options.time, options.output.display(), stats);
+    Ok(())
+}
 
 
 // ----------------------------------------------------------------------------------------
 
 fn main() {
+
+    // Render a single frame to a PNG instead of opening a window, e.g.
+    //     cargo run -- --render-frame t=3.5 --out frame.png
+    match headless::RenderOptions::from_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => {
+            if let Err(e) = render_headless(&options) {
+                eprintln!("Headless rendering failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(None) => { }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, headless::USAGE);
+            std::process::exit(2);
+        }
+    }
     
     // Set up the necessary objects to deal with windows and event handling
     let el = glutin::event_loop::EventLoop::new();
@@ -270,21 +525,7 @@ fn main() {
         let mut window_aspect_ratio = INITIAL_SCREEN_W as f32 / INITIAL_SCREEN_H as f32;
 
         // Set up openGL
-        unsafe {
-            gl::Enable(gl::DEPTH_TEST);
-            gl::DepthFunc(gl::LESS);
-            //gl::Enable(gl::CULL_FACE);
-            gl::Disable(gl::MULTISAMPLE);
-            gl::Enable(gl::BLEND);
-            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
-            gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
-            gl::DebugMessageCallback(Some(util::debug_callback), ptr::null());
-
-            // Print some diagnostics
-            println!("{}: {}", util::get_gl_string(gl::VENDOR), util::get_gl_string(gl::RENDERER));
-            println!("OpenGL\t: {}", util::get_gl_string(gl::VERSION));
-            println!("GLSL\t: {}", util::get_gl_string(gl::SHADING_LANGUAGE_VERSION));
-        }
+        unsafe { setup_gl(); }
 
         // == // Set up your VAO around here
 
@@ -397,65 +638,21 @@ fn main() {
 
 
         // == // Set up your shaders here
-        let shader = unsafe {
-            shader::ShaderBuilder::new()
-                .attach_file("./shaders/simple.frag")
-                .attach_file("./shaders/simple.vert")
-                .link()
-        };
-        // load terrain
-        let terrain= mesh::Terrain::load("./resources/lunarsurface.obj");
-        
-
-        let my_terrain = unsafe{ 
-            create_vao(
-                &terrain.vertices,
-                &terrain.indices,
-                &terrain.colors,
-                &terrain.normals) 
-        };
-
-        // load helicopters
-
-
-
-        let helicopter = mesh::Helicopter::load("./resources/helicopter.obj");
-        let heli_body = helicopter.body;
-        let heli_door = helicopter.door;
-        let heli_m_rotor = helicopter.main_rotor;
-        let heli_t_rotor = helicopter.tail_rotor;
-
-
-        let my_heli_body = unsafe{
-            create_vao(
-                &heli_body.vertices, 
-                &heli_body.indices, 
-                &heli_body.colors,
-                &heli_body.normals)
-        };
-
-        let my_heli_door = unsafe{
-            create_vao(
-                &heli_door.vertices, 
-                &heli_door.indices, 
-                &heli_door.colors,
-                &heli_door.normals)
-        };
-
-        let my_heli_m_rotor = unsafe{
-            create_vao(
-                &heli_m_rotor.vertices, 
-                &heli_m_rotor.indices, 
-                &heli_m_rotor.colors,
-                &heli_m_rotor.normals)
-        };
-        let my_heli_t_rotor = unsafe{
-            create_vao(
-                &heli_t_rotor.vertices, 
-                &heli_t_rotor.indices, 
-                &heli_t_rotor.colors,
-                &heli_t_rotor.normals)
+        // Without a shader we can't draw anything, but keep the window open and show why.
+        // The shader files are watched, and the shader is rebuilt whenever they are saved.
+        let mut failed_shader_files = vec![];
+        let mut shaders = match unsafe { Shaders::load() } {
+            Ok(shaders) => Some(shaders),
+            Err(e) => {
+                eprintln!("{}", e);
+                failed_shader_files = shader_files_to_watch(&e);
+                None
+            }
         };
+        let mut failed_shader_times = shader::modification_times(&failed_shader_files);
+        let (mut scene, helicopters) = load_scene(true, HELICOPTER_COUNT);
+        // Printed whenever something moves on or off screen
+        let mut last_stats = DrawStats::default();
 
 
         // // !!!!!!!!!!!!!!!  AFFINE MATRIX TRANSFORMATIONS !!!!!!!!
@@ -485,7 +682,7 @@ fn main() {
         /*
         let simple_shader = unsafe {
             shader::ShaderBuilder::new()
-                .attach_file("./path/to/simple/shader.file")
+                .attach_file("./path/to/simple/shader.file")?
                 .link()
         };
         */
@@ -646,7 +843,6 @@ fn main() {
             // == // Please compute camera transforms here (exercise 2 & 3)
             //dealarations
             //projection deaclaration
-            let projection: glm::Mat4 = glm::perspective(window_aspect_ratio, 90.0, 1.0, 1000.0); // increased far to 1000
 
 
 
@@ -671,18 +867,12 @@ fn main() {
 
 
         //  // matrix multiplications goes here:
-        let rot_y:glm::Mat4= glm::rotation(roty_val,&glm::vec3(0.0, 1.0, 0.0));
-        let rot_x:glm::Mat4= glm::rotation(rotx_val,&glm::vec3(1.0, 0.0, 0.0));
-
-
-            let mut trans: glm::Mat4= glm::identity(); //final computed matrix
-            trans = glm::translation(&glm::vec3(0.0, 0.0, -2.0)) * trans;
-            trans = glm::translation(&glm::vec3(transx_val, transy_val, transz_val)) * trans;
-            trans = rot_y * trans;
-            trans = rot_x * trans;
-
-            //this must always be last!
-            trans = projection *trans;
+            let trans = camera_transform(
+                window_aspect_ratio,
+                &glm::vec3(transx_val, transy_val, transz_val),
+                rotx_val,
+                roty_val,
+            );
 
 
             // unsafe{
@@ -691,19 +881,8 @@ fn main() {
             //     gl::UniformMatrix4fv(10, 1,0, trans.as_ptr());
             // }
 
-            let rps_tail = 2.0;
-            let rot_scale_tail = rps_tail *2.0*3.14;
-            let mut rotation_tail = rot_scale_tail* elapsed;
-
-            let rps_main = 1.0;
-            let rot_scale_main = rps_main *2.0*3.14;
-            let mut rotation_main = elapsed* rot_scale_main;
 
             unsafe {
-                // Clear the color and depth buffers
-                gl::ClearColor(0.035, 0.046, 0.078, 1.0); // night sky, full opacity
-                gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
-                shader.activate();
                 // //     terrain.index_count,
                 // //     gl::UNSIGNED_INT,
                 // //     std::ptr::null()
@@ -757,79 +936,50 @@ fn main() {
 
 
 
-            //let world_pos: Vec3= vec3(trans[(3, 0)], trans[(3,1)], trans[(3,2)]);
-            let mut root_node = SceneNode::new();
-            let mut scene_node = SceneNode::from_vao(my_terrain, terrain.index_count);
-            root_node.add_child(&scene_node);
-            //root_node.reference_point = -world_pos;
-
-            //etter dette skal alt forhåpentlgvis funbgere via loopen::
-
-            // let mut heli_bod_node = SceneNode::from_vao(my_heli_body, heli_body.index_count);
-            // scene_node.add_child(&heli_bod_node);
-            // heli_bod_node.reference_point = vec3(heading.x, 0.0, heading.z);
-            // heli_bod_node.rotation = vec3(heading.roll, heading.yaw, heading.pitch);
-            
-
-
-            // let mut heli_door_node = SceneNode::from_vao(my_heli_door, heli_door.index_count);
-            // heli_bod_node.add_child(&heli_door_node);
-            // heli_door_node.reference_point = vec3(0.0, 0.0, 0.0);
-            // //heli_door_node.rotation= glm::vec3(0.0, rotation_tail, 0.0);
-
-            // let mut heli_m_rot_node = SceneNode::from_vao(my_heli_m_rotor, heli_m_rotor.index_count);
-            // heli_bod_node.add_child(&heli_m_rot_node);
-            // heli_m_rot_node.reference_point= glm::vec3(0.0, 2.3, 0.0);
-            // heli_m_rot_node.rotation= glm::vec3(0.0, rotation_main, 0.0);
-
-            // let mut heli_t_rot_node = SceneNode::from_vao(my_heli_t_rotor, heli_t_rotor.index_count);
-            // heli_bod_node.add_child(&heli_t_rot_node);
-            // heli_t_rot_node.reference_point= glm::vec3(0.35, 2.3, 10.4);
-            // heli_t_rot_node.rotation= glm::vec3(rotation_tail, 0.0, 0.0);
-            
-            // // det over denne skal fungere fra loopen
-
-            // heli_bod_node.print();
-            // heli_door_node.print();
-            // heli_m_rot_node.print();
-            // heli_t_rot_node.print();
-            ///////
-            let offset = 3.2;
-            let mut nodes: Vec<scene_graph::Node> = Vec::new();
-            //nodes[0] = scene_node;
-
-            for i in 0..5{
-                let mut heading = toolbox::simple_heading_animation(elapsed + offset * &(i as f32));
-                //heli body
-                nodes.push(SceneNode::from_vao(my_heli_body, heli_body.index_count)); 
-                scene_node.add_child(&nodes[i]);
-                nodes[i].position = vec3(heading.x, 20.0, heading.z);
-                
-                //nodes[i].rotation = vec3(heading.roll, heading.yaw, heading.pitch);
-                nodes[i].rotation = vec3(heading.pitch, heading.yaw, heading.roll);
-                //Heli door
-                nodes[i].add_child(&SceneNode::from_vao(my_heli_door, heli_door.index_count)); 
-                nodes[i].get_child(0).reference_point = vec3(0.0, 0.0, 0.0);
-                //heli main rotor
-                nodes[i].add_child(&SceneNode::from_vao(my_heli_m_rotor, heli_m_rotor.index_count));
-                nodes[i].get_child(1).reference_point= glm::vec3(0.0, 2.3, 0.0);
-                nodes[i].get_child(1).rotation= glm::vec3(0.0, rotation_main, 0.0);
-                //heli tail rotor
-                nodes[i].add_child(&SceneNode::from_vao(my_heli_t_rotor, heli_t_rotor.index_count));
-                nodes[i].get_child(2).reference_point= glm::vec3(0.35, 2.3, 10.4);
-                nodes[i].get_child(2).rotation= glm::vec3(rotation_tail, 0.0, 0.0);
-
-
-
-
-
+            // Pick up any changes to the shader files
+            match &mut shaders {
+                Some(shaders) => match unsafe { shaders.reload_if_changed() } {
+                    Ok(true)  => println!("Reloaded shaders"),
+                    Ok(false) => { }
+                    Err(e)    => eprintln!("{}\nKeeping the previous shader", e),
+                },
+                None => {
+                    let times = shader::modification_times(&failed_shader_files);
+                    if times != failed_shader_times {
+                        failed_shader_times = times;
+                        match unsafe { Shaders::load() } {
+                            Ok(loaded) => {
+                                println!("Reloaded shaders");
+                                shaders = Some(loaded);
+                            }
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                // The shaders may include other files now
+                                failed_shader_files = shader_files_to_watch(&e);
+                                failed_shader_times = shader::modification_times(&failed_shader_files);
+                            }
+                        }
+                    }
+                }
             }
 
+            // Animate the helicopters, the scene graph itself is built once before the loop
+            animate_scene(&mut scene, &helicopters, elapsed);
 
-            /////
-            unsafe{
-
-            draw_scene(&root_node,&trans, &glm::Mat4::identity());
+            unsafe {
+                match &shaders {
+                    Some(shaders) => {
+                        let stats = render_frame(shaders, &mut scene, &trans);
+                        if stats != last_stats {
+                            println!("{}", stats);
+                            last_stats = stats;
+                        }
+                    }
+                    None => {
+                        gl::ClearColor(CLEAR_COLOR[0], CLEAR_COLOR[1], CLEAR_COLOR[2], CLEAR_COLOR[3]);
+                        gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+                    }
+                }
             }
 
 
@@ -848,7 +998,7 @@ fn main() {
     let render_thread_healthy = Arc::new(RwLock::new(true));
     let render_thread_watchdog = Arc::clone(&render_thread_healthy);
     thread::spawn(move || {
-        if !render_thread.join().is_ok() {
+        if render_thread.join().is_err() {
             if let Ok(mut health) = render_thread_watchdog.write() {
                 println!("Render thread panicked!");
                 *health = false;
@@ -862,7 +1012,7 @@ fn main() {
 
         // Terminate program if render thread panics
         if let Ok(health) = render_thread_healthy.read() {
-            if *health == false {
+            if !*health {
                 *control_flow = ControlFlow::Exit;
             }
         }
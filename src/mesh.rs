@@ -1,124 +1,1219 @@
-use tobj;
+extern crate nalgebra_glm as glm;
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::bounds::Bounds;
+use crate::gpu_mesh::GpuMesh;
+use crate::lod;
+use crate::mesh_cache;
+use crate::texture::{Texture, TextureOptions};
 
 // internal helper
 fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
     color.iter().cloned().cycle().take(num*4).collect()
 }
 
+// Material
+
+// How the surface of a mesh looks, as described in an MTL file. The colours are multiplied with
+// the vertex colours in the fragment shader.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Material {
+    pub name      : String,
+    pub ambient   : [f32; 3],   // Ka, lit even where the light doesn't reach
+    pub diffuse   : [f32; 3],   // Kd, lit by the light from its direction
+    pub specular  : [f32; 3],   // Ks, the colour of highlights
+    pub shininess : f32,        // Ns, higher for smaller highlights, 0 for none at all
+    pub dissolve  : f32,        // d, the opacity
+
+    // Textures, relative to the working directory
+    pub ambient_texture  : Option<PathBuf>,   // map_Ka
+    pub diffuse_texture  : Option<PathBuf>,   // map_Kd
+    pub specular_texture : Option<PathBuf>,   // map_Ks
+    pub normal_texture   : Option<PathBuf>,   // map_Bump or norm
+}
+
+impl Default for Material {
+    // A plain white surface, which looks the same as a mesh without a material used to
+    fn default() -> Material {
+        Material {
+            name      : String::new(),
+            ambient   : [0.0; 3],
+            diffuse   : [1.0; 3],
+            specular  : [0.0; 3],
+            shininess : 0.0,
+            dissolve  : 1.0,
+            ambient_texture  : None,
+            diffuse_texture  : None,
+            specular_texture : None,
+            normal_texture   : None,
+        }
+    }
+}
+
+impl Material {
+    // `directory` is where the MTL file is, which the texture paths are relative to
+    pub fn from(material: &tobj::Material, directory: &Path) -> Material {
+        let texture = |name: &str| if name.is_empty() { None } else { Some(directory.join(name)) };
+        Material {
+            name      : material.name.clone(),
+            ambient   : material.ambient,
+            diffuse   : material.diffuse,
+            specular  : material.specular,
+            shininess : material.shininess,
+            dissolve  : material.dissolve,
+            ambient_texture  : texture(&material.ambient_texture),
+            diffuse_texture  : texture(&material.diffuse_texture),
+            specular_texture : texture(&material.specular_texture),
+            // tobj only knows of map_Bump and bump
+            normal_texture   : texture(&material.normal_texture)
+                .or_else(|| material.unknown_param.get("norm").and_then(|name| texture(name))),
+        }
+    }
+}
+
+
 // Mesh
 
+// How much each triangle around a vertex counts towards its smooth normal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormalWeighting {
+    Area,   // Larger triangles count more
+    Angle,  // Triangles count by the angle of their corner at the vertex, so that splitting a
+            // triangle in two doesn't change the normals around it
+}
+
+// Something wrong with a mesh, as found by `Mesh::validate`. Triangle t is the one made of
+// indices[3t..3t + 3].
+#[derive(Clone, Debug, PartialEq)]
+pub enum MeshIssue {
+    // Not one value per vertex for an attribute, e.g. 3 for a normal. Normals, texture
+    // coordinates and tangents may also be left out entirely.
+    AttributeCount { attribute: &'static str, expected: usize, actual: usize },
+    PartialTriangle { index_count: usize },         // The number of indices isn't a multiple of 3
+    IndexOutOfBounds { triangle: usize, index: u32 },
+    NonFinitePosition { vertex: u32 },              // NaN or infinite
+    DegenerateTriangle { triangle: usize },         // No area, so no normal and nothing to draw
+    NonManifoldEdge { vertices: [u32; 2], triangles: usize }, // An edge shared by more than two triangles
+    InconsistentWinding { triangles: [usize; 2] },  // Neighbours whose fronts are on opposite sides
+}
+
+impl MeshIssue {
+    // What kind of issue this is, in plural, for counting them
+    fn kind(&self) -> &'static str {
+        match self {
+            MeshIssue::AttributeCount { .. }      => "attributes of the wrong length",
+            MeshIssue::PartialTriangle { .. }     => "partial triangles",
+            MeshIssue::IndexOutOfBounds { .. }    => "indices out of bounds",
+            MeshIssue::NonFinitePosition { .. }   => "non-finite positions",
+            MeshIssue::DegenerateTriangle { .. }  => "degenerate triangles",
+            MeshIssue::NonManifoldEdge { .. }     => "non-manifold edges",
+            MeshIssue::InconsistentWinding { .. } => "inconsistently wound edges",
+        }
+    }
+
+    // How many there are of each kind, e.g. "degenerate triangles: 3, non-manifold edges: 1"
+    pub fn summary(issues: &[MeshIssue]) -> String {
+        let mut counts: Vec<(&str, usize)> = vec![];
+        for issue in issues {
+            match counts.iter_mut().find(|(kind, _)| *kind == issue.kind()) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((issue.kind(), 1)),
+            }
+        }
+        counts.iter().map(|(kind, count)| format!("{}: {}", kind, count)).collect::<Vec<_>>().join(", ")
+    }
+}
+
+impl fmt::Display for MeshIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshIssue::AttributeCount { attribute, expected, actual } =>
+                write!(f, "{} {} values instead of {}", actual, attribute, expected),
+            MeshIssue::PartialTriangle { index_count } =>
+                write!(f, "{} indices, which isn't a whole number of triangles", index_count),
+            MeshIssue::IndexOutOfBounds { triangle, index } =>
+                write!(f, "triangle {} uses vertex {}, which doesn't exist", triangle, index),
+            MeshIssue::NonFinitePosition { vertex } =>
+                write!(f, "vertex {} has a position which isn't finite", vertex),
+            MeshIssue::DegenerateTriangle { triangle } =>
+                write!(f, "triangle {} has no area", triangle),
+            MeshIssue::NonManifoldEdge { vertices: [a, b], triangles } =>
+                write!(f, "the edge between vertex {} and {} is shared by {} triangles", a, b, triangles),
+            MeshIssue::InconsistentWinding { triangles: [a, b] } =>
+                write!(f, "triangle {} and {} are wound in opposite directions", a, b),
+        }
+    }
+}
+
+// An edge between two positions, see `Mesh::position_key`, the lower one first
+type Edge = ([u32; 3], [u32; 3]);
+
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices    : Vec<f32>,
     pub normals     : Vec<f32>,
+    pub texcoords   : Vec<f32>,     // Two per vertex (u, v), or none at all
+    pub tangents    : Vec<f32>,     // Four per vertex, see `compute_tangents`, or none at all
     pub colors      : Vec<f32>,
     pub indices     : Vec<u32>,
     pub index_count : i32,
+    pub material    : Material,
+    pub bounds      : Bounds,       // Around the vertices, see `update_bounds`
 }
 
 impl Mesh {
-    pub fn from(mesh: tobj::Mesh, color: [f32; 4]) -> Self {
+    // The vertex colours are taken from the file if it has them, and are white otherwise.
+    // The mesh is checked with `validate`, and whatever would break drawing it is dropped, see
+    // `drop_broken`. Vertices aren't welded and windings aren't changed, `repair` does that if
+    // wanted. Smooth normals are generated if the file doesn't have a normal for every vertex,
+    // and tangents if the material has a normal map.
+    pub fn from(mesh: tobj::Mesh, material: Material) -> Self {
         let num_verts = mesh.positions.len() / 3;
         let index_count = mesh.indices.len() as i32;
-        Mesh {
+        let colors = if mesh.vertex_color.len() == num_verts * 3 {
+            mesh.vertex_color.chunks_exact(3).flat_map(|c| [c[0], c[1], c[2], 1.0]).collect()
+        } else {
+            generate_color_vec([1.0, 1.0, 1.0, 1.0], num_verts)
+        };
+        let mut mesh = Mesh {
             vertices: mesh.positions,
             normals: mesh.normals,
+            texcoords: mesh.texcoords,
+            tangents: vec![],
             indices: mesh.indices,
-            colors: generate_color_vec(color, num_verts),
+            colors,
             index_count,
+            material,
+            bounds: Bounds::empty(),
+        };
+        let issues = mesh.validate();
+        if !issues.is_empty() {
+            eprintln!("  Found {}", MeshIssue::summary(&issues));
+            let dropped = mesh.drop_broken();
+            if dropped > 0 {
+                eprintln!("  Dropped {} broken triangles", dropped);
+            }
+        }
+        if !mesh.has_normals() {
+            mesh.compute_smooth_normals(NormalWeighting::Angle);
         }
+        if mesh.material.normal_texture.is_some() {
+            mesh.compute_tangents();
+        }
+        mesh.update_bounds();
+        mesh
     }
-}
 
-// Lunar terrain
+    // A mesh of white triangles, without normals, texture coordinates or a material
+    pub fn from_positions(vertices: Vec<f32>, indices: Vec<u32>) -> Mesh {
+        let mut mesh = Mesh {
+            colors: generate_color_vec([1.0, 1.0, 1.0, 1.0], vertices.len() / 3),
+            vertices,
+            normals: vec![],
+            texcoords: vec![],
+            tangents: vec![],
+            index_count: indices.len() as i32,
+            indices,
+            material: Material::default(),
+            bounds: Bounds::empty(),
+        };
+        mesh.update_bounds();
+        mesh
+    }
 
-pub struct Terrain;
-impl Terrain {
-    pub fn load(path: &str) -> Mesh {
-        println!("Loading terrain model...");
-        let before = std::time::Instant::now();
-        let (models, _materials)
-            = tobj::load_obj(path,
-                &tobj::LoadOptions{
-                    triangulate: true,
-                    single_index: true,
-                    ..Default::default()
+    pub fn set_color(&mut self, color: [f32; 4]) {
+        self.colors = generate_color_vec(color, self.vertices.len() / 3);
+    }
+
+    // Computes the box and sphere around the vertices, which has to be done again whenever the
+    // vertices are changed
+    pub fn update_bounds(&mut self) {
+        self.bounds = Bounds::around(&self.vertices);
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vertices.len() / 3
+    }
+
+    // Whether there is exactly one normal per vertex
+    pub fn has_normals(&self) -> bool {
+        self.normals.len() == self.vertices.len()
+    }
+
+    // Whether there is exactly one tangent per vertex
+    pub fn has_tangents(&self) -> bool {
+        !self.tangents.is_empty() && self.tangents.len() == 4 * self.vertex_count()
+    }
+
+    // Computes a tangent for every vertex from the normals and texture coordinates, for normal
+    // mapping. It follows the conventions of MikkTSpace, which most tools bake normal maps with:
+    // the tangent points along increasing u and is orthogonal to the normal, and w is 1 or -1 for
+    // whether the bitangent is cross(normal, tangent) or the opposite, i.e. whether the texture
+    // is mirrored. The bitangent is not stored, but rebuilt from these in the fragment shader.
+    //
+    // Like in MikkTSpace, each triangle counts by the angle of its corner. Unlike it, vertices
+    // where mirrored and unmirrored triangles meet are not split, so the tangents can differ from
+    // those of the baking tool along such seams.
+    //
+    // Meshes without normals or texture coordinates get no tangents.
+    pub fn compute_tangents(&mut self) {
+        let vertex_count = self.vertex_count();
+        self.tangents.clear();
+        if !self.has_normals() || self.texcoords.len() != 2 * vertex_count {
+            return;
+        }
+
+        let mut tangents = vec![glm::Vec3::zeros(); vertex_count];
+        let mut bitangents = vec![glm::Vec3::zeros(); vertex_count];
+        for triangle in self.triangles() {
+            let [a, b, c] = triangle;
+            let (edge_1, edge_2) = (self.position(b) - self.position(a), self.position(c) - self.position(a));
+            let (delta_1, delta_2) = (self.texcoord(b) - self.texcoord(a), self.texcoord(c) - self.texcoord(a));
+            let determinant = delta_1.x * delta_2.y - delta_2.x * delta_1.y;
+            if determinant == 0.0 || !determinant.is_finite() {
+                continue;
+            }
+            // The directions of increasing u and v along the triangle
+            let u_direction = (edge_1 * delta_2.y - edge_2 * delta_1.y) / determinant;
+            let v_direction = (edge_2 * delta_1.x - edge_1 * delta_2.x) / determinant;
+
+            for (corner, &vertex) in triangle.iter().enumerate() {
+                let normal = self.normal(vertex);
+                let angle = self.corner_angle(triangle, corner);
+                tangents[vertex as usize] += project_onto_plane(&u_direction, &normal) * angle;
+                bitangents[vertex as usize] += project_onto_plane(&v_direction, &normal) * angle;
+            }
+        }
+
+        for vertex in 0..vertex_count {
+            let normal = self.normal(vertex as u32);
+            let mut tangent = project_onto_plane(&tangents[vertex], &normal);
+            if tangent == glm::Vec3::zeros() {
+                // No texture coordinates to follow, any direction along the surface will do
+                let axis = if normal.x.abs() < 0.9 { glm::Vec3::x() } else { glm::Vec3::y() };
+                tangent = project_onto_plane(&axis, &normal);
+            }
+            let handedness = if normal.cross(&tangent).dot(&bitangents[vertex]) < 0.0 { -1.0 } else { 1.0 };
+            self.tangents.extend_from_slice(&[tangent.x, tangent.y, tangent.z, handedness]);
+        }
+    }
+
+    // Three per vertex, as the fragment shader computes them from the normals and tangents
+    pub fn bitangents(&self) -> Vec<f32> {
+        self.normals.chunks_exact(3).zip(self.tangents.chunks_exact(4))
+            .flat_map(|(normal, tangent)| {
+                let bitangent = glm::make_vec3(normal).cross(&glm::make_vec3(&tangent[..3])) * tangent[3];
+                [bitangent.x, bitangent.y, bitangent.z]
+            })
+            .collect()
+    }
+
+    // Gives every vertex the average normal of the triangles around it, so that the surface looks
+    // curved. Vertices at the same position share the normal even if they are separate vertices,
+    // e.g. because they have different texture coordinates, so no seams show up in the lighting.
+    pub fn compute_smooth_normals(&mut self, weighting: NormalWeighting) {
+        self.compute_normals(weighting, std::f32::consts::PI);
+    }
+
+    // Gives every triangle its own three vertices, with the normal of the triangle, so that the
+    // surface looks faceted
+    pub fn compute_flat_normals(&mut self) {
+        let triangles: Vec<[u32; 3]> = self.triangles().collect();
+        let corners: Vec<(u32, glm::Vec3)> = triangles.iter()
+            .flat_map(|&triangle| {
+                let normal = self.face_normal(triangle).map_or_else(default_normal, |n| n.normalize());
+                triangle.iter().map(move |&vertex| (vertex, normal)).collect::<Vec<_>>()
+            })
+            .collect();
+        self.rebuild_with_normals(&corners);
+    }
+
+    // Smooth normals, except across edges where the triangles on either side meet at more than
+    // `crease_angle` (in radians), which stay sharp. The vertices along such edges are split in
+    // two, one for each side. A crease angle of pi gives the same result as
+    // `compute_smooth_normals`.
+    pub fn compute_normals(&mut self, weighting: NormalWeighting, crease_angle: f32) {
+        let triangles: Vec<[u32; 3]> = self.triangles().collect();
+        let face_normals: Vec<Option<glm::Vec3>> = triangles.iter()
+            .map(|&triangle| self.face_normal(triangle))
+            .collect();
+
+        // The corners of the triangles at each position, as (triangle, corner) pairs
+        let mut corners_at: HashMap<[u32; 3], Vec<(usize, usize)>> = HashMap::new();
+        for (t, triangle) in triangles.iter().enumerate() {
+            for (corner, &vertex) in triangle.iter().enumerate() {
+                corners_at.entry(self.position_key(vertex)).or_default().push((t, corner));
+            }
+        }
+
+        let min_cos = crease_angle.cos();
+        let mut corners = Vec::with_capacity(triangles.len() * 3);
+        for (t, triangle) in triangles.iter().enumerate() {
+            for (corner, &vertex) in triangle.iter().enumerate() {
+                let own_normal = match face_normals[t] {
+                    Some(normal) => normal.normalize(),
+                    None => {
+                        corners.push((vertex, default_normal()));
+                        continue;
+                    }
+                };
+                let sum = corners_at[&self.position_key(vertex)].iter()
+                    .filter_map(|&(other, other_corner)| {
+                        let normal = face_normals[other]?;
+                        let unit = normal.normalize();
+                        if other != t && unit.dot(&own_normal) < min_cos {
+                            return None;
+                        }
+                        Some(match weighting {
+                            NormalWeighting::Area  => normal,
+                            NormalWeighting::Angle => unit * self.corner_angle(triangles[other], other_corner),
+                        })
+                    })
+                    .fold(glm::Vec3::zeros(), |sum, normal| sum + normal);
+                let normal = if sum.norm() > 0.0 { sum.normalize() } else { own_normal };
+                corners.push((vertex, normal));
+            }
+        }
+        self.rebuild_with_normals(&corners);
+    }
+
+    // A copy with at most `triangle_count` triangles, made by collapsing the edges whose removal
+    // changes the shape the least, see `lod.rs`
+    pub fn simplify(&self, triangle_count: usize) -> Mesh {
+        lod::simplify(self, triangle_count)
+    }
+
+    // Everything wrong with the mesh, from what would make it fail to draw (wrong attribute
+    // counts, indices out of bounds) to what only looks wrong (degenerate triangles, inconsistent
+    // winding). Edges are between positions rather than vertices, so that vertices split along
+    // e.g. a texture seam still count as connected. Triangles with indices out of bounds or
+    // non-finite positions are not checked further.
+    pub fn validate(&self) -> Vec<MeshIssue> {
+        let mut issues = vec![];
+        let vertex_count = self.vertex_count();
+        let attributes = [
+            ("position", self.vertices.len(),  3, false),
+            ("normal",   self.normals.len(),   3, true),
+            ("texcoord", self.texcoords.len(), 2, true),
+            ("tangent",  self.tangents.len(),  4, true),
+            ("color",    self.colors.len(),    4, false),
+        ];
+        for &(attribute, actual, per_vertex, optional) in attributes.iter() {
+            let expected = vertex_count * per_vertex;
+            if actual != expected && !(optional && actual == 0) {
+                issues.push(MeshIssue::AttributeCount { attribute, expected, actual });
+            }
+        }
+        // `is_multiple_of` is only in Rust 1.87 and newer
+        #[allow(clippy::manual_is_multiple_of)]
+        if self.indices.len() % 3 != 0 {
+            issues.push(MeshIssue::PartialTriangle { index_count: self.indices.len() });
+        }
+
+        let finite: Vec<bool> = self.vertices.chunks_exact(3)
+            .map(|p| p.iter().all(|c| c.is_finite()))
+            .collect();
+        for (vertex, _) in finite.iter().enumerate().filter(|(_, &finite)| !finite) {
+            issues.push(MeshIssue::NonFinitePosition { vertex: vertex as u32 });
+        }
+
+        let mut checked = vec![];
+        for (t, triangle) in self.triangles().enumerate() {
+            if let Some(&index) = triangle.iter().find(|&&index| index as usize >= vertex_count) {
+                issues.push(MeshIssue::IndexOutOfBounds { triangle: t, index });
+            } else if triangle.iter().all(|&vertex| finite[vertex as usize]) {
+                if self.face_normal(triangle).is_none() {
+                    issues.push(MeshIssue::DegenerateTriangle { triangle: t });
+                } else {
+                    checked.push(t);
                 }
-            ).expect("Failed to load terrain model");
-        let after = std::time::Instant::now();
-        println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
+            }
+        }
 
-        if models.len() > 1 || models.len() == 0 {
-            panic!("Please use a model with a single mesh!")
-            // You could try merging the vertices and indices
-            // of the separate meshes into a single mesh.
-            // I'll leave that as an optional exercise. ;)
+        for ((low, high), sides) in self.edges(&checked) {
+            match sides.as_slice() {
+                &[(a, forward_a), (b, forward_b)] if forward_a == forward_b => {
+                    issues.push(MeshIssue::InconsistentWinding { triangles: [a, b] });
+                }
+                _ if sides.len() > 2 => {
+                    // Named by the vertices of the first triangle along the edge
+                    let vertex_at = |key: [u32; 3]| {
+                        let t = sides[0].0;
+                        *self.indices[3 * t..3 * t + 3].iter().find(|&&v| self.position_key(v) == key).unwrap()
+                    };
+                    issues.push(MeshIssue::NonManifoldEdge {
+                        vertices  : [vertex_at(low), vertex_at(high)],
+                        triangles : sides.len(),
+                    });
+                }
+                _ => { }
+            }
         }
+        issues
+    }
 
-        let terrain = models[0].to_owned();
-        println!("Loaded {} with {} points and {} triangles.",
-            terrain.name,
-            terrain.mesh.positions.len() /3,
-            terrain.mesh.indices.len() / 3,
-        );
+    // Fixes what `validate` finds, as far as that can be done, by `drop_broken`, `weld` and then
+    // `unify_winding`. Non-manifold edges are left as they are.
+    pub fn repair(&mut self) {
+        self.drop_broken();
+        self.weld();
+        self.unify_winding();
+    }
+
+    // Drops what would break drawing the mesh, which can't change how the rest of it looks:
+    //     - attributes of the wrong length are dropped, or made white for the colours
+    //     - partial triangles, and those with indices out of bounds, non-finite positions or no
+    //       area are dropped
+    // Returns the number of triangles dropped.
+    pub fn drop_broken(&mut self) -> usize {
+        let vertex_count = self.vertex_count();
+        self.vertices.truncate(vertex_count * 3);
+        if self.normals.len() != vertex_count * 3 {
+            self.normals.clear();
+        }
+        if self.texcoords.len() != vertex_count * 2 {
+            self.texcoords.clear();
+        }
+        if self.tangents.len() != vertex_count * 4 {
+            self.tangents.clear();
+        }
+        if self.colors.len() != vertex_count * 4 {
+            self.set_color([1.0, 1.0, 1.0, 1.0]);
+        }
+
+        let triangle_count = self.indices.len() / 3;
+        let finite = |mesh: &Mesh, vertex: u32| mesh.position(vertex).iter().all(|c| c.is_finite());
+        self.indices = self.triangles()
+            .filter(|triangle| triangle.iter().all(|&vertex| (vertex as usize) < vertex_count && finite(self, vertex)))
+            .filter(|&triangle| self.face_normal(triangle).is_some())
+            .flatten()
+            .collect();
+        self.index_count = self.indices.len() as i32;
+        self.update_bounds();
+        triangle_count - self.indices.len() / 3
+    }
+
+    // Merges vertices which are exactly the same in every attribute, and drops those no triangle
+    // uses. The vertices are renumbered in the order they are first used. This changes nothing
+    // about how the mesh looks, but any other data kept per vertex no longer lines up. Expects
+    // every index to be in bounds, see `drop_broken`. Returns how many vertices are gone.
+    pub fn weld(&mut self) -> usize {
+        let vertex_count = self.vertex_count();
+        let has_texcoords = self.texcoords.len() == vertex_count * 2;
+        let attributes = [
+            (&self.vertices, 3),
+            (&self.normals, 3),
+            (&self.texcoords, 2),
+            (&self.tangents, 4),
+            (&self.colors, 4),
+        ];
+        // + 0.0 turns -0.0 into 0.0
+        let key = |v: usize| -> Vec<u32> {
+            attributes.iter()
+                .filter(|(values, n)| values.len() == vertex_count * n)
+                .flat_map(|(values, n)| values[n * v..n * v + n].iter().map(|value| (value + 0.0).to_bits()))
+                .collect()
+        };
+
+        let mut kept = vec![];  // The old index of every new vertex
+        let mut new_index = vec![None; vertex_count];
+        let mut first_with: HashMap<Vec<u32>, u32> = HashMap::new();
+        let indices: Vec<u32> = self.indices.iter()
+            .map(|&v| *new_index[v as usize].get_or_insert_with(|| {
+                *first_with.entry(key(v as usize)).or_insert_with(|| {
+                    kept.push(v as usize);
+                    (kept.len() - 1) as u32
+                })
+            }))
+            .collect();
+
+        let gather = |values: &[f32], n: usize| -> Vec<f32> {
+            kept.iter().flat_map(|&v| values[n * v..n * v + n].iter().copied()).collect()
+        };
+        self.vertices = gather(&self.vertices, 3);
+        if self.normals.len() == vertex_count * 3 {
+            self.normals = gather(&self.normals, 3);
+        }
+        if has_texcoords {
+            self.texcoords = gather(&self.texcoords, 2);
+        }
+        if self.tangents.len() == vertex_count * 4 {
+            self.tangents = gather(&self.tangents, 4);
+        }
+        self.colors = gather(&self.colors, 4);
+        self.indices = indices;
+        vertex_count - self.vertex_count()
+    }
+
+    // Turns triangles around so that neighbours agree on which side is the front. Each connected
+    // part is made to face the way most of its normals point, or outwards if there are no normals
+    // and the part is closed. Meshes which are meant to have both sides showing may end up
+    // looking different. Expects every triangle to have an area, see `drop_broken`. Returns how
+    // many triangles were turned around.
+    pub fn unify_winding(&mut self) -> usize {
+        let triangles: Vec<[u32; 3]> = self.triangles().collect();
+        let all: Vec<usize> = (0..triangles.len()).collect();
+
+        // Neighbours across edges shared by exactly two triangles, and whether one of the two
+        // has to be turned around for them to agree
+        let mut neighbours: Vec<Vec<(usize, bool)>> = vec![vec![]; triangles.len()];
+        let mut on_border = vec![false; triangles.len()];
+        for sides in self.edges(&all).values() {
+            match sides.as_slice() {
+                &[(a, forward_a), (b, forward_b)] => {
+                    neighbours[a].push((b, forward_a == forward_b));
+                    neighbours[b].push((a, forward_a == forward_b));
+                }
+                _ => sides.iter().for_each(|&(t, _)| on_border[t] = true),
+            }
+        }
+
+        let mut flipped: Vec<Option<bool>> = vec![None; triangles.len()];
+        for seed in 0..triangles.len() {
+            if flipped[seed].is_some() {
+                continue;
+            }
+            flipped[seed] = Some(false);
+            let mut part = vec![];
+            let mut queue = VecDeque::from(vec![seed]);
+            while let Some(t) = queue.pop_front() {
+                part.push(t);
+                let flip = flipped[t].unwrap();
+                for &(other, disagree) in &neighbours[t] {
+                    if flipped[other].is_none() {
+                        flipped[other] = Some(flip != disagree);
+                        queue.push_back(other);
+                    }
+                }
+            }
+
+            // Now that the part agrees with the seed, decide which way the whole part should face
+            let facing = |t: usize| {
+                let normal = self.face_normal(triangles[t]).unwrap();
+                if flipped[t] == Some(true) { -normal } else { normal }
+            };
+            let backwards = if self.has_normals() {
+                let agreement: f32 = part.iter()
+                    .map(|&t| facing(t).dot(&triangles[t].iter().map(|&v| self.normal(v)).sum::<glm::Vec3>()))
+                    .sum();
+                agreement < 0.0
+            } else if part.iter().all(|&t| !on_border[t]) {
+                // Positive volume if the triangles face outwards
+                let volume: f32 = part.iter()
+                    .map(|&t| {
+                        let [a, b, c] = triangles[t];
+                        let (a, b, c) = (self.position(a), self.position(b), self.position(c));
+                        let signed = a.dot(&b.cross(&c));
+                        if flipped[t] == Some(true) { -signed } else { signed }
+                    })
+                    .sum();
+                volume < 0.0
+            } else {
+                false
+            };
+            if backwards {
+                part.iter().for_each(|&t| flipped[t] = flipped[t].map(|flip| !flip));
+            }
+        }
+
+        let mut turned = 0;
+        for (triangle, flip) in self.indices.chunks_exact_mut(3).zip(flipped) {
+            if flip == Some(true) {
+                triangle.swap(1, 2);
+                turned += 1;
+            }
+        }
+        turned
+    }
+
+    // The given triangles along each edge, and whether they go along it from the lower position
+    // key to the higher. Edges are between positions, see `position_key`, and edges between two
+    // vertices at the same position are left out.
+    fn edges(&self, triangles: &[usize]) -> HashMap<Edge, Vec<(usize, bool)>> {
+        let mut edges: HashMap<_, Vec<(usize, bool)>> = HashMap::new();
+        for &t in triangles {
+            for corner in 0..3 {
+                let from = self.position_key(self.indices[3 * t + corner]);
+                let to = self.position_key(self.indices[3 * t + (corner + 1) % 3]);
+                if from != to {
+                    let key = if from < to { (from, to) } else { (to, from) };
+                    edges.entry(key).or_default().push((t, from < to));
+                }
+            }
+        }
+        edges
+    }
+
+    fn triangles(&self) -> impl Iterator<Item = [u32; 3]> + '_ {
+        self.indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]])
+    }
+
+    fn position(&self, vertex: u32) -> glm::Vec3 {
+        glm::make_vec3(&self.vertices[3 * vertex as usize..3 * vertex as usize + 3])
+    }
+
+    fn normal(&self, vertex: u32) -> glm::Vec3 {
+        glm::make_vec3(&self.normals[3 * vertex as usize..3 * vertex as usize + 3])
+    }
+
+    fn texcoord(&self, vertex: u32) -> glm::Vec2 {
+        glm::make_vec2(&self.texcoords[2 * vertex as usize..2 * vertex as usize + 2])
+    }
+
+    // Vertices with exactly the same position are the same point of the surface
+    fn position_key(&self, vertex: u32) -> [u32; 3] {
+        let p = self.position(vertex);
+        // + 0.0 turns -0.0 into 0.0
+        [(p.x + 0.0).to_bits(), (p.y + 0.0).to_bits(), (p.z + 0.0).to_bits()]
+    }
+
+    // Counter-clockwise is the front. The length is twice the area of the triangle, and there is
+    // no normal if the area is 0.
+    fn face_normal(&self, [a, b, c]: [u32; 3]) -> Option<glm::Vec3> {
+        let (a, b, c) = (self.position(a), self.position(b), self.position(c));
+        let normal = (b - a).cross(&(c - a));
+        if normal.norm() > 0.0 { Some(normal) } else { None }
+    }
+
+    fn corner_angle(&self, triangle: [u32; 3], corner: usize) -> f32 {
+        let at = self.position(triangle[corner]);
+        let to_next = self.position(triangle[(corner + 1) % 3]) - at;
+        let to_previous = self.position(triangle[(corner + 2) % 3]) - at;
+        glm::angle(&to_next, &to_previous)
+    }
+
+    // Sets the normal of every corner of every triangle, given in the same order as the indices.
+    // A vertex whose corners get different normals keeps the first one, and is copied to the end
+    // for each of the others. Tangents depend on the normals, so they are computed again.
+    fn rebuild_with_normals(&mut self, corners: &[(u32, glm::Vec3)]) {
+        let vertex_count = self.vertex_count();
+        let had_tangents = !self.tangents.is_empty();
+        self.tangents.clear();
+        let has_texcoords = self.texcoords.len() / 2 == vertex_count;
+        if !has_texcoords {
+            self.texcoords.clear();
+        }
+        let key = |normal: &glm::Vec3| [normal.x.to_bits(), normal.y.to_bits(), normal.z.to_bits()];
 
-        Mesh::from(terrain.mesh, [1.0, 1.0, 1.0, 1.0])
+        self.normals = default_normal().as_slice().repeat(vertex_count);
+        let mut normal_of: Vec<Option<[u32; 3]>> = vec![None; vertex_count];
+        let mut copies: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+        let mut indices = Vec::with_capacity(corners.len());
+        for &(vertex, normal) in corners {
+            let v = vertex as usize;
+            let index = match normal_of[v] {
+                None => {
+                    normal_of[v] = Some(key(&normal));
+                    self.normals[3*v..3*v + 3].copy_from_slice(normal.as_slice());
+                    vertex
+                }
+                Some(k) if k == key(&normal) => vertex,
+                Some(_) => *copies.entry((vertex, key(&normal))).or_insert_with(|| {
+                    self.vertices.extend_from_within(3*v..3*v + 3);
+                    self.normals.extend_from_slice(normal.as_slice());
+                    if has_texcoords {
+                        self.texcoords.extend_from_within(2*v..2*v + 2);
+                    }
+                    self.colors.extend_from_within(4*v..4*v + 4);
+                    (self.vertices.len() / 3 - 1) as u32
+                }),
+            };
+            indices.push(index);
+        }
+        self.indices = indices;
+        self.index_count = self.indices.len() as i32;
+        if had_tangents {
+            self.compute_tangents();
+        }
     }
+
+    // Combines several meshes into one, shifting the indices of each mesh past the vertices of
+    // the ones before it. Meshes without normals get smooth ones, and texture coordinates and
+    // tangents are only kept if every mesh has them. A mesh has only one material, so the merged mesh gets
+    // that of the first mesh.
+    pub fn merge<'a, I: IntoIterator<Item = &'a Mesh>>(meshes: I) -> Mesh {
+        let mut merged = Mesh {
+            vertices: vec![],
+            normals: vec![],
+            texcoords: vec![],
+            tangents: vec![],
+            colors: vec![],
+            indices: vec![],
+            index_count: 0,
+            material: Material::default(),
+            bounds: Bounds::empty(),
+        };
+        let mut all_have_texcoords = true;
+        let mut all_have_tangents = true;
+        for (i, mesh) in meshes.into_iter().enumerate() {
+            if i == 0 {
+                merged.material = mesh.material.clone();
+            }
+            let with_normals;
+            let mesh = if mesh.has_normals() {
+                mesh
+            } else {
+                let mut copy = mesh.clone();
+                copy.compute_smooth_normals(NormalWeighting::Angle);
+                with_normals = copy;
+                &with_normals
+            };
+            let base = (merged.vertices.len() / 3) as u32;
+            merged.vertices.extend_from_slice(&mesh.vertices);
+            merged.normals.extend_from_slice(&mesh.normals);
+            merged.texcoords.extend_from_slice(&mesh.texcoords);
+            merged.tangents.extend_from_slice(&mesh.tangents);
+            merged.colors.extend_from_slice(&mesh.colors);
+            merged.indices.extend(mesh.indices.iter().map(|i| i + base));
+            all_have_texcoords &= mesh.texcoords.len() / 2 == mesh.vertices.len() / 3;
+            all_have_tangents &= mesh.has_tangents();
+        }
+        if !all_have_texcoords {
+            merged.texcoords.clear();
+        }
+        if !all_have_tangents {
+            merged.tangents.clear();
+        }
+        merged.index_count = merged.indices.len() as i32;
+        merged.update_bounds();
+        merged
+    }
+}
+
+// For triangles without an area, which have no direction of their own
+fn default_normal() -> glm::Vec3 {
+    glm::vec3(0.0, 1.0, 0.0)
 }
 
+// The direction of `v` along the plane with the normal `normal`, or 0 if `v` is perpendicular
+// to the plane
+fn project_onto_plane(v: &glm::Vec3, normal: &glm::Vec3) -> glm::Vec3 {
+    let projected = v - normal * normal.dot(v);
+    if projected.norm() > 0.0 { projected.normalize() } else { glm::Vec3::zeros() }
+}
 
-// Helicopter
 
-pub struct Helicopter {
-    pub body       : Mesh,
-    pub door       : Mesh,
-    pub main_rotor : Mesh,
-    pub tail_rotor : Mesh,
+// Model
+
+// Everything in an OBJ file, as one mesh per object
+pub struct Model {
+    pub name    : String,   // The file name, without the extension
+    pub objects : Vec<ModelObject>,
+}
+
+pub struct ModelObject {
+    pub name           : String,                // As given by `o` or `g` in the file
+    pub mesh           : Rc<Mesh>,
+    pub gpu_mesh       : Option<Rc<GpuMesh>>,   // Set by `Model::upload`
+    pub texture        : Option<Rc<Texture>>,   // The diffuse texture of the material, set by `Model::upload`
+    pub normal_texture : Option<Rc<Texture>>,   // The normal map of the material, set by `Model::upload`
 }
 
-// You can use square brackets to access the components of the helicopter, if you want to use loops!
-use std::ops::Index;
-impl Index<usize> for Helicopter {
-    type Output = Mesh;
-    fn index<'a>(&'a self, i: usize) -> &'a Mesh {
-        match i {
-            0 => &self.body,
-            1 => &self.main_rotor,
-            2 => &self.tail_rotor,
-            3 => &self.door,
-            _ => panic!("Invalid index, try [0,3]"),
+impl Model {
+    // Loads the model from the cache next to the OBJ file if the files it was made from haven't
+    // changed, see `mesh_cache`. Otherwise the OBJ file is parsed, and the cache written for the
+    // next time.
+    pub fn load(path: &str) -> Result<Model, tobj::LoadError> {
+        let before = std::time::Instant::now();
+        match mesh_cache::load(Path::new(path)) {
+            Ok(Some(model)) => {
+                println!("Loaded {} from its cache in {:.3}ms.", path, before.elapsed().as_micros() as f32 / 1e3);
+                return Ok(model);
+            }
+            Ok(None) => { }
+            Err(e) => eprintln!("Ignoring the broken cache of {}: {}", path, e),
+        }
+
+        let model = Model::load_obj(path)?;
+        if let Err(e) = mesh_cache::save(Path::new(path), &model) {
+            eprintln!("Failed to write the cache of {}: {}", path, e);
         }
+        Ok(model)
     }
-}
 
-impl Helicopter {
-    pub fn load(path: &str) -> Self {
-        println!("Loading helicopter model...");
+    // Loads every object in the file, along with its material, without going through the cache.
+    //
+    // The materials are taken from the MTL files the OBJ file refers to. Objects without a
+    // material get the one with the same name as the object from the MTL file next to the OBJ
+    // file, e.g. `helicopter.mtl`, so that materials can be given to an OBJ file which has none.
+    // Objects without either are plain white.
+    pub fn load_obj(path: &str) -> Result<Model, tobj::LoadError> {
+        println!("Loading {}...", path);
         let before = std::time::Instant::now();
-        let (models, _materials)
+        let (models, materials)
             = tobj::load_obj(path,
                 &tobj::LoadOptions{
                     triangulate: true,
                     single_index: true,
                     ..Default::default()
                 }
-            ).expect("Failed to load helicopter model");
+            )?;
         let after = std::time::Instant::now();
-        println!("Done in {:.3}ms!", after.duration_since(before).as_micros() as f32 / 1e3);
+        println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
+
+        let path = Path::new(path);
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        let materials = materials.unwrap_or_else(|e| {
+            eprintln!("Failed to load the materials of {}: {}", path.display(), e);
+            vec![]
+        });
+        let sibling_path = path.with_extension("mtl");
+        let sibling_materials = if models.iter().any(|m| m.mesh.material_id.and_then(|id| materials.get(id)).is_none())
+            && sibling_path.exists()
+        {
+            match tobj::load_mtl(&sibling_path) {
+                Ok((materials, _)) => materials,
+                Err(e) => {
+                    eprintln!("Failed to load {}: {}", sibling_path.display(), e);
+                    vec![]
+                }
+            }
+        } else {
+            vec![]
+        };
 
-        for model in &models {
+        let name = path.file_stem()
+            .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+        let objects = models.into_iter().map(|model| {
+            let material = model.mesh.material_id.and_then(|id| materials.get(id))
+                .or_else(|| sibling_materials.iter().find(|m| m.name == model.name))
+                .map_or_else(Material::default, |m| Material::from(m, directory));
             println!("Loaded {} with {} points and {} triangles.", model.name, model.mesh.positions.len() / 3, model.mesh.indices.len() / 3);
+            ModelObject {
+                name           : model.name,
+                mesh           : Rc::new(Mesh::from(model.mesh, material)),
+                gpu_mesh       : None,
+                texture        : None,
+                normal_texture : None,
+            }
+        }).collect();
+        Ok(Model { name, objects })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ModelObject> {
+        self.objects.iter().find(|object| object.name == name)
+    }
+
+    // Panics if there is no such object, since the model file is then not the one we expect
+    pub fn set_color(&mut self, name: &str, color: [f32; 4]) {
+        let model_name = &self.name;
+        let object = self.objects.iter_mut().find(|object| object.name == name)
+            .unwrap_or_else(|| panic!("No object called {} in {}", name, model_name));
+        Rc::make_mut(&mut object.mesh).set_color(color);
+    }
+
+    // All the objects as a single mesh
+    pub fn merged_mesh(&self) -> Mesh {
+        Mesh::merge(self.objects.iter().map(|object| object.mesh.as_ref()))
+    }
+
+    // Turns the model into one with a single object, named after the model
+    pub fn merge(self) -> Model {
+        let mesh = self.merged_mesh();
+        Model {
+            objects: vec![ModelObject {
+                name           : self.name.clone(),
+                mesh           : Rc::new(mesh),
+                gpu_mesh       : None,
+                texture        : None,
+                normal_texture : None,
+            }],
+            name: self.name,
+        }
+    }
+
+    // Uploads every object to the GPU along with the diffuse textures and normal maps of the
+    // materials, which requires an active OpenGL context. Textures which fail to load are left
+    // out.
+    pub unsafe fn upload(&mut self) {
+        let mut textures: HashMap<PathBuf, Option<Rc<Texture>>> = HashMap::new();
+        let mut load_texture = |path: &Option<PathBuf>| {
+            let path = path.as_ref()?;
+            textures.entry(path.clone()).or_insert_with(|| {
+                match Texture::load(path, &TextureOptions::default()) {
+                    Ok(texture) => Some(Rc::new(texture)),
+                    Err(e) => {
+                        eprintln!("Failed to load texture {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            }).clone()
+        };
+        for object in &mut self.objects {
+            object.gpu_mesh = Some(Rc::new(GpuMesh::new(&object.mesh)));
+            object.texture = load_texture(&object.mesh.material.diffuse_texture);
+            object.normal_texture = load_texture(&object.mesh.material.normal_texture);
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cube from -1 to 1 along every axis, with one vertex per corner and the triangles facing
+    // outwards. Vertex 4x + 2y + z is at the corner with those coordinates positive.
+    pub fn cube() -> Mesh {
+        let vertices = (0..8)
+            .flat_map(|v| [v & 4, v & 2, v & 1].map(|bit| if bit != 0 { 1.0 } else { -1.0 }))
+            .collect();
+        let mut indices = vec![];
+        for axis in 0..3 {
+            for &side in &[0, 1] {
+                let (b, c) = ((axis + 1) % 3, (axis + 2) % 3);
+                let corner = |along_b: u32, along_c: u32| {
+                    let mut bits = [0; 3];
+                    bits[axis] = side;
+                    bits[b] = along_b;
+                    bits[c] = along_c;
+                    bits[0] * 4 + bits[1] * 2 + bits[2]
+                };
+                let quad = [corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1)];
+                // Counter-clockwise seen from the positive side of the axis, so reversed on the other
+                let quad = if side == 1 { quad } else { [quad[3], quad[2], quad[1], quad[0]] };
+                indices.extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+            }
+        }
+        Mesh::from_positions(vertices, indices)
+    }
+
+    // A unit square in the XY plane facing +Z, with texture coordinates following X and Y, or
+    // with U running the other way if `mirrored`
+    fn quad(mirrored: bool) -> Mesh {
+        let mut mesh = Mesh::from_positions(
+            vec![0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  1.0, 1.0, 0.0,  0.0, 1.0, 0.0],
+            vec![0, 1, 2, 0, 2, 3],
+        );
+        mesh.texcoords = vec![0.0, 0.0,  1.0, 0.0,  1.0, 1.0,  0.0, 1.0];
+        if mirrored {
+            mesh.texcoords.chunks_exact_mut(2).for_each(|uv| uv[0] = 1.0 - uv[0]);
+        }
+        mesh.normals = [0.0, 0.0, 1.0].repeat(4);
+        mesh
+    }
+
+    fn assert_close(actual: &glm::Vec3, expected: &glm::Vec3) {
+        assert!(glm::distance(actual, expected) < 1e-5, "{:?} is not {:?}", actual, expected);
+    }
+
+    #[test]
+    fn cube_faces_outwards() {
+        let mesh = cube();
+        for triangle in mesh.triangles() {
+            let center = (mesh.position(triangle[0]) + mesh.position(triangle[1]) + mesh.position(triangle[2])) / 3.0;
+            assert!(mesh.face_normal(triangle).unwrap().dot(&center) > 0.0);
+        }
+    }
+
+    #[test]
+    fn smooth_normals_point_away_from_the_corner() {
+        let mut mesh = cube();
+        mesh.compute_smooth_normals(NormalWeighting::Angle);
+        assert_eq!(mesh.vertex_count(), 8);
+        for vertex in 0..8 {
+            // Every corner has a right angle on each of the three faces around it
+            assert_close(&mesh.normal(vertex), &mesh.position(vertex).normalize());
+        }
+
+        // Larger triangles count more, which depends on how the faces are split, but the
+        // normals still point outwards
+        mesh.compute_smooth_normals(NormalWeighting::Area);
+        for vertex in 0..8 {
+            assert!(mesh.normal(vertex).dot(&mesh.position(vertex)) > 0.0);
+        }
+    }
+
+    #[test]
+    fn flat_normals_follow_the_faces() {
+        let mut mesh = cube();
+        mesh.compute_flat_normals();
+        // Every corner is shared by three faces
+        assert_eq!(mesh.vertex_count(), 24);
+        for triangle in mesh.triangles() {
+            let face = mesh.face_normal(triangle).unwrap().normalize();
+            for &vertex in &triangle {
+                assert_close(&mesh.normal(vertex), &face);
+                assert_eq!(mesh.normal(vertex).dot(&mesh.position(vertex)), 1.0, "Not an outward axis");
+            }
         }
+    }
 
-        let body_model = models.iter().find(|m| m.name == "Body_body").expect("Incorrect model file!").to_owned();
-        let door_model = models.iter().find(|m| m.name == "Door_door").expect("Incorrect model file!").to_owned();
-        let main_rotor_model = models.iter().find(|m| m.name == "Main_Rotor_main_rotor").expect("Incorrect model file!").to_owned();
-        let tail_rotor_model = models.iter().find(|m| m.name == "Tail_Rotor_tail_rotor").expect("Incorrect model file!").to_owned();
+    #[test]
+    fn creases_split_edges_sharper_than_the_angle() {
+        // The faces of a cube meet at 90 degrees
+        let mut sharp = cube();
+        sharp.compute_normals(NormalWeighting::Angle, 80f32.to_radians());
+        assert_eq!(sharp.vertex_count(), 24);
+        for triangle in sharp.triangles() {
+            let face = sharp.face_normal(triangle).unwrap().normalize();
+            triangle.iter().for_each(|&vertex| assert_close(&sharp.normal(vertex), &face));
+        }
+
+        let mut smooth = cube();
+        smooth.compute_normals(NormalWeighting::Angle, 100f32.to_radians());
+        assert_eq!(smooth.vertex_count(), 8);
+        for vertex in 0..8 {
+            assert_close(&smooth.normal(vertex), &smooth.position(vertex).normalize());
+        }
+    }
 
-        Helicopter {
-            body:       Mesh::from(body_model.mesh,         [0.3, 0.3, 0.3, 1.0]),
-            door:       Mesh::from(door_model.mesh,         [0.1, 0.1, 0.3, 1.0]),
-            main_rotor: Mesh::from(main_rotor_model.mesh,   [0.3, 0.1, 0.1, 1.0]),
-            tail_rotor: Mesh::from(tail_rotor_model.mesh,   [0.1, 0.3, 0.1, 1.0]),
+    #[test]
+    fn tangents_follow_u() {
+        let mut mesh = quad(false);
+        mesh.compute_tangents();
+        assert!(mesh.has_tangents());
+        for tangent in mesh.tangents.chunks_exact(4) {
+            assert_close(&glm::make_vec3(&tangent[..3]), &glm::vec3(1.0, 0.0, 0.0));
+            assert_eq!(tangent[3], 1.0);
         }
+        // The bitangent follows v
+        for bitangent in mesh.bitangents().chunks_exact(3) {
+            assert_close(&glm::make_vec3(bitangent), &glm::vec3(0.0, 1.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn mirrored_texture_flips_the_handedness() {
+        let mut mesh = quad(true);
+        mesh.compute_tangents();
+        for tangent in mesh.tangents.chunks_exact(4) {
+            assert_close(&glm::make_vec3(&tangent[..3]), &glm::vec3(-1.0, 0.0, 0.0));
+            assert_eq!(tangent[3], -1.0);
+        }
+        // v still runs along +Y, which takes the flipped handedness to get back to
+        for bitangent in mesh.bitangents().chunks_exact(3) {
+            assert_close(&glm::make_vec3(bitangent), &glm::vec3(0.0, 1.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn no_tangents_without_texture_coordinates() {
+        let mut mesh = quad(false);
+        mesh.texcoords.clear();
+        mesh.compute_tangents();
+        assert!(!mesh.has_tangents());
+    }
+
+    // Two triangles sharing the edge from vertex 0 to 2, facing +Z
+    fn square() -> Mesh {
+        Mesh::from_positions(
+            vec![0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  1.0, 1.0, 0.0,  0.0, 1.0, 0.0],
+            vec![0, 1, 2, 0, 2, 3],
+        )
+    }
+
+    #[test]
+    fn indices_out_of_bounds_are_dropped() {
+        let mut mesh = square();
+        mesh.indices[4] = 7;
+        assert_eq!(mesh.validate(), [MeshIssue::IndexOutOfBounds { triangle: 1, index: 7 }]);
+        assert_eq!(mesh.drop_broken(), 1);
+        assert_eq!(mesh.indices, [0, 1, 2]);
+        assert_eq!(mesh.index_count, 3);
+        assert!(mesh.validate().is_empty());
+    }
+
+    #[test]
+    fn non_finite_positions_are_dropped() {
+        let mut mesh = square();
+        mesh.vertices[10] = f32::NAN;
+        assert_eq!(mesh.validate(), [MeshIssue::NonFinitePosition { vertex: 3 }]);
+        assert_eq!(mesh.drop_broken(), 1);
+        assert_eq!(mesh.indices, [0, 1, 2]);
+        // The vertex itself stays until it is welded away
+        assert_eq!(mesh.validate(), [MeshIssue::NonFinitePosition { vertex: 3 }]);
+        assert_eq!(mesh.weld(), 1);
+        assert!(mesh.validate().is_empty());
+    }
+
+    #[test]
+    fn degenerate_triangles_are_dropped() {
+        let mut mesh = square();
+        mesh.vertices.extend_from_slice(&[2.0, 2.0, 0.0]);
+        mesh.indices.extend_from_slice(&[0, 2, 4]);
+        mesh.set_color([1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(mesh.validate(), [MeshIssue::DegenerateTriangle { triangle: 2 }]);
+        assert_eq!(mesh.drop_broken(), 1);
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn partial_triangles_and_wrong_attributes_are_dropped() {
+        let mut mesh = square();
+        mesh.indices.push(0);
+        mesh.normals = vec![0.0, 0.0, 1.0];
+        let issues = mesh.validate();
+        assert!(issues.contains(&MeshIssue::PartialTriangle { index_count: 7 }));
+        assert!(issues.contains(&MeshIssue::AttributeCount { attribute: "normal", expected: 12, actual: 3 }));
+        assert_eq!(mesh.drop_broken(), 0);
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+        assert!(mesh.normals.is_empty());
+        assert!(mesh.validate().is_empty());
+    }
+
+    #[test]
+    fn non_manifold_edges_are_left_alone() {
+        // A third triangle on the diagonal, standing up from it
+        let mut mesh = square();
+        mesh.vertices.extend_from_slice(&[0.5, 0.5, 1.0]);
+        mesh.indices.extend_from_slice(&[0, 2, 4]);
+        mesh.set_color([1.0, 1.0, 1.0, 1.0]);
+        let issues = mesh.validate();
+        assert_eq!(issues.len(), 1);
+        match issues[0] {
+            MeshIssue::NonManifoldEdge { vertices, triangles } => {
+                assert_eq!(triangles, 3);
+                assert!(vertices == [0, 2] || vertices == [2, 0], "{:?}", vertices);
+            }
+            ref issue => panic!("{:?}", issue),
+        }
+        mesh.repair();
+        assert_eq!(mesh.indices.len(), 9);
+        assert_eq!(mesh.validate(), issues);
+    }
+
+    #[test]
+    fn flipped_triangles_are_only_turned_on_request() {
+        let mut mesh = cube();
+        mesh.indices.swap(7, 8);
+        let issues = mesh.validate();
+        assert!(!issues.is_empty());
+        assert!(issues.iter().all(|issue| matches!(issue, MeshIssue::InconsistentWinding { triangles: [_, _] })));
+
+        assert_eq!(mesh.drop_broken(), 0);
+        let after = mesh.validate();
+        assert!(after.len() == issues.len() && after.iter().all(|issue| issues.contains(issue)));
+        assert_eq!(mesh.unify_winding(), 1);
+        assert!(mesh.validate().is_empty());
+        assert_eq!(mesh.indices, cube().indices);
+    }
+
+    #[test]
+    fn loading_leaves_the_winding_alone() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3\nf 1 4 3\n";
+        let options = tobj::LoadOptions { single_index: true, triangulate: true, ..Default::default() };
+        let (models, _) = tobj::load_obj_buf(&mut obj.as_bytes(), &options, |_| Err(tobj::LoadError::OpenFileFailed)).unwrap();
+        let mesh = Mesh::from(models[0].mesh.clone(), Material::default());
+        assert_eq!(mesh.validate(), [MeshIssue::InconsistentWinding { triangles: [0, 1] }]);
+        let facing: Vec<f32> = mesh.triangles().map(|triangle| mesh.face_normal(triangle).unwrap().z).collect();
+        assert_eq!(facing, [1.0, -1.0]);
+    }
+
+    #[test]
+    fn welding_merges_identical_vertices() {
+        let mut mesh = square();
+        // Every triangle with its own copies of the corners
+        mesh.vertices = mesh.indices.iter().flat_map(|&v| mesh.position(v).as_slice().to_vec()).collect();
+        mesh.indices = (0..6).collect();
+        mesh.set_color([1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(mesh.weld(), 2);
+        assert_eq!(mesh.vertex_count(), 4);
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
     }
 }
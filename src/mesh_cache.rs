@@ -0,0 +1,464 @@
+extern crate nalgebra_glm as glm;
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use memmap2::Mmap;
+
+use crate::bounds::{Aabb, Bounds, Sphere};
+use crate::mesh::{Material, Mesh, Model, ModelObject};
+
+// A binary cache of the models loaded from OBJ files, so that each OBJ file only has to be parsed
+// once. The cache of `lunarsurface.obj` is `lunarsurface.meshcache` next to it. It holds the
+// meshes as they are after loading, i.e. with generated normals and tangents, along with their
+// materials.
+//
+// The cache is used as long as the files it was built from are unchanged. The OBJ file counts as
+// unchanged if it has the same size and modification time, or else the same hash, e.g. after a
+// fresh checkout, in which case its stamp in the cache is updated so that it isn't hashed again.
+// The MTL files are only compared by size and modification time.
+//
+// Everything is stored little endian, in this order:
+//     magic            b"GLOOMMSH"
+//     version          u32, see `VERSION`
+//     source hash      u64, FNV-1a of the OBJ file
+//     dependencies     u32 count, then for each: path, size u64 and modification time u64 (in
+//                      nanoseconds since the epoch), with u64::MAX for both if it doesn't exist
+//     name             of the model
+//     objects          u32 count, then for each:
+//         name
+//         material     name, ambient, diffuse and specular (3 f32 each), shininess, dissolve,
+//                      and the ambient, diffuse, specular and normal texture paths ("" for none)
+//         bounds       the minimum and maximum corner of the box around the positions, 3 f32 each,
+//                      then the center (3 f32) and radius of the sphere around them
+//         positions, normals, texcoords, tangents, colors: u32 count, then that many f32
+//         indices      u32 count, then that many u32
+// Strings are a u32 length followed by UTF-8.
+
+const MAGIC : &[u8; 8] = b"GLOOMMSH";
+
+// Bump this whenever the layout changes, or what `Model::load` does to the meshes, so that old
+// caches are rebuilt
+const VERSION : u32 = 4;
+
+pub fn cache_path(obj_path: &Path) -> PathBuf {
+    obj_path.with_extension("meshcache")
+}
+
+// The model in the cache of `obj_path`, or None if there is no cache or it is out of date. An
+// error means the cache is broken.
+pub fn load(obj_path: &Path) -> io::Result<Option<Model>> {
+    let path = cache_path(obj_path);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // Safety: the cache is only ever replaced as a whole by renaming a new file over it, never
+    // written to in place, so the mapping doesn't change under our feet
+    let map = unsafe { Mmap::map(&file)? };
+    let mut reader = Reader { bytes: &map, position: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid_data("not a mesh cache"));
+    }
+    if reader.u32()? != VERSION {
+        return Ok(None);
+    }
+    let source_hash = reader.u64()?;
+    let dependency_count = reader.u32()?;
+    let mut new_stamp = None;     // Where the stamp of the OBJ file is, and what it should be
+    for i in 0..dependency_count {
+        let dependency = PathBuf::from(reader.string()?);
+        let stamp_position = reader.position;
+        let stamp = (reader.u64()?, reader.u64()?);
+        let current_stamp = file_stamp(&dependency);
+        if stamp != current_stamp {
+            // The OBJ file is always first, and may just have been touched
+            let same_source = i == 0 && fs::read(&dependency).is_ok_and(|bytes| fnv1a(&bytes) == source_hash);
+            if !same_source {
+                return Ok(None);
+            }
+            new_stamp = Some((stamp_position, current_stamp));
+        }
+    }
+
+    let name = reader.string()?;
+    let object_count = reader.u32()?;
+    let mut objects = Vec::with_capacity(object_count as usize);
+    for _ in 0..object_count {
+        let name = reader.string()?;
+        let material = reader.material()?;
+        let bounds = reader.bounds()?;   // Can be read without going through the vertices
+        let vertices = reader.f32s()?;
+        let normals = reader.f32s()?;
+        let texcoords = reader.f32s()?;
+        let tangents = reader.f32s()?;
+        let colors = reader.f32s()?;
+        let indices = reader.u32s()?;
+        let vertex_count = (vertices.len() / 3) as u32;
+        if indices.iter().any(|&index| index >= vertex_count) {
+            return Err(invalid_data("index out of range"));
+        }
+        let mesh = Mesh {
+            index_count: indices.len() as i32,
+            vertices,
+            normals,
+            texcoords,
+            tangents,
+            colors,
+            indices,
+            material,
+            bounds,
+        };
+        objects.push(ModelObject {
+            name,
+            mesh           : Rc::new(mesh),
+            gpu_mesh       : None,
+            texture        : None,
+            normal_texture : None,
+        });
+    }
+
+    if let Some((position, stamp)) = new_stamp {
+        let bytes = with_stamp(&map, position, stamp);
+        drop(map);
+        drop(file);
+        if let Err(e) = replace(&path, &bytes) {
+            eprintln!("Failed to update the cache of {}: {}", obj_path.display(), e);
+        }
+    }
+    Ok(Some(Model { name, objects }))
+}
+
+// Writes the cache of `obj_path`, which `model` was loaded from. The cache depends on the OBJ
+// file, the MTL files it refers to, and the MTL file next to it, see `Model::load`.
+pub fn save(obj_path: &Path, model: &Model) -> io::Result<()> {
+    let source = fs::read(obj_path)?;
+    let directory = obj_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut dependencies = vec![obj_path.to_path_buf()];
+    dependencies.extend(
+        String::from_utf8_lossy(&source).lines()
+            .filter_map(|line| line.trim().strip_prefix("mtllib "))
+            .map(|name| directory.join(name.trim()))
+    );
+    let sibling = obj_path.with_extension("mtl");
+    if !dependencies.contains(&sibling) {
+        dependencies.push(sibling);
+    }
+
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u32(VERSION);
+    writer.u64(fnv1a(&source));
+    writer.u32(dependencies.len() as u32);
+    for path in &dependencies {
+        let (size, modified) = file_stamp(path);
+        writer.string(&path.to_string_lossy());
+        writer.u64(size);
+        writer.u64(modified);
+    }
+
+    writer.string(&model.name);
+    writer.u32(model.objects.len() as u32);
+    for object in &model.objects {
+        let mesh = &object.mesh;
+        writer.string(&object.name);
+        writer.material(&mesh.material);
+        writer.bounds(&mesh.bounds);
+        writer.f32s(&mesh.vertices);
+        writer.f32s(&mesh.normals);
+        writer.f32s(&mesh.texcoords);
+        writer.f32s(&mesh.tangents);
+        writer.f32s(&mesh.colors);
+        writer.u32s(&mesh.indices);
+    }
+
+    replace(&cache_path(obj_path), &writer.bytes)
+}
+
+// Writes `bytes` next to `path` first and then renames it over `path`, so that nobody ever sees
+// half a cache, and a cache which is mapped is never changed
+fn replace(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("meshcache.tmp");
+    fs::write(&temporary, bytes)?;
+    fs::rename(&temporary, path)
+}
+
+// A copy of the cache in `bytes` with the stamp at `position` set to `(size, modified)`
+fn with_stamp(bytes: &[u8], position: usize, (size, modified): (u64, u64)) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+    bytes[position..position + 8].copy_from_slice(&size.to_le_bytes());
+    bytes[position + 8..position + 16].copy_from_slice(&modified.to_le_bytes());
+    bytes
+}
+
+// The size and modification time of a file, or u64::MAX for both if it can't be read
+fn file_stamp(path: &Path) -> (u64, u64) {
+    match fs::metadata(path) {
+        Ok(metadata) => {
+            let modified = metadata.modified().ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(u64::MAX, |duration| duration.as_nanos() as u64);
+            (metadata.len(), modified)
+        }
+        Err(_) => (u64::MAX, u64::MAX),
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+struct Writer {
+    bytes : Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    // Without the count, for arrays whose length is known
+    fn f32s_exact(&mut self, values: &[f32]) {
+        values.iter().for_each(|&value| self.f32(value));
+    }
+
+    fn f32s(&mut self, values: &[f32]) {
+        self.u32(values.len() as u32);
+        self.f32s_exact(values);
+    }
+
+    fn u32s(&mut self, values: &[u32]) {
+        self.u32(values.len() as u32);
+        values.iter().for_each(|&value| self.u32(value));
+    }
+
+    fn material(&mut self, material: &Material) {
+        let path = |path: &Option<PathBuf>| path.as_ref().map_or(String::new(), |p| p.to_string_lossy().into_owned());
+        self.string(&material.name);
+        self.f32s_exact(&material.ambient);
+        self.f32s_exact(&material.diffuse);
+        self.f32s_exact(&material.specular);
+        self.f32(material.shininess);
+        self.f32(material.dissolve);
+        for texture in [&material.ambient_texture, &material.diffuse_texture, &material.specular_texture, &material.normal_texture] {
+            self.string(&path(texture));
+        }
+    }
+
+    fn bounds(&mut self, bounds: &Bounds) {
+        self.f32s_exact(bounds.aabb.min.as_slice());
+        self.f32s_exact(bounds.aabb.max.as_slice());
+        self.f32s_exact(bounds.sphere.center.as_slice());
+        self.f32(bounds.sphere.radius);
+    }
+}
+
+
+// Reads straight out of the mapped file
+struct Reader<'a> {
+    bytes    : &'a [u8],
+    position : usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        let end = self.position.checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid_data("truncated"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| invalid_data("invalid UTF-8"))
+    }
+
+    fn f32s_exact(&mut self, count: usize) -> io::Result<Vec<f32>> {
+        let bytes = self.take(count.checked_mul(4).ok_or_else(|| invalid_data("truncated"))?)?;
+        Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect())
+    }
+
+    fn f32s(&mut self) -> io::Result<Vec<f32>> {
+        let count = self.u32()? as usize;
+        self.f32s_exact(count)
+    }
+
+    fn u32s(&mut self) -> io::Result<Vec<u32>> {
+        let count = self.u32()? as usize;
+        let bytes = self.take(count.checked_mul(4).ok_or_else(|| invalid_data("truncated"))?)?;
+        Ok(bytes.chunks_exact(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect())
+    }
+
+    fn vec3(&mut self) -> io::Result<[f32; 3]> {
+        Ok([self.f32()?, self.f32()?, self.f32()?])
+    }
+
+    fn material(&mut self) -> io::Result<Material> {
+        let path = |path: String| if path.is_empty() { None } else { Some(PathBuf::from(path)) };
+        Ok(Material {
+            name      : self.string()?,
+            ambient   : self.vec3()?,
+            diffuse   : self.vec3()?,
+            specular  : self.vec3()?,
+            shininess : self.f32()?,
+            dissolve  : self.f32()?,
+            ambient_texture  : path(self.string()?),
+            diffuse_texture  : path(self.string()?),
+            specular_texture : path(self.string()?),
+            normal_texture   : path(self.string()?),
+        })
+    }
+
+    fn bounds(&mut self) -> io::Result<Bounds> {
+        Ok(Bounds {
+            aabb   : Aabb { min: glm::make_vec3(&self.vec3()?), max: glm::make_vec3(&self.vec3()?) },
+            sphere : Sphere { center: glm::make_vec3(&self.vec3()?), radius: self.f32()? },
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::time::{Duration, SystemTime};
+
+    const OBJ : &str = "mtllib quad.mtl\no quad\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nusemtl red\nf 1/1 2/2 3/3\nf 1/1 3/3 4/4\n";
+    const MTL : &str = "newmtl red\nKd 1 0 0\nd 0.5\n";
+
+    // A directory of its own with a quad in `quad.obj` and its material in `quad.mtl`
+    fn scene(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("gloom-mesh-cache-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("quad.obj"), OBJ).unwrap();
+        fs::write(directory.join("quad.mtl"), MTL).unwrap();
+        directory.join("quad.obj")
+    }
+
+    fn cached(obj_path: &Path) -> Model {
+        let model = Model::load_obj(obj_path.to_str().unwrap()).unwrap();
+        save(obj_path, &model).unwrap();
+        model
+    }
+
+    // The stamp of the OBJ file as written in its cache
+    fn stamp_in_cache(obj_path: &Path) -> (u64, u64) {
+        let bytes = fs::read(cache_path(obj_path)).unwrap();
+        let mut reader = Reader { bytes: &bytes, position: MAGIC.len() + 4 + 8 + 4 };
+        reader.string().unwrap();
+        (reader.u64().unwrap(), reader.u64().unwrap())
+    }
+
+    #[test]
+    fn round_trip() {
+        let obj_path = scene("round-trip");
+        let model = cached(&obj_path);
+        let loaded = load(&obj_path).unwrap().expect("the cache is up to date");
+
+        assert_eq!(loaded.name, model.name);
+        assert_eq!(loaded.objects.len(), model.objects.len());
+        for (loaded, object) in loaded.objects.iter().zip(&model.objects) {
+            let (a, b) = (&loaded.mesh, &object.mesh);
+            assert_eq!(loaded.name, object.name);
+            assert_eq!(a.material, b.material);
+            assert_eq!(a.material.dissolve, 0.5);
+            assert_eq!(a.bounds, b.bounds);
+            assert_eq!(a.vertices, b.vertices);
+            assert_eq!(a.normals, b.normals);
+            assert_eq!(a.texcoords, b.texcoords);
+            assert_eq!(a.tangents, b.tangents);
+            assert_eq!(a.colors, b.colors);
+            assert_eq!(a.indices, b.indices);
+            assert_eq!(a.index_count, b.index_count);
+        }
+    }
+
+    #[test]
+    fn other_versions_are_rebuilt() {
+        let obj_path = scene("version");
+        cached(&obj_path);
+        let mut bytes = fs::read(cache_path(&obj_path)).unwrap();
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(cache_path(&obj_path), bytes).unwrap();
+        assert!(load(&obj_path).unwrap().is_none());
+    }
+
+    #[test]
+    fn changed_dependencies_invalidate_the_cache() {
+        let obj_path = scene("dependencies");
+        cached(&obj_path);
+        fs::write(obj_path.with_extension("mtl"), "newmtl red\nKd 0 0.5 0\nd 0.5\n").unwrap();
+        assert!(load(&obj_path).unwrap().is_none());
+
+        cached(&obj_path);
+        fs::write(&obj_path, OBJ.replace("v 1 1 0", "v 1.5 1.5 0")).unwrap();
+        assert!(load(&obj_path).unwrap().is_none());
+
+        cached(&obj_path);
+        fs::remove_file(obj_path.with_extension("mtl")).unwrap();
+        assert!(load(&obj_path).unwrap().is_none());
+    }
+
+    #[test]
+    fn touched_sources_refresh_their_stamp() {
+        let obj_path = scene("touched");
+        cached(&obj_path);
+        let file = OpenOptions::new().write(true).open(&obj_path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        drop(file);
+        assert_ne!(stamp_in_cache(&obj_path), file_stamp(&obj_path));
+
+        assert!(load(&obj_path).unwrap().is_some());
+        assert_eq!(stamp_in_cache(&obj_path), file_stamp(&obj_path));
+        assert!(load(&obj_path).unwrap().is_some());
+    }
+
+    #[test]
+    fn other_files_are_errors() {
+        let obj_path = scene("not-a-cache");
+        fs::write(cache_path(&obj_path), b"not a mesh cache").unwrap();
+        assert!(load(&obj_path).is_err());
+        fs::remove_file(cache_path(&obj_path)).unwrap();
+        assert!(load(&obj_path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,571 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use crate::mesh::{Material, Mesh, Model, ModelObject};
+use crate::scene_graph::{NodeId, SceneGraph};
+
+// Writers for meshes in formats other tools can read:
+//     OBJ  with an MTL file next to it for the materials, e.g. `out.obj` and `out.mtl`. Vertex
+//          colours are written after the position (`v x y z r g b`) unless they are all white.
+//     PLY  ASCII or binary, with positions, normals, texture coordinates (s, t) and vertex
+//          colours (as bytes), whichever the mesh has, and a list of indices per triangle.
+//     STL  ASCII or binary, only the triangles and their face normals.
+// Texture coordinates are written as they are stored, with v pointing up as in OBJ files.
+//
+// `export_scene` bakes the world transforms of a part of a scene graph into its meshes, and
+// writes them all to a single file.
+
+// The writers refuse meshes with indices past the last vertex, or a number of indices which isn't
+// a multiple of 3, with an `InvalidInput` error.
+
+// How PLY and STL files are stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Ascii,
+    Binary,     // Little endian
+}
+
+// Writes `mesh` as a single object named after the file, see `write_model_obj`
+pub fn write_obj(path: &Path, mesh: &Mesh) -> io::Result<()> {
+    let name = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    write_objects_obj(path, &[(&name, mesh)])
+}
+
+// Writes every object of `model` to an OBJ file, and their materials to an MTL file with the
+// same name. Materials without a name are named after the first object using them.
+pub fn write_model_obj(path: &Path, model: &Model) -> io::Result<()> {
+    let objects: Vec<(&str, &Mesh)> = model.objects.iter()
+        .map(|object| (object.name.as_str(), object.mesh.as_ref()))
+        .collect();
+    write_objects_obj(path, &objects)
+}
+
+pub fn write_ply(path: &Path, mesh: &Mesh, encoding: Encoding) -> io::Result<()> {
+    check_indices(mesh)?;
+    let vertex_count = mesh.vertex_count();
+    let has_normals = mesh.has_normals();
+    let has_texcoords = mesh.texcoords.len() == vertex_count * 2;
+    let has_colors = mesh.colors.len() == vertex_count * 4;
+    let color_byte = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "ply")?;
+    writeln!(out, "format {} 1.0", match encoding {
+        Encoding::Ascii  => "ascii",
+        Encoding::Binary => "binary_little_endian",
+    })?;
+    writeln!(out, "comment Exported by gloom-rs")?;
+    writeln!(out, "element vertex {}", vertex_count)?;
+    let mut properties = vec!["float x", "float y", "float z"];
+    if has_normals {
+        properties.extend_from_slice(&["float nx", "float ny", "float nz"]);
+    }
+    if has_texcoords {
+        properties.extend_from_slice(&["float s", "float t"]);
+    }
+    if has_colors {
+        properties.extend_from_slice(&["uchar red", "uchar green", "uchar blue", "uchar alpha"]);
+    }
+    for property in properties {
+        writeln!(out, "property {}", property)?;
+    }
+    writeln!(out, "element face {}", mesh.indices.len() / 3)?;
+    writeln!(out, "property list uchar uint vertex_indices")?;
+    writeln!(out, "end_header")?;
+
+    for vertex in 0..vertex_count {
+        let mut floats = mesh.vertices[vertex * 3..vertex * 3 + 3].to_vec();
+        if has_normals {
+            floats.extend_from_slice(&mesh.normals[vertex * 3..vertex * 3 + 3]);
+        }
+        if has_texcoords {
+            floats.extend_from_slice(&mesh.texcoords[vertex * 2..vertex * 2 + 2]);
+        }
+        let bytes: Vec<u8> = if has_colors {
+            mesh.colors[vertex * 4..vertex * 4 + 4].iter().map(|&c| color_byte(c)).collect()
+        } else {
+            vec![]
+        };
+        match encoding {
+            Encoding::Ascii => {
+                let values: Vec<String> = floats.iter().map(f32::to_string)
+                    .chain(bytes.iter().map(u8::to_string))
+                    .collect();
+                writeln!(out, "{}", values.join(" "))?;
+            }
+            Encoding::Binary => {
+                for value in floats {
+                    out.write_all(&value.to_le_bytes())?;
+                }
+                out.write_all(&bytes)?;
+            }
+        }
+    }
+
+    for triangle in mesh.indices.chunks_exact(3) {
+        match encoding {
+            Encoding::Ascii => writeln!(out, "3 {} {} {}", triangle[0], triangle[1], triangle[2])?,
+            Encoding::Binary => {
+                out.write_all(&[3])?;
+                for index in triangle {
+                    out.write_all(&index.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    out.flush()
+}
+
+pub fn write_stl(path: &Path, mesh: &Mesh, encoding: Encoding) -> io::Result<()> {
+    check_indices(mesh)?;
+    let name = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    let position = |index: u32| {
+        let i = index as usize * 3;
+        glm::vec3(mesh.vertices[i], mesh.vertices[i + 1], mesh.vertices[i + 2])
+    };
+    let triangles = mesh.indices.chunks_exact(3).map(|triangle| {
+        let corners = [position(triangle[0]), position(triangle[1]), position(triangle[2])];
+        let normal = (corners[1] - corners[0]).cross(&(corners[2] - corners[0]));
+        // Triangles without an area get no normal, which readers are expected to handle
+        let normal = if normal.norm() > 0.0 { normal.normalize() } else { normal };
+        (normal, corners)
+    });
+
+    let mut out = BufWriter::new(File::create(path)?);
+    match encoding {
+        Encoding::Ascii => {
+            writeln!(out, "solid {}", name)?;
+            for (normal, corners) in triangles {
+                writeln!(out, "  facet normal {} {} {}", normal.x, normal.y, normal.z)?;
+                writeln!(out, "    outer loop")?;
+                for corner in &corners {
+                    writeln!(out, "      vertex {} {} {}", corner.x, corner.y, corner.z)?;
+                }
+                writeln!(out, "    endloop")?;
+                writeln!(out, "  endfacet")?;
+            }
+            writeln!(out, "endsolid {}", name)?;
+        }
+        Encoding::Binary => {
+            // The header must not start with "solid", or readers take it for an ASCII file
+            let mut header = [b' '; 80];
+            let text = format!("Exported by gloom-rs: {}", name);
+            let length = text.len().min(header.len());
+            header[..length].copy_from_slice(&text.as_bytes()[..length]);
+            out.write_all(&header)?;
+            out.write_all(&((mesh.indices.len() / 3) as u32).to_le_bytes())?;
+            for (normal, corners) in triangles {
+                for vector in std::iter::once(&normal).chain(&corners) {
+                    for &value in vector.iter() {
+                        out.write_all(&value.to_le_bytes())?;
+                    }
+                }
+                out.write_all(&0u16.to_le_bytes())?;   // Attribute byte count, unused
+            }
+        }
+    }
+    out.flush()
+}
+
+// A model with an object for every node with a mesh below (and including) `id`, in the order
+// of `SceneGraph::descendants`. The meshes are moved to where the nodes are in the world, so the
+// model looks like that part of the scene does now. Nodes without a name are called "node<i>".
+pub fn bake_scene(scene: &mut SceneGraph, id: NodeId) -> Model {
+    let mut objects = vec![];
+    for (i, node_id) in scene.descendants(id).into_iter().enumerate() {
+        let mesh = match &scene[node_id].mesh {
+            Some(mesh) => Rc::clone(mesh),
+            None => continue,
+        };
+        let name = match scene[node_id].name.as_str() {
+            "" => format!("node{}", i),
+            name => name.to_string(),
+        };
+        let world = scene.world_matrix(node_id);
+        objects.push(ModelObject {
+            name,
+            mesh           : Rc::new(transformed(&mesh, &world)),
+            gpu_mesh       : None,
+            texture        : None,
+            normal_texture : None,
+        });
+    }
+    Model { name: scene[id].name.clone(), objects }
+}
+
+// Bakes the part of the scene below (and including) `id` as `bake_scene` does, and writes it to
+// `path` in the format given by its extension: .obj, .ply or .stl. OBJ files keep each node as a
+// separate object, while the other formats get all nodes merged into one mesh, and are binary.
+pub fn export_scene(scene: &mut SceneGraph, id: NodeId, path: &Path) -> io::Result<()> {
+    let model = bake_scene(scene, id);
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("obj") => write_model_obj(path, &model),
+        Some("ply") => write_ply(path, &model.merged_mesh(), Encoding::Binary),
+        Some("stl") => write_stl(path, &model.merged_mesh(), Encoding::Binary),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't export to {}, expected a .obj, .ply or .stl file", path.display()),
+        )),
+    }
+}
+
+// A copy of `mesh` transformed by `matrix`. Normals and tangents are transformed so that they
+// stay perpendicular to and along the surface. If the matrix mirrors the mesh, the triangles are
+// turned around so that they still face outwards.
+fn transformed(mesh: &Mesh, matrix: &glm::Mat4) -> Mesh {
+    let linear = glm::mat4_to_mat3(matrix);
+    let normal_matrix = glm::inverse_transpose(linear);
+    let mirrored = linear.determinant() < 0.0;
+    let direction = |m: &glm::Mat3, v: &[f32]| {
+        let v = m * glm::vec3(v[0], v[1], v[2]);
+        if v.norm() > 0.0 { v.normalize() } else { v }
+    };
+
+    let mut copy = mesh.clone();
+    copy.vertices = mesh.vertices.chunks_exact(3)
+        .flat_map(|p| {
+            let p = matrix * glm::vec4(p[0], p[1], p[2], 1.0);
+            [p.x, p.y, p.z]
+        })
+        .collect();
+    copy.normals = mesh.normals.chunks_exact(3)
+        .flat_map(|n| {
+            let n = direction(&normal_matrix, n);
+            [n.x, n.y, n.z]
+        })
+        .collect();
+    copy.tangents = mesh.tangents.chunks_exact(4)
+        .flat_map(|t| {
+            let direction = direction(&linear, t);
+            let handedness = if mirrored { -t[3] } else { t[3] };
+            [direction.x, direction.y, direction.z, handedness]
+        })
+        .collect();
+    if mirrored {
+        copy.indices.chunks_exact_mut(3).for_each(|triangle| triangle.swap(1, 2));
+    }
+    copy.update_bounds();
+    copy
+}
+
+fn write_objects_obj(path: &Path, objects: &[(&str, &Mesh)]) -> io::Result<()> {
+    for &(_, mesh) in objects {
+        check_indices(mesh)?;
+    }
+    let mtl_path = path.with_extension("mtl");
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    // The name of the material of every object, and every material once
+    let mut material_names = vec![];
+    let mut materials: Vec<(String, &Material)> = vec![];
+    let mut seen = HashSet::new();
+    for &(name, mesh) in objects {
+        let material_name = match mesh.material.name.as_str() {
+            "" => name.to_string(),
+            material_name => material_name.to_string(),
+        };
+        if seen.insert(material_name.clone()) {
+            materials.push((material_name.clone(), &mesh.material));
+        }
+        material_names.push(material_name);
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "# Exported by gloom-rs")?;
+    if let Some(mtl_name) = mtl_path.file_name() {
+        writeln!(out, "mtllib {}", mtl_name.to_string_lossy())?;
+    }
+
+    // OBJ indices are 1-based, and count across all objects in the file. Objects without texture
+    // coordinates or normals write none, so those are counted separately.
+    let (mut vertex_base, mut texcoord_base, mut normal_base) = (1, 1, 1);
+    for (&(name, mesh), material_name) in objects.iter().zip(&material_names) {
+        let vertex_count = mesh.vertex_count();
+        let has_normals = mesh.has_normals();
+        let has_texcoords = mesh.texcoords.len() == vertex_count * 2;
+        let has_colors = mesh.colors.len() == vertex_count * 4 && mesh.colors.iter().any(|&c| c != 1.0);
+
+        writeln!(out, "o {}", name)?;
+        for vertex in 0..vertex_count {
+            let p = &mesh.vertices[vertex * 3..vertex * 3 + 3];
+            if has_colors {
+                let c = &mesh.colors[vertex * 4..vertex * 4 + 3];
+                writeln!(out, "v {} {} {} {} {} {}", p[0], p[1], p[2], c[0], c[1], c[2])?;
+            } else {
+                writeln!(out, "v {} {} {}", p[0], p[1], p[2])?;
+            }
+        }
+        if has_texcoords {
+            for t in mesh.texcoords.chunks_exact(2) {
+                writeln!(out, "vt {} {}", t[0], t[1])?;
+            }
+        }
+        if has_normals {
+            for n in mesh.normals.chunks_exact(3) {
+                writeln!(out, "vn {} {} {}", n[0], n[1], n[2])?;
+            }
+        }
+        writeln!(out, "usemtl {}", material_name)?;
+        let corner = |index: u32| {
+            let v = vertex_base + index as usize;
+            let t = texcoord_base + index as usize;
+            let n = normal_base + index as usize;
+            match (has_texcoords, has_normals) {
+                (true, true)   => format!("{}/{}/{}", v, t, n),
+                (true, false)  => format!("{}/{}", v, t),
+                (false, true)  => format!("{}//{}", v, n),
+                (false, false) => format!("{}", v),
+            }
+        };
+        for triangle in mesh.indices.chunks_exact(3) {
+            writeln!(out, "f {} {} {}", corner(triangle[0]), corner(triangle[1]), corner(triangle[2]))?;
+        }
+
+        vertex_base += vertex_count;
+        if has_texcoords {
+            texcoord_base += vertex_count;
+        }
+        if has_normals {
+            normal_base += vertex_count;
+        }
+    }
+    out.flush()?;
+
+    let mut out = BufWriter::new(File::create(&mtl_path)?);
+    writeln!(out, "# Exported by gloom-rs")?;
+    for (name, material) in materials {
+        let texture = |path: &Option<PathBuf>| path.as_ref().map(|path| relative_path(path, directory));
+        writeln!(out)?;
+        writeln!(out, "newmtl {}", name)?;
+        writeln!(out, "Ka {} {} {}", material.ambient[0], material.ambient[1], material.ambient[2])?;
+        writeln!(out, "Kd {} {} {}", material.diffuse[0], material.diffuse[1], material.diffuse[2])?;
+        writeln!(out, "Ks {} {} {}", material.specular[0], material.specular[1], material.specular[2])?;
+        writeln!(out, "Ns {}", material.shininess)?;
+        writeln!(out, "d {}", material.dissolve)?;
+        for (keyword, path) in [
+            ("map_Ka", &material.ambient_texture),
+            ("map_Kd", &material.diffuse_texture),
+            ("map_Ks", &material.specular_texture),
+            ("norm",   &material.normal_texture),
+        ] {
+            if let Some(path) = texture(path) {
+                writeln!(out, "{} {}", keyword, path.display())?;
+            }
+        }
+    }
+    out.flush()
+}
+
+fn check_indices(mesh: &Mesh) -> io::Result<()> {
+    let vertex_count = mesh.vertex_count();
+    if !mesh.indices.chunks_exact(3).remainder().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} indices don't make whole triangles", mesh.indices.len()),
+        ));
+    }
+    match mesh.indices.iter().find(|&&index| index as usize >= vertex_count) {
+        Some(index) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Index {} is out of range for {} vertices", index, vertex_count),
+        )),
+        None => Ok(()),
+    }
+}
+
+// `path` relative to `directory`, as texture paths in MTL files are. Both are relative to the
+// working directory, or absolute. Falls back on an absolute path if they have no common root.
+fn relative_path(path: &Path, directory: &Path) -> PathBuf {
+    let absolute = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| {
+        std::env::current_dir().map_or_else(|_| path.to_path_buf(), |current| current.join(path))
+    });
+    let path = absolute(path);
+    let directory = absolute(if directory.as_os_str().is_empty() { Path::new(".") } else { directory });
+
+    let path_components: Vec<Component> = path.components().collect();
+    let directory_components: Vec<Component> = directory.components().collect();
+    let common = path_components.iter().zip(&directory_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path;
+    }
+    let mut relative = PathBuf::new();
+    for _ in common..directory_components.len() {
+        relative.push("..");
+    }
+    relative.extend(&path_components[common..]);
+    relative
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    // An empty directory of its own
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("gloom-mesh-export-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    // A unit square in the xy plane facing +z, two triangles over four vertices, shifted along x
+    fn square(x: f32) -> Mesh {
+        let vertices = vec![x, 0.0, 0.0,  x + 1.0, 0.0, 0.0,  x + 1.0, 1.0, 0.0,  x, 1.0, 0.0];
+        Mesh::from_positions(vertices, vec![0, 1, 2, 0, 2, 3])
+    }
+
+    fn with_normals(mut mesh: Mesh) -> Mesh {
+        mesh.normals = [0.0, 0.0, 1.0].repeat(mesh.vertex_count());
+        mesh
+    }
+
+    fn with_texcoords(mut mesh: Mesh) -> Mesh {
+        mesh.texcoords = vec![0.0, 0.0,  1.0, 0.0,  1.0, 1.0,  0.0, 1.0];
+        mesh
+    }
+
+    #[test]
+    fn obj_indices_count_across_objects() {
+        let path = directory("obj").join("squares.obj");
+        let meshes = [
+            with_normals(with_texcoords(square(0.0))),
+            square(2.0),
+            with_normals(square(4.0)),
+            with_texcoords(square(6.0)),
+            with_normals(with_texcoords(square(8.0))),
+        ];
+        let names = ["both", "neither", "normals", "texcoords", "both again"];
+        let objects: Vec<(&str, &Mesh)> = names.iter().copied().zip(&meshes).collect();
+        write_objects_obj(&path, &objects).unwrap();
+
+        let options = tobj::LoadOptions { single_index: true, triangulate: true, ..Default::default() };
+        let (models, materials) = tobj::load_obj(&path, &options).unwrap();
+        materials.unwrap();
+        assert_eq!(models.len(), meshes.len());
+        for ((model, mesh), name) in models.iter().zip(&meshes).zip(&names) {
+            assert_eq!(&model.name, name);
+            let loaded = &model.mesh;
+            assert_eq!(loaded.indices.len(), mesh.indices.len());
+            for (&a, &b) in loaded.indices.iter().zip(&mesh.indices) {
+                let (a, b) = (a as usize, b as usize);
+                assert_eq!(loaded.positions[a * 3..a * 3 + 3], mesh.vertices[b * 3..b * 3 + 3], "{}", name);
+                if mesh.has_normals() {
+                    assert_eq!(loaded.normals[a * 3..a * 3 + 3], mesh.normals[b * 3..b * 3 + 3], "{}", name);
+                } else {
+                    assert!(loaded.normals.is_empty(), "{}", name);
+                }
+                if !mesh.texcoords.is_empty() {
+                    assert_eq!(loaded.texcoords[a * 2..a * 2 + 2], mesh.texcoords[b * 2..b * 2 + 2], "{}", name);
+                } else {
+                    assert!(loaded.texcoords.is_empty(), "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn binary_stl_has_a_header_and_count() {
+        let path = directory("stl").join("square.stl");
+        write_stl(&path, &square(0.0), Encoding::Binary).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 80 + 4 + 2 * 50);
+        assert!(!bytes.starts_with(b"solid"));
+        assert!(bytes[..80].starts_with(b"Exported by gloom-rs: square"));
+        assert_eq!(u32::from_le_bytes(bytes[80..84].try_into().unwrap()), 2);
+        let normal: Vec<f32> = bytes[84..96].chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(normal, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn ply_header_follows_the_vertex_layout() {
+        let directory = directory("ply");
+        let header = |path: &Path| -> Vec<String> {
+            let bytes = fs::read(path).unwrap();
+            let text = String::from_utf8_lossy(&bytes);
+            text.lines().take_while(|&line| line != "end_header").map(String::from).collect()
+        };
+        let properties = |header: &[String]| -> Vec<String> {
+            header.iter().filter_map(|line| line.strip_prefix("property ")).map(String::from).collect()
+        };
+
+        let path = directory.join("plain.ply");
+        write_ply(&path, &square(0.0), Encoding::Ascii).unwrap();
+        let plain = header(&path);
+        assert!(plain.contains(&"format ascii 1.0".to_string()));
+        assert!(plain.contains(&"element vertex 4".to_string()));
+        assert!(plain.contains(&"element face 2".to_string()));
+        assert_eq!(properties(&plain), [
+            "float x", "float y", "float z",
+            "uchar red", "uchar green", "uchar blue", "uchar alpha",
+            "list uchar uint vertex_indices",
+        ]);
+
+        let path = directory.join("full.ply");
+        write_ply(&path, &with_normals(with_texcoords(square(0.0))), Encoding::Binary).unwrap();
+        let full = header(&path);
+        assert!(full.contains(&"format binary_little_endian 1.0".to_string()));
+        assert_eq!(properties(&full), [
+            "float x", "float y", "float z",
+            "float nx", "float ny", "float nz",
+            "float s", "float t",
+            "uchar red", "uchar green", "uchar blue", "uchar alpha",
+            "list uchar uint vertex_indices",
+        ]);
+        let header_length: usize = full.iter().map(|line| line.len() + 1).sum::<usize>() + "end_header\n".len();
+        let vertex_size = 8 * 4 + 4;
+        let face_size = 1 + 3 * 4;
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, header_length + 4 * vertex_size + 2 * face_size);
+    }
+
+    #[test]
+    fn mirroring_turns_triangles_and_tangents_around() {
+        let mut mesh = with_normals(with_texcoords(square(0.0)));
+        mesh.tangents = [1.0, 0.0, 0.0, 1.0].repeat(mesh.vertex_count());
+
+        let mirrored = transformed(&mesh, &glm::scaling(&glm::vec3(-1.0, 1.0, 1.0)));
+        assert_eq!(mirrored.indices, [0, 2, 1, 0, 3, 2]);
+        for triangle in mirrored.indices.chunks_exact(3) {
+            let corner = |i: usize| glm::make_vec3(&mirrored.vertices[triangle[i] as usize * 3..][..3]);
+            let face_normal = (corner(1) - corner(0)).cross(&(corner(2) - corner(0)));
+            assert!(face_normal.z > 0.0, "the triangles still face +z");
+        }
+        assert_eq!(mirrored.normals, mesh.normals);
+        assert_eq!(mirrored.tangents, [-1.0, 0.0, 0.0, -1.0].repeat(4));
+
+        let turned = transformed(&mesh, &glm::rotation(std::f32::consts::PI, &glm::vec3(0.0, 1.0, 0.0)));
+        assert_eq!(turned.indices, mesh.indices);
+        assert!(turned.tangents.chunks_exact(4).all(|t| t[3] == 1.0));
+    }
+
+    #[test]
+    fn broken_indices_are_invalid_input() {
+        let directory = directory("broken");
+        let mut mesh = square(0.0);
+        mesh.indices[4] = 4;
+        for result in [
+            write_stl(&directory.join("broken.stl"), &mesh, Encoding::Binary),
+            write_ply(&directory.join("broken.ply"), &mesh, Encoding::Ascii),
+            write_obj(&directory.join("broken.obj"), &mesh),
+        ] {
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let mut mesh = square(0.0);
+        mesh.indices.pop();
+        let result = write_stl(&directory.join("broken.stl"), &mesh, Encoding::Ascii);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,70 +1,219 @@
 extern crate nalgebra_glm as glm;
 
-use std::mem::ManuallyDrop;
-use std::pin::Pin;
-
-// Used to create an unholy abomination upon which you should not cast your gaze. This ended up
-// being a necessity due to wanting to keep the code written by students as "straight forward" as
-// possible. It is very very double plus ungood Rust, and intentionally leaks memory like a sieve.
-// But it works, and you're more than welcome to pretend it doesn't exist! In case you're curious
-// about how it works: It allocates memory on the heap (Box), promises to prevent it from being
-// moved or deallocated until dropped (Pin) and finally prevents the compiler from dropping it
-// automatically at all (ManuallyDrop).
-// ...
-// If that sounds like a janky solution, it's because it is!
-// Prettier, Rustier and better solutions were tried numerous times, but were all found wanting of
-// having what I arbitrarily decided to be the required level of "simplicity of use".
-pub type Node = ManuallyDrop<Pin<Box<SceneNode>>>;
+use std::fmt;
+use std::ops::{Index, IndexMut};
+use std::rc::Rc;
+
+use crate::bounds::{Aabb, Bounds};
+use crate::gpu_mesh::GpuMesh;
+use crate::lod::LodChain;
+use crate::mesh::{Mesh, Model};
+use crate::texture::Texture;
+
+// The scene graph owns every node in a single arena (a Vec of slots). Nodes refer to each other
+// through `NodeId` handles instead of pointers, so there is nothing to leak: removing a node frees
+// its slot (and those of its descendants), and dropping the `SceneGraph` drops everything.
+//
+// A slot that has been freed may later be reused by a new node. Every slot therefore carries a
+// generation counter, and a `NodeId` only matches the slot while the generation is unchanged.
+// This way a stale handle to a removed node can never silently point at an unrelated new node.
+//
+// Every node caches its local transform (relative to its parent) and its world transform. Any
+// mutable access to a node through the graph marks it dirty, and the cached matrices of a dirty
+// node and of everything below it are recomputed on the next call to `update_transforms`, or on
+// demand when asking for the world matrix of a single node.
+//
+// Nodes with a mesh also cache the bounding volumes around it in the world, and every node those
+// around itself and everything below it. These are recomputed by `update_transforms` too, and let
+// whole subtrees be skipped when looking for what is on screen or under the mouse.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index      : usize,
+    generation : u32,
+}
+
+// The order in which the Euler angles of `SceneNode::rotation` are applied. The letters list the
+// axes in the order the rotations happen, so `ZYX` first rotates about Z, then Y and finally X.
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EulerOrder {
+    XYZ,
+    XZY,
+    YXZ,
+    YZX,
+    ZXY,
+    #[default]
+    ZYX,
+}
+
+impl EulerOrder {
+    // Indices of the axes (0 = X, 1 = Y, 2 = Z), in the order they are applied
+    fn axes(self) -> [usize; 3] {
+        match self {
+            EulerOrder::XYZ => [0, 1, 2],
+            EulerOrder::XZY => [0, 2, 1],
+            EulerOrder::YXZ => [1, 0, 2],
+            EulerOrder::YZX => [1, 2, 0],
+            EulerOrder::ZXY => [2, 0, 1],
+            EulerOrder::ZYX => [2, 1, 0],
+        }
+    }
+
+    // Combines the three angles of `rotation` into a single quaternion
+    pub fn to_quat(self, rotation: &glm::Vec3) -> glm::Quat {
+        let mut quat = glm::quat_identity();
+        for &axis in self.axes().iter() {
+            let mut axis_vec: glm::Vec3 = glm::zero();
+            axis_vec[axis] = 1.0;
+            quat = glm::quat_angle_axis(rotation[axis], &axis_vec) * quat;
+        }
+        quat
+    }
+}
 
 pub struct SceneNode {
+    pub name            : String,      // What I'm called, e.g. after the object in a model file
     pub position        : glm::Vec3,   // Where I should be in relation to my parent
     pub rotation        : glm::Vec3,   // How I should be rotated, around the X, the Y and the Z axes
+    pub euler_order     : EulerOrder,  // In which order those rotations are applied
+    pub orientation     : Option<glm::Quat>, // If set, how I should be rotated instead of `rotation`
     pub scale           : glm::Vec3,   // How I should be scaled
     pub reference_point : glm::Vec3,   // The point I shall rotate and scale about
 
-    pub vao_id      : u32,             // What I should draw
-    pub index_count : i32,             // How much of it there is to draw
+    pub gpu_mesh       : Option<Rc<GpuMesh>>, // What I should draw
+    pub mesh           : Option<Rc<Mesh>>,    // The same, as it looks on the CPU side
+    pub texture        : Option<Rc<Texture>>, // What I should be painted with, instead of the texture of my material
+    pub normal_texture : Option<Rc<Texture>>, // How my surface is bumped, needs a mesh with tangents
+    pub lods           : Option<Rc<LodChain>>, // Coarser versions of my mesh, for when I'm small on screen
 
-    pub children: Vec<*mut SceneNode>, // Those I command
+    parent   : Option<NodeId>,         // The one I answer to
+    children : Vec<NodeId>,            // Those I command
+
+    local_matrix : glm::Mat4,          // Where I am in relation to my parent, as of the last update
+    world_matrix : glm::Mat4,          // Where I am in relation to the world, as of the last update
+    dirty        : bool,               // Whether my fields changed since the last update
+
+    bounds         : Bounds,           // Around my mesh in the world, as of the last update
+    subtree_bounds : Bounds,           // Around mine and those of everything below me, likewise
 }
 
 impl SceneNode {
 
-    pub fn new() -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
+    pub fn new() -> SceneNode {
+        SceneNode {
+            name            : String::new(),
             position        : glm::zero(),
             rotation        : glm::zero(),
+            euler_order     : EulerOrder::default(),
+            orientation     : None,
             scale           : glm::vec3(1.0, 1.0, 1.0),
             reference_point : glm::zero(),
-            vao_id          : 0,
-            index_count     : -1,
+            gpu_mesh        : None,
+            mesh            : None,
+            texture         : None,
+            normal_texture  : None,
+            lods            : None,
+            parent          : None,
             children        : vec![],
-        })))
+            local_matrix    : glm::identity(),
+            world_matrix    : glm::identity(),
+            dirty           : true,
+            bounds          : Bounds::empty(),
+            subtree_bounds  : Bounds::empty(),
+        }
     }
 
-    pub fn from_vao(vao_id: u32, index_count: i32) -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
-            position        : glm::zero(),
-            rotation        : glm::zero(),
-            scale           : glm::vec3(1.0, 1.0, 1.0),
-            reference_point : glm::zero(),
-            vao_id,
-            index_count,
-            children: vec![],
-        })))
+    pub fn from_gpu_mesh(gpu_mesh: Rc<GpuMesh>) -> SceneNode {
+        SceneNode {
+            gpu_mesh : Some(gpu_mesh),
+            ..SceneNode::new()
+        }
     }
 
-    pub fn add_child(&mut self, child: &SceneNode) {
-        self.children.push(child as *const SceneNode as *mut SceneNode)
+    // A node drawing `mesh`, along with its copy on the GPU if it has been uploaded
+    pub fn from_mesh(mesh: Rc<Mesh>, gpu_mesh: Option<Rc<GpuMesh>>) -> SceneNode {
+        SceneNode {
+            gpu_mesh,
+            mesh : Some(mesh),
+            ..SceneNode::new()
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn get_child(& mut self, index: usize) -> & mut SceneNode {
-        unsafe {
-            &mut (*self.children[index])
+    // What to draw me with when seen through `view_projection`: the level of `lods` meant for my
+    // size on screen, or `mesh` and `gpu_mesh` if I have no levels. Uses my world matrix as of
+    // the last update.
+    pub fn lod(&self, view_projection: &glm::Mat4) -> (Option<&Rc<Mesh>>, Option<&Rc<GpuMesh>>) {
+        match &self.lods {
+            Some(lods) => {
+                let level = lods.select(lods.screen_size(&self.world_matrix, view_projection));
+                (Some(lods.mesh(level)), lods.gpu_mesh(level))
+            }
+            None => (self.mesh.as_ref(), self.gpu_mesh.as_ref()),
         }
     }
 
+    // Whether I have a mesh, on either side
+    pub fn is_drawable(&self) -> bool {
+        self.mesh.is_some() || self.gpu_mesh.is_some()
+    }
+
+    // My rotation as a quaternion, taken from `orientation` if set and from `rotation` otherwise.
+    // Useful for slerping between two poses with `glm::quat_slerp`.
+    pub fn rotation_quat(&self) -> glm::Quat {
+        match self.orientation {
+            Some(orientation) => orientation,
+            None              => self.euler_order.to_quat(&self.rotation),
+        }
+    }
+
+    // Computes the transformation from my own coordinate system to that of my parent
+    pub fn compute_local_matrix(&self) -> glm::Mat4 {
+        let mut transformation: glm::Mat4 = glm::identity();
+        transformation = glm::translation(&(-self.reference_point)) * transformation;
+        transformation = glm::scaling(&self.scale) * transformation;
+        transformation = glm::quat_to_mat4(&self.rotation_quat()) * transformation;
+        transformation = glm::translation(&self.reference_point) * transformation;
+        transformation = glm::translation(&self.position) * transformation;
+        transformation
+    }
+
+    // The cached matrices are only up to date after `SceneGraph::update_transforms`
+    pub fn local_matrix(&self) -> &glm::Mat4 {
+        &self.local_matrix
+    }
+
+    pub fn world_matrix(&self) -> &glm::Mat4 {
+        &self.world_matrix
+    }
+
+    // The bounding volumes are empty if there is nothing to draw, and only up to date after
+    // `SceneGraph::update_transforms`
+    pub fn bounds(&self) -> &Bounds {
+        &self.bounds
+    }
+
+    pub fn subtree_bounds(&self) -> &Bounds {
+        &self.subtree_bounds
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    #[allow(dead_code)]
+    pub fn get_child(&self, index: usize) -> NodeId {
+        self.children[index]
+    }
+
     #[allow(dead_code)]
     pub fn get_n_children(&self) -> usize {
         self.children.len()
@@ -74,15 +223,18 @@ impl SceneNode {
     pub fn print(&self) {
         println!(
 "SceneNode {{
+    Name:      {}
     VAO:       {}
     Indices:   {}
     Children:  {}
     Position:  [{:.2}, {:.2}, {:.2}]
-    Rotation:  [{:.2}, {:.2}, {:.2}]
+    Rotation:  [{:.2}, {:.2}, {:.2}] ({:?})
+    Scale:     [{:.2}, {:.2}, {:.2}]
     Reference: [{:.2}, {:.2}, {:.2}]
 }}",
-            self.vao_id,
-            self.index_count,
+            self.name,
+            self.gpu_mesh.as_ref().map_or(0, |m| m.vao_id()),
+            self.gpu_mesh.as_ref().map_or(-1, |m| m.index_count()),
             self.children.len(),
             self.position.x,
             self.position.y,
@@ -90,6 +242,10 @@ impl SceneNode {
             self.rotation.x,
             self.rotation.y,
             self.rotation.z,
+            self.euler_order,
+            self.scale.x,
+            self.scale.y,
+            self.scale.z,
             self.reference_point.x,
             self.reference_point.y,
             self.reference_point.z,
@@ -98,21 +254,706 @@ impl SceneNode {
 
 }
 
+impl Default for SceneNode {
+    fn default() -> Self {
+        SceneNode::new()
+    }
+}
 
-// You can also use square brackets to access the children of a SceneNode
-use std::ops::{Index, IndexMut};
-impl Index<usize> for SceneNode {
-    type Output = SceneNode;
-    fn index(&self, index: usize) -> &SceneNode {
-        unsafe {
-            & *(self.children[index] as *const SceneNode)
+
+// What a traversal of the scene drew. Nodes with a mesh which are outside the view frustum are
+// culled instead, including every one in a subtree which is culled as a whole.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DrawStats {
+    pub drawn      : usize,
+    pub culled     : usize,
+    pub draw_calls : usize,   // Fewer than `drawn` when nodes are drawn together
+}
+
+impl DrawStats {
+    pub fn total(&self) -> usize {
+        self.drawn + self.culled
+    }
+}
+
+impl fmt::Display for DrawStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} nodes drawn in {} draw calls, {} culled", self.drawn, self.total(), self.draw_calls, self.culled)
+    }
+}
+
+
+// Scene graph
+
+struct Slot {
+    generation : u32,
+    node       : Option<SceneNode>,
+}
+
+pub struct SceneGraph {
+    slots : Vec<Slot>,
+    free  : Vec<usize>,   // Indices of empty slots, ready to be reused
+    root  : NodeId,
+}
+
+impl SceneGraph {
+
+    // Creates a graph containing only an empty root node
+    pub fn new() -> SceneGraph {
+        let mut graph = SceneGraph {
+            slots : vec![],
+            free  : vec![],
+            root  : NodeId { index: 0, generation: 0 },
+        };
+        graph.root = graph.insert(SceneNode::new());
+        graph
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    // Number of nodes currently alive in the graph, including the root
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&SceneNode> {
+        self.slots.get(id.index)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.node.as_ref())
+    }
+
+    // Mutable access marks the node dirty, as we can't tell which fields the caller will change
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut SceneNode> {
+        let node = self.node_mut(id)?;
+        node.dirty = true;
+        Some(node)
+    }
+
+    // Mutable access for bookkeeping which does not affect the transform of the node
+    fn node_mut(&mut self, id: NodeId) -> Option<&mut SceneNode> {
+        self.slots.get_mut(id.index)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.node.as_mut())
+    }
+
+    // Moves `node` into the graph as the last child of `parent`, and returns its handle
+    pub fn add_child(&mut self, parent: NodeId, mut node: SceneNode) -> NodeId {
+        assert!(self.contains(parent), "Tried to add a child to a node which is not in the graph");
+        node.parent = None;
+        node.children.clear();
+        node.dirty = true;
+        let id = self.insert(node);
+        self.attach(parent, id);
+        id
+    }
+
+    // Removes a node along with its entire subtree, returning the removed node itself.
+    // The root can not be removed, but it can be emptied by removing its children.
+    pub fn remove(&mut self, id: NodeId) -> Option<SceneNode> {
+        if id == self.root || !self.contains(id) {
+            return None;
+        }
+        self.detach(id);
+
+        let mut node = self.take(id);
+        let mut stack = std::mem::take(&mut node.children);
+        while let Some(child) = stack.pop() {
+            let descendant = self.take(child);
+            stack.extend(descendant.children);
+        }
+        Some(node)
+    }
+
+    // Moves a node (with its subtree) to become the last child of `new_parent`
+    pub fn reparent(&mut self, id: NodeId, new_parent: NodeId) {
+        assert!(id != self.root, "The root node can not be reparented");
+        assert!(self.contains(id) && self.contains(new_parent), "Tried to reparent a node which is not in the graph");
+        assert!(!self.is_ancestor_of(id, new_parent), "A node can not be moved below itself");
+        self.detach(id);
+        self.attach(new_parent, id);
+        self.node(id).dirty = true;
+    }
+
+    // Recomputes the cached matrices of every dirty node and of everything below them, and the
+    // bounding volumes of every node
+    pub fn update_transforms(&mut self) {
+        let root = self.root;
+        self.update_subtree(root, &glm::identity(), false);
+    }
+
+    // The transformation from the coordinate system of `id` to world coordinates.
+    // Only the part of the tree above `id` is brought up to date, if needed.
+    pub fn world_matrix(&mut self, id: NodeId) -> glm::Mat4 {
+        // Find the topmost dirty node above us, everything above that is still valid
+        let mut topmost_dirty = None;
+        let mut current = Some(id);
+        while let Some(node_id) = current {
+            if self[node_id].dirty {
+                topmost_dirty = Some(node_id);
+            }
+            current = self[node_id].parent;
+        }
+
+        if let Some(dirty_id) = topmost_dirty {
+            let parent_world = match self[dirty_id].parent {
+                Some(parent) => self[parent].world_matrix,
+                None         => glm::identity(),
+            };
+            self.update_subtree(dirty_id, &parent_world, true);
+        }
+        self[id].world_matrix
+    }
+
+    // Where the reference point of `id` (the point it rotates about) currently is in the world
+    pub fn world_position(&mut self, id: NodeId) -> glm::Vec3 {
+        let reference_point = self[id].reference_point;
+        self.transform_point(id, &reference_point)
+    }
+
+    // Transforms a point given in the coordinate system of `id` to world coordinates
+    pub fn transform_point(&mut self, id: NodeId, point: &glm::Vec3) -> glm::Vec3 {
+        let world = self.world_matrix(id) * glm::vec4(point.x, point.y, point.z, 1.0);
+        glm::vec3(world.x, world.y, world.z)
+    }
+
+    // Where everything below (and including) `id` is in the world, e.g. for pointing the camera
+    // at it with `Sphere::framing_distance`. Empty if none of it has a mesh.
+    pub fn world_bounds(&mut self, id: NodeId) -> Bounds {
+        self.update_transforms();
+        self[id].subtree_bounds
+    }
+
+    // The node whose mesh the ray from `origin` in `direction` hits first, along with how far
+    // along the ray that is, in multiples of `direction`. Only the bounding volumes are tested,
+    // not the triangles, so a ray passing close by a mesh may count as a hit.
+    pub fn pick(&mut self, origin: &glm::Vec3, direction: &glm::Vec3) -> Option<(NodeId, f32)> {
+        self.update_transforms();
+        let mut nearest: Option<(NodeId, f32)> = None;
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            let node = &self[id];
+            // `is_none_or` is only in Rust 1.82 and newer
+            #[allow(clippy::unnecessary_map_or)]
+            let is_nearer = |distance: f32| nearest.map_or(true, |(_, nearest)| distance < nearest);
+            // Nothing below can be nearer than where the ray enters the subtree
+            match node.subtree_bounds.ray_intersection(origin, direction) {
+                Some(distance) if is_nearer(distance) => {}
+                _ => continue,
+            }
+            if let Some(distance) = node.bounds.ray_intersection(origin, direction) {
+                if is_nearer(distance) {
+                    nearest = Some((id, distance));
+                }
+            }
+            stack.extend(node.children.iter());
+        }
+        nearest
+    }
+
+    // Every node whose mesh touches `aabb`, given in world coordinates, parents before children
+    pub fn nodes_in(&mut self, aabb: &Aabb) -> Vec<NodeId> {
+        self.update_transforms();
+        let mut found = vec![];
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            let node = &self[id];
+            if node.subtree_bounds.is_empty() || !node.subtree_bounds.aabb.intersects(aabb) {
+                continue;
+            }
+            if !node.bounds.is_empty() && node.bounds.aabb.intersects(aabb) {
+                found.push(id);
+            }
+            stack.extend(node.children.iter().rev());
+        }
+        found
+    }
+
+    // Returns the bounds of the subtree
+    fn update_subtree(&mut self, id: NodeId, parent_world: &glm::Mat4, parent_changed: bool) -> Bounds {
+        let node = self.node(id);
+        let changed = parent_changed || node.dirty;
+        if node.dirty {
+            node.local_matrix = node.compute_local_matrix();
+            node.dirty = false;
+        }
+        if changed {
+            node.world_matrix = parent_world * node.local_matrix;
+            node.bounds = match (&node.mesh, &node.gpu_mesh) {
+                (Some(mesh), _)    => mesh.bounds.transformed(&node.world_matrix),
+                (None, Some(mesh)) => mesh.bounds().transformed(&node.world_matrix),
+                (None, None)       => Bounds::empty(),
+            };
+        }
+
+        let world = node.world_matrix;
+        let mut subtree_bounds = node.bounds;
+        for i in 0..self[id].children.len() {
+            let child = self[id].children[i];
+            subtree_bounds = subtree_bounds.union(&self.update_subtree(child, &world, changed));
+        }
+        self.node(id).subtree_bounds = subtree_bounds;
+        subtree_bounds
+    }
+
+    // Adds a node named after the model below `parent`, with a child for every object in the
+    // model, in the order they are in the file. The objects share their meshes with the model,
+    // so a model can be added many times without taking up more memory. Returns the new node.
+    pub fn add_model(&mut self, parent: NodeId, model: &Model) -> NodeId {
+        let group = self.add_child(parent, SceneNode {
+            name: model.name.clone(),
+            ..SceneNode::new()
+        });
+        for object in &model.objects {
+            self.add_child(group, SceneNode {
+                name: object.name.clone(),
+                texture: object.texture.clone(),
+                normal_texture: object.normal_texture.clone(),
+                ..SceneNode::from_mesh(Rc::clone(&object.mesh), object.gpu_mesh.clone())
+            });
+        }
+        group
+    }
+
+    // The first node below (and including) `id` with the given name
+    pub fn find(&self, id: NodeId, name: &str) -> Option<NodeId> {
+        self.descendants(id).into_iter().find(|&node_id| self[node_id].name == name)
+    }
+
+    // How many nodes below (and including) `id` have a mesh
+    pub fn drawable_count(&self, id: NodeId) -> usize {
+        self.descendants(id).into_iter().filter(|&node_id| self[node_id].is_drawable()).count()
+    }
+
+    // Is `ancestor` equal to, or somewhere above `id`?
+    pub fn is_ancestor_of(&self, ancestor: NodeId, id: NodeId) -> bool {
+        let mut current = Some(id);
+        while let Some(node_id) = current {
+            if node_id == ancestor {
+                return true;
+            }
+            current = self[node_id].parent;
+        }
+        false
+    }
+
+    // All nodes below (and including) `id`, parents always listed before their children
+    pub fn descendants(&self, id: NodeId) -> Vec<NodeId> {
+        let mut order = vec![];
+        let mut stack = vec![id];
+        while let Some(node_id) = stack.pop() {
+            order.push(node_id);
+            stack.extend(self[node_id].children.iter().rev());
+        }
+        order
+    }
+
+    fn insert(&mut self, node: SceneNode) -> NodeId {
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index];
+            slot.node = Some(node);
+            NodeId { index, generation: slot.generation }
+        } else {
+            self.slots.push(Slot { generation: 0, node: Some(node) });
+            NodeId { index: self.slots.len() - 1, generation: 0 }
         }
     }
+
+    // Empties the slot of `id` and bumps its generation, invalidating any remaining handles
+    fn take(&mut self, id: NodeId) -> SceneNode {
+        let slot = &mut self.slots[id.index];
+        let node = slot.node.take().expect("Scene graph slot was already empty");
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        node
+    }
+
+    fn node(&mut self, id: NodeId) -> &mut SceneNode {
+        self.node_mut(id).expect("Invalid NodeId, the node has been removed from the graph")
+    }
+
+    fn attach(&mut self, parent: NodeId, id: NodeId) {
+        self.node(parent).children.push(id);
+        self.node(id).parent = Some(parent);
+    }
+
+    fn detach(&mut self, id: NodeId) {
+        if let Some(parent) = self.node(id).parent.take() {
+            self.node(parent).children.retain(|&child| child != id);
+        }
+    }
+}
+
+impl Default for SceneGraph {
+    fn default() -> Self {
+        SceneGraph::new()
+    }
+}
+
+
+// You can use square brackets with a NodeId to access the nodes of a SceneGraph,
+// and with a (NodeId, usize) pair to access the n-th child of a node.
+impl Index<NodeId> for SceneGraph {
+    type Output = SceneNode;
+    fn index(&self, id: NodeId) -> &SceneNode {
+        self.get(id).expect("Invalid NodeId, the node has been removed from the graph")
+    }
+}
+impl IndexMut<NodeId> for SceneGraph {
+    fn index_mut(&mut self, id: NodeId) -> &mut SceneNode {
+        self.get_mut(id).expect("Invalid NodeId, the node has been removed from the graph")
+    }
 }
-impl IndexMut<usize> for SceneNode {
-    fn index_mut(&mut self, index: usize) -> &mut SceneNode {
-        unsafe {
-            &mut (*self.children[index])
+impl Index<(NodeId, usize)> for SceneGraph {
+    type Output = SceneNode;
+    fn index(&self, (id, child): (NodeId, usize)) -> &SceneNode {
+        let child_id = self[id].children[child];
+        &self[child_id]
+    }
+}
+impl IndexMut<(NodeId, usize)> for SceneGraph {
+    fn index_mut(&mut self, (id, child): (NodeId, usize)) -> &mut SceneNode {
+        let child_id = self[id].children[child];
+        &mut self[child_id]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A node with a box from -1 to 1 along every axis as its mesh, at `position`
+    fn cube_at(position: glm::Vec3) -> SceneNode {
+        let vertices = (0..8)
+            .flat_map(|v| [v & 4, v & 2, v & 1].map(|bit| if bit != 0 { 1.0 } else { -1.0 }))
+            .collect();
+        SceneNode {
+            position,
+            ..SceneNode::from_mesh(Rc::new(Mesh::from_positions(vertices, vec![0, 1, 2])), None)
+        }
+    }
+
+    fn assert_aabb(aabb: &Aabb, min: glm::Vec3, max: glm::Vec3) {
+        assert!(glm::distance(&aabb.min, &min) < 1e-5 && glm::distance(&aabb.max, &max) < 1e-5,
+            "{:?} is not from {:?} to {:?}", aabb, min, max);
+    }
+
+    #[test]
+    fn subtree_bounds_include_everything_below() {
+        let mut scene = SceneGraph::new();
+        let group = scene.add_child(scene.root(), SceneNode {
+            position: glm::vec3(10.0, 0.0, 0.0),
+            ..SceneNode::new()
+        });
+        let a = scene.add_child(group, cube_at(glm::vec3(0.0, 5.0, 0.0)));
+        let b = scene.add_child(group, SceneNode {
+            scale: glm::vec3(2.0, 2.0, 2.0),
+            ..cube_at(glm::vec3(0.0, 0.0, -5.0))
+        });
+        scene.update_transforms();
+
+        assert!(scene[group].bounds().is_empty());
+        assert_aabb(&scene[a].bounds().aabb, glm::vec3(9.0, 4.0, -1.0), glm::vec3(11.0, 6.0, 1.0));
+        assert_aabb(&scene[b].bounds().aabb, glm::vec3(8.0, -2.0, -7.0), glm::vec3(12.0, 2.0, -3.0));
+        assert_aabb(&scene[group].subtree_bounds().aabb, glm::vec3(8.0, -2.0, -7.0), glm::vec3(12.0, 6.0, 1.0));
+        assert_eq!(scene.world_bounds(scene.root()), *scene[group].subtree_bounds());
+
+        // Moving the group moves everything below it, moving a child only that child
+        scene[group].position = glm::vec3(0.0, 0.0, 0.0);
+        scene[b].position = glm::vec3(0.0, 0.0, 5.0);
+        let bounds = scene.world_bounds(scene.root());
+        assert_aabb(&bounds.aabb, glm::vec3(-2.0, -2.0, -1.0), glm::vec3(2.0, 6.0, 7.0));
+        assert!(bounds.sphere.contains(&glm::vec3(0.0, 6.0, 0.0)) && bounds.sphere.contains(&glm::vec3(0.0, 0.0, 7.0)));
+
+        // Removed nodes no longer count
+        scene.remove(b);
+        assert_aabb(&scene.world_bounds(scene.root()).aabb, glm::vec3(-1.0, 4.0, -1.0), glm::vec3(1.0, 6.0, 1.0));
+        scene.remove(a);
+        assert!(scene.world_bounds(scene.root()).is_empty());
+    }
+
+    #[test]
+    fn pick_finds_the_nearest_hit() {
+        let mut scene = SceneGraph::new();
+        let far = scene.add_child(scene.root(), cube_at(glm::vec3(0.0, 0.0, -20.0)));
+        let group = scene.add_child(scene.root(), SceneNode::new());
+        let near = scene.add_child(group, cube_at(glm::vec3(0.0, 0.0, -10.0)));
+        let aside = scene.add_child(group, cube_at(glm::vec3(5.0, 0.0, -5.0)));
+
+        let origin = glm::vec3(0.0, 0.0, 0.0);
+        let (hit, distance) = scene.pick(&origin, &glm::vec3(0.0, 0.0, -1.0)).unwrap();
+        assert_eq!(hit, near);
+        assert!((distance - 9.0).abs() < 1e-5, "hit at {}", distance);
+
+        // Distances are in multiples of the direction
+        let (_, distance) = scene.pick(&origin, &glm::vec3(0.0, 0.0, -2.0)).unwrap();
+        assert!((distance - 4.5).abs() < 1e-5, "hit at {}", distance);
+
+        assert_eq!(scene.pick(&origin, &glm::vec3(1.0, 0.0, -1.0)).map(|(id, _)| id), Some(aside));
+        assert_eq!(scene.pick(&origin, &glm::vec3(0.0, 0.0, 1.0)), None);
+        assert_eq!(scene.pick(&glm::vec3(0.0, 0.0, -15.0), &glm::vec3(0.0, 0.0, -1.0)).map(|(id, _)| id), Some(far));
+
+        scene.remove(group);
+        assert_eq!(scene.pick(&origin, &glm::vec3(0.0, 0.0, -1.0)).map(|(id, _)| id), Some(far));
+    }
+
+    fn named(name: &str) -> SceneNode {
+        SceneNode { name: name.to_string(), ..SceneNode::new() }
+    }
+
+    #[test]
+    fn remove_frees_the_subtree() {
+        let mut scene = SceneGraph::new();
+        let a = scene.add_child(scene.root(), named("a"));
+        let b = scene.add_child(a, named("b"));
+        let c = scene.add_child(b, named("c"));
+        let d = scene.add_child(scene.root(), named("d"));
+        assert_eq!(scene.len(), 5);
+
+        let removed = scene.remove(a).expect("a is in the graph");
+        assert_eq!(removed.name, "a");
+        assert_eq!(scene.len(), 2);
+        assert!(!scene.contains(a) && !scene.contains(b) && !scene.contains(c));
+        assert_eq!(scene[scene.root()].children(), [d]);
+        assert_eq!(scene.remove(a).map(|node| node.name), None);
+        assert!(scene.remove(scene.root()).is_none());
+
+        // The freed slots are used again before the arena grows
+        let slot_count = scene.slots.len();
+        for name in ["e", "f", "g"] {
+            scene.add_child(d, named(name));
         }
+        assert_eq!(scene.slots.len(), slot_count);
+        scene.add_child(d, named("h"));
+        assert_eq!(scene.slots.len(), slot_count + 1);
+    }
+
+    #[test]
+    fn stale_ids_are_rejected() {
+        let mut scene = SceneGraph::new();
+        let old = scene.add_child(scene.root(), named("old"));
+        scene.remove(old);
+        let new = scene.add_child(scene.root(), named("new"));
+        assert_eq!(new.index, old.index, "the slot is reused");
+        assert_ne!(new, old);
+
+        assert!(!scene.contains(old));
+        assert!(scene.get(old).is_none());
+        assert!(scene.get_mut(old).is_none());
+        assert!(scene.remove(old).is_none());
+        assert_eq!(scene[new].name, "new");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid NodeId")]
+    fn indexing_with_a_stale_id_panics() {
+        let mut scene = SceneGraph::new();
+        let old = scene.add_child(scene.root(), named("old"));
+        scene.remove(old);
+        scene.add_child(scene.root(), named("new"));
+        let _ = &scene[old];
+    }
+
+    #[test]
+    #[should_panic(expected = "A node can not be moved below itself")]
+    fn reparent_refuses_to_make_a_node_its_own_ancestor() {
+        let mut scene = SceneGraph::new();
+        let a = scene.add_child(scene.root(), named("a"));
+        let b = scene.add_child(a, named("b"));
+        let c = scene.add_child(b, named("c"));
+        scene.reparent(a, c);
+    }
+
+    #[test]
+    #[should_panic(expected = "A node can not be moved below itself")]
+    fn reparent_refuses_to_make_a_node_its_own_parent() {
+        let mut scene = SceneGraph::new();
+        let a = scene.add_child(scene.root(), named("a"));
+        scene.reparent(a, a);
+    }
+
+    #[test]
+    fn reparent_moves_the_subtree() {
+        let mut scene = SceneGraph::new();
+        let a = scene.add_child(scene.root(), named("a"));
+        let b = scene.add_child(a, named("b"));
+        let c = scene.add_child(b, named("c"));
+        let d = scene.add_child(scene.root(), named("d"));
+        scene.reparent(b, d);
+        assert!(scene[a].children().is_empty());
+        assert_eq!(scene[d].children(), [b]);
+        assert_eq!(scene[b].parent(), Some(d));
+        assert!(scene.is_ancestor_of(d, c));
+        assert!(!scene.is_ancestor_of(a, c));
+    }
+
+    #[test]
+    fn descendants_and_find_go_in_order() {
+        let mut scene = SceneGraph::new();
+        let root = scene.root();
+        let a = scene.add_child(root, named("a"));
+        let a1 = scene.add_child(a, named("a1"));
+        let a2 = scene.add_child(a, named("twin"));
+        let b = scene.add_child(root, named("b"));
+        let b1 = scene.add_child(b, named("twin"));
+        let c = scene.add_child(root, named("c"));
+
+        assert_eq!(scene[root].children(), [a, b, c]);
+        assert_eq!(scene.descendants(root), [root, a, a1, a2, b, b1, c]);
+        assert_eq!(scene.descendants(b), [b, b1]);
+        assert_eq!(scene.find(root, "twin"), Some(a2));
+        assert_eq!(scene.find(b, "twin"), Some(b1));
+        assert_eq!(scene.find(b, "a1"), None);
+        assert_eq!(scene[a].get_child(1), a2);
+        assert_eq!(scene[(root, 2)].name, "c");
+    }
+
+    fn assert_matrix_eq(actual: &glm::Mat4, expected: &glm::Mat4) {
+        assert!((actual - expected).abs().max() < 1e-5, "{} is not {}", actual, expected);
+    }
+
+    // Three levels deep, with a sibling branch next to the middle one
+    fn family() -> (SceneGraph, [NodeId; 4]) {
+        let mut scene = SceneGraph::new();
+        let node = |position: glm::Vec3, rotation: glm::Vec3| {
+            SceneNode { position, rotation, reference_point: glm::vec3(0.5, 0.0, 0.0), ..SceneNode::new() }
+        };
+        let grandparent = scene.add_child(scene.root(), node(glm::vec3(1.0, 2.0, 3.0), glm::vec3(0.3, 0.0, 0.0)));
+        let parent = scene.add_child(grandparent, node(glm::vec3(0.0, 1.0, 0.0), glm::vec3(0.0, 0.7, 0.0)));
+        let grandchild = scene.add_child(parent, node(glm::vec3(0.0, 0.0, -2.0), glm::vec3(0.0, 0.0, 1.1)));
+        let sibling = scene.add_child(grandparent, node(glm::vec3(4.0, 0.0, 0.0), glm::vec3(0.2, 0.2, 0.2)));
+        scene.update_transforms();
+        (scene, [grandparent, parent, grandchild, sibling])
+    }
+
+    #[test]
+    fn moving_a_grandparent_moves_the_grandchild() {
+        let (mut scene, [grandparent, parent, grandchild, _]) = family();
+        scene[grandparent].position = glm::vec3(-5.0, 0.5, 8.0);
+        assert!(scene[grandparent].is_dirty());
+        let on_demand = scene.world_matrix(grandchild);
+
+        let expected = scene[grandparent].compute_local_matrix()
+            * scene[parent].compute_local_matrix()
+            * scene[grandchild].compute_local_matrix();
+        assert_matrix_eq(&on_demand, &expected);
+
+        // A full update from scratch agrees with the one done on demand
+        for id in scene.descendants(scene.root()) {
+            scene[id].dirty = true;
+        }
+        scene.update_transforms();
+        assert_matrix_eq(scene[grandchild].world_matrix(), &on_demand);
+    }
+
+    #[test]
+    fn clean_subtrees_are_not_recomputed() {
+        let (mut scene, [grandparent, parent, grandchild, sibling]) = family();
+        let sibling_world = *scene[sibling].world_matrix();
+
+        // Moving the sibling without marking it dirty must go unnoticed, unless it is recomputed
+        scene.node_mut(sibling).unwrap().position = glm::vec3(100.0, 100.0, 100.0);
+        scene[parent].rotation.y = -0.4;
+        scene.update_transforms();
+        assert_eq!(*scene[sibling].world_matrix(), sibling_world);
+        assert!(!scene[parent].is_dirty() && !scene[grandchild].is_dirty());
+
+        // Nor does asking for a single world matrix touch unrelated dirty nodes
+        scene[sibling].rotation.x = 1.0;
+        scene[grandchild].rotation.z = 0.0;
+        scene.world_matrix(grandchild);
+        assert!(!scene[grandchild].is_dirty());
+        assert!(scene[sibling].is_dirty());
+        assert_eq!(*scene[sibling].world_matrix(), sibling_world);
+
+        // Until the sibling or one of its ancestors changes
+        scene[grandparent].position.x += 1.0;
+        scene.update_transforms();
+        assert_ne!(*scene[sibling].world_matrix(), sibling_world);
+    }
+
+    fn rotation_about(axis: usize, angle: f32) -> glm::Mat4 {
+        let mut axis_vec: glm::Vec3 = glm::zero();
+        axis_vec[axis] = 1.0;
+        glm::rotation(angle, &axis_vec)
+    }
+
+    #[test]
+    fn default_order_matches_the_original_matrix() {
+        let node = SceneNode {
+            position        : glm::vec3(1.0, -2.0, 3.0),
+            rotation        : glm::vec3(0.4, -1.2, 2.5),
+            reference_point : glm::vec3(-0.5, 0.25, 2.0),
+            ..SceneNode::new()
+        };
+        assert_eq!(node.euler_order, EulerOrder::ZYX);
+
+        // How the local matrix was built before the Euler order and scale could be chosen
+        let original = glm::translation(&node.position)
+            * glm::translation(&node.reference_point)
+            * glm::rotation(node.rotation.x, &glm::vec3(1.0, 0.0, 0.0))
+            * glm::rotation(node.rotation.y, &glm::vec3(0.0, 1.0, 0.0))
+            * glm::rotation(node.rotation.z, &glm::vec3(0.0, 0.0, 1.0))
+            * glm::translation(&(-node.reference_point));
+        assert_matrix_eq(&node.compute_local_matrix(), &original);
+    }
+
+    #[test]
+    fn every_order_applies_the_axes_in_turn() {
+        let rotation = glm::vec3(0.4, -1.2, 2.5);
+        let orders = [
+            (EulerOrder::XYZ, [0, 1, 2]),
+            (EulerOrder::XZY, [0, 2, 1]),
+            (EulerOrder::YXZ, [1, 0, 2]),
+            (EulerOrder::YZX, [1, 2, 0]),
+            (EulerOrder::ZXY, [2, 0, 1]),
+            (EulerOrder::ZYX, [2, 1, 0]),
+        ];
+        for &(order, [first, second, third]) in orders.iter() {
+            // The rotation applied first is the rightmost factor
+            let expected = rotation_about(third, rotation[third])
+                * rotation_about(second, rotation[second])
+                * rotation_about(first, rotation[first]);
+            assert_matrix_eq(&glm::quat_to_mat4(&order.to_quat(&rotation)), &expected);
+
+            let node = SceneNode { rotation, euler_order: order, ..SceneNode::new() };
+            assert_matrix_eq(&node.compute_local_matrix(), &expected);
+        }
+    }
+
+    #[test]
+    fn scaling_is_about_the_reference_point() {
+        let node = SceneNode {
+            position        : glm::vec3(10.0, 0.0, 0.0),
+            rotation        : glm::vec3(0.0, 0.9, 0.0),
+            scale           : glm::vec3(2.0, 3.0, 0.5),
+            reference_point : glm::vec3(1.0, 2.0, 3.0),
+            ..SceneNode::new()
+        };
+        let expected = glm::translation(&node.position)
+            * glm::translation(&node.reference_point)
+            * rotation_about(1, node.rotation.y)
+            * glm::scaling(&node.scale)
+            * glm::translation(&(-node.reference_point));
+        let local = node.compute_local_matrix();
+        assert_matrix_eq(&local, &expected);
+
+        // The reference point itself only moves by `position`, other points move away from it
+        let transform = |point: glm::Vec3| (local * glm::vec4(point.x, point.y, point.z, 1.0)).xyz();
+        assert!((transform(node.reference_point) - (node.reference_point + node.position)).norm() < 1e-5);
+        let offset = transform(node.reference_point + glm::vec3(0.0, 1.0, 0.0)) - transform(node.reference_point);
+        assert!((offset - glm::vec3(0.0, 3.0, 0.0)).norm() < 1e-5);
     }
 }
@@ -1,21 +1,59 @@
-use gl;
+extern crate nalgebra_glm as glm;
+
 use std::{
+    collections::HashMap,
+    fmt,
     ptr,
     str,
     ffi::CString,
-    path::Path,
+    path::{Path, PathBuf},
+    time::SystemTime,
 };
 
 pub struct Shader {
     pub program_id: u32,
+    pub uniforms: HashMap<String, ShaderVariable>,          // The active uniforms outside of blocks
+    pub uniform_blocks: HashMap<String, UniformBlock>,
+    pub attributes: HashMap<String, ShaderVariable>,        // The active vertex attributes
+    stages: Vec<Stage>,                 // What the program was built from, for reloading
+    dependencies: Vec<PathBuf>,         // Every file which was read, including the included ones
+    modified: Vec<Option<SystemTime>>,  // When the dependencies were last changed
 }
 
 pub struct ShaderBuilder {
     program_id: u32,
     shaders: Vec::<u32>,
+    stages: Vec<Stage>,
+    dependencies: Vec<PathBuf>,
+    defines: Vec<(String, String)>,     // Injected into the shaders attached from now on
+}
+
+// An active uniform or vertex attribute of a linked program
+#[derive(Clone, Debug)]
+pub struct ShaderVariable {
+    pub name     : String,
+    pub gl_type  : gl::types::GLenum,   // e.g. gl::FLOAT_MAT4
+    pub size     : i32,                 // The length for arrays, otherwise 1
+    pub location : i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct UniformBlock {
+    pub name      : String,
+    pub index     : u32,
+    pub binding   : u32,
+    pub data_size : i32,    // In bytes
+}
+
+// A shader attached to a builder, along with the defines it was compiled with
+#[derive(Clone)]
+enum Stage {
+    File(PathBuf, Vec<(String, String)>),
+    Source(String, ShaderType, Vec<(String, String)>),
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ShaderType {
     Vertex,
     Fragment,
@@ -24,21 +62,223 @@ pub enum ShaderType {
     Geometry,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShaderErrorKind {
+    Read,       // The source file could not be read, or has an unknown extension
+    Preprocess, // A malformed or cyclic #include
+    Compile,
+    Link,
+}
+
+// A line of the info log which refers to a line in the source
+#[derive(Clone, Debug)]
+pub struct ShaderDiagnostic {
+    pub path    : Option<PathBuf>,  // The file the line is in, if the source was read from a file
+    pub line    : u32,
+    pub message : String,
+}
+
+#[derive(Debug)]
+pub struct ShaderError {
+    pub kind         : ShaderErrorKind,
+    pub stage        : Option<ShaderType>,  // None for link errors
+    pub path         : Option<PathBuf>,     // None for link errors and shaders compiled from a string
+    pub log          : String,              // The full info log from the driver
+    pub diagnostics  : Vec<ShaderDiagnostic>,
+    pub dependencies : Vec<PathBuf>,        // The files read (or tried) so far, to know when to try again
+}
+
+impl ShaderError {
+    fn read(path: &Path, stage: Option<ShaderType>, message: String) -> ShaderError {
+        ShaderError {
+            kind: ShaderErrorKind::Read,
+            stage,
+            path: Some(path.to_path_buf()),
+            log: message,
+            diagnostics: vec![],
+            dependencies: vec![],
+        }
+    }
+
+    fn preprocess(path: Option<&Path>, stage: ShaderType, message: String) -> ShaderError {
+        ShaderError {
+            kind: ShaderErrorKind::Preprocess,
+            stage: Some(stage),
+            path: path.map(Path::to_path_buf),
+            log: message,
+            diagnostics: vec![],
+            dependencies: vec![],
+        }
+    }
+}
+
+impl fmt::Display for ShaderType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ShaderType::Vertex                  => "vertex",
+            ShaderType::Fragment                => "fragment",
+            ShaderType::TessellationControl     => "tessellation control",
+            ShaderType::TessellationEvaluation  => "tessellation evaluation",
+            ShaderType::Geometry                => "geometry",
+        })
+    }
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.kind, self.stage) {
+            (ShaderErrorKind::Read, _)              => write!(f, "Failed to read shader")?,
+            (ShaderErrorKind::Preprocess, _)        => write!(f, "Failed to preprocess shader")?,
+            (ShaderErrorKind::Compile, Some(stage)) => write!(f, "Failed to compile {} shader", stage)?,
+            (ShaderErrorKind::Compile, None)        => write!(f, "Failed to compile shader")?,
+            (ShaderErrorKind::Link, _)              => write!(f, "Failed to link shader program")?,
+        }
+        if let Some(path) = &self.path {
+            write!(f, " {}", path.display())?;
+        }
+        writeln!(f, ":")?;
+
+        // Point at the lines in the source when we could make sense of the log,
+        // otherwise show the log as it is
+        if self.diagnostics.is_empty() {
+            write!(f, "{}", self.log.trim_end())
+        } else {
+            for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+                if i > 0 {
+                    writeln!(f)?;
+                }
+                match &diagnostic.path {
+                    Some(path) => write!(f, "{}:{}: {}", path.display(), diagnostic.line, diagnostic.message)?,
+                    None       => write!(f, "line {}: {}", diagnostic.line, diagnostic.message)?,
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
 impl Shader {
-    // Make sure the shader is active before calling this
-    pub unsafe fn get_uniform_location(&self, name: &str) -> i32 {
-        let name_cstr = CString::new(name).expect("CString::new failed");
-        gl::GetUniformLocation(self.program_id, name_cstr.as_ptr())
+    // -1 if there is no active uniform with this name, like `gl::GetUniformLocation`
+    pub fn get_uniform_location(&self, name: &str) -> i32 {
+        self.uniforms.get(name).map_or(-1, |uniform| uniform.location)
+    }
+
+    // -1 if there is no active attribute with this name, like `gl::GetAttribLocation`
+    pub fn get_attribute_location(&self, name: &str) -> i32 {
+        self.attributes.get(name).map_or(-1, |attribute| attribute.location)
+    }
+
+    // The setters below don't need the shader to be active. Uniforms which are not active, e.g.
+    // because the compiler removed them as unused, are ignored, like OpenGL does. Setting a
+    // uniform with a value of the wrong type is a bug, and panics in debug builds.
+
+    pub unsafe fn set_f32(&self, name: &str, value: f32) {
+        if let Some(location) = self.checked_location(name, &[gl::FLOAT]) {
+            gl::ProgramUniform1f(self.program_id, location, value);
+        }
+    }
+
+    // Also used for booleans and to set the texture unit of samplers
+    pub unsafe fn set_i32(&self, name: &str, value: i32) {
+        let types = [gl::INT, gl::BOOL, gl::SAMPLER_2D, gl::SAMPLER_3D, gl::SAMPLER_CUBE, gl::SAMPLER_2D_SHADOW];
+        if let Some(location) = self.checked_location(name, &types) {
+            gl::ProgramUniform1i(self.program_id, location, value);
+        }
+    }
+
+    pub unsafe fn set_vec2(&self, name: &str, value: &glm::Vec2) {
+        if let Some(location) = self.checked_location(name, &[gl::FLOAT_VEC2]) {
+            gl::ProgramUniform2fv(self.program_id, location, 1, value.as_ptr());
+        }
+    }
+
+    pub unsafe fn set_vec3(&self, name: &str, value: &glm::Vec3) {
+        if let Some(location) = self.checked_location(name, &[gl::FLOAT_VEC3]) {
+            gl::ProgramUniform3fv(self.program_id, location, 1, value.as_ptr());
+        }
+    }
+
+    pub unsafe fn set_vec4(&self, name: &str, value: &glm::Vec4) {
+        if let Some(location) = self.checked_location(name, &[gl::FLOAT_VEC4]) {
+            gl::ProgramUniform4fv(self.program_id, location, 1, value.as_ptr());
+        }
+    }
+
+    pub unsafe fn set_mat3(&self, name: &str, value: &glm::Mat3) {
+        if let Some(location) = self.checked_location(name, &[gl::FLOAT_MAT3]) {
+            gl::ProgramUniformMatrix3fv(self.program_id, location, 1, gl::FALSE, value.as_ptr());
+        }
+    }
+
+    pub unsafe fn set_mat4(&self, name: &str, value: &glm::Mat4) {
+        if let Some(location) = self.checked_location(name, &[gl::FLOAT_MAT4]) {
+            gl::ProgramUniformMatrix4fv(self.program_id, location, 1, gl::FALSE, value.as_ptr());
+        }
+    }
+
+    fn checked_location(&self, name: &str, types: &[gl::types::GLenum]) -> Option<i32> {
+        let uniform = self.uniforms.get(name)?;
+        debug_assert!(types.contains(&uniform.gl_type),
+            "Uniform {} is a {}, but was set with a {}",
+            name, gl_type_name(uniform.gl_type), gl_type_name(types[0]));
+        Some(uniform.location)
     }
 
     pub unsafe fn activate(&self) {
         gl::UseProgram(self.program_id);
     }
+
+    // Rebuilds the program if any of its files have changed since it was built. If the new
+    // program fails to build, the error is returned and the previous program is kept, so the
+    // scene can still be drawn until the files are fixed. Returns whether the program changed.
+    //
+    // Remember to activate the shader again afterwards, the new program is not in use.
+    pub unsafe fn reload_if_changed(&mut self) -> Result<bool, ShaderError> {
+        let modified = modification_times(&self.dependencies);
+        if modified == self.modified {
+            return Ok(false);
+        }
+        // Don't try again until the files change once more
+        self.modified = modified;
+
+        let mut builder = ShaderBuilder::new();
+        for stage in &self.stages {
+            builder.defines = match stage {
+                Stage::File(_, defines) | Stage::Source(_, _, defines) => defines.clone(),
+            };
+            builder = match stage {
+                Stage::File(path, _) => builder.attach_file(path)?,
+                Stage::Source(source, shader_type, _) => builder.compile_shader(source, *shader_type)?,
+            };
+        }
+        let mut new = builder.link()?;
+
+        // `new` ends up with the previous program, which is deleted as it's dropped
+        std::mem::swap(self, &mut new);
+        Ok(true)
+    }
+}
+
+impl Drop for Shader {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteProgram(self.program_id);
+        }
+    }
+}
+
+// When each file was last modified, None for files which can't be read
+pub fn modification_times<P: AsRef<Path>>(files: &[P]) -> Vec<Option<SystemTime>> {
+    files.iter()
+        .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
 }
 
-impl Into<gl::types::GLenum> for ShaderType {
-    fn into(self) -> gl::types::GLenum {
-        match self {
+impl From<ShaderType> for gl::types::GLenum {
+    fn from(shader_type: ShaderType) -> gl::types::GLenum {
+        match shader_type {
             ShaderType::Vertex                  => { gl::VERTEX_SHADER          },
             ShaderType::Fragment                => { gl::FRAGMENT_SHADER        },
             ShaderType::TessellationControl     => { gl::TESS_CONTROL_SHADER    },
@@ -50,105 +290,659 @@ impl Into<gl::types::GLenum> for ShaderType {
 
 impl ShaderType {
     fn from_ext(ext: &std::ffi::OsStr) -> Result<ShaderType, String> {
-        match ext.to_str().expect("Failed to read extension") {
-            "vert" => { Ok(ShaderType::Vertex) },
-            "frag" => { Ok(ShaderType::Fragment) },
-            "tcs"  => { Ok(ShaderType::TessellationControl) },
-            "tes"  => { Ok(ShaderType::TessellationEvaluation) },
-            "geom" => { Ok(ShaderType::Geometry) },
-            e => { Err(e.to_string()) },
+        match ext.to_str() {
+            Some("vert") => { Ok(ShaderType::Vertex) },
+            Some("frag") => { Ok(ShaderType::Fragment) },
+            Some("tcs")  => { Ok(ShaderType::TessellationControl) },
+            Some("tes")  => { Ok(ShaderType::TessellationEvaluation) },
+            Some("geom") => { Ok(ShaderType::Geometry) },
+            _ => { Err(ext.to_string_lossy().into_owned()) },
         }
     }
 }
 
+// Picks the lines which refer to the source out of an info log. Every driver has its own format,
+// the common ones are
+//     Mesa:           0:12(5): error: `foo' undeclared
+//     Nvidia:         0(12) : error C1008: undefined variable "foo"
+//     AMD and Intel:  ERROR: 0:12: 'foo' : undeclared identifier
+// where the first number is the index of the source string and the second is the line. The
+// `#line` directives from `preprocess` give each file its own range of line numbers, which tells
+// us which file in `files` the line is in. Lines we don't recognize are left out, they are still
+// in the full log.
+fn parse_info_log(log: &str, files: &[Option<PathBuf>]) -> Vec<ShaderDiagnostic> {
+    log.lines().filter_map(|line| {
+        let line = line.trim();
+        let rest = line.strip_prefix("ERROR: ")
+            .or_else(|| line.strip_prefix("WARNING: "))
+            .unwrap_or(line);
+
+        // The source string index, which we don't need
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        if digits == 0 {
+            return None;
+        }
+        let rest = &rest[digits..];
+
+        // The line number, either as `:12` or `(12)`
+        let (line_number, rest): (u32, &str) = if let Some(rest) = rest.strip_prefix(':') {
+            let end = rest.find(|c: char| !c.is_ascii_digit())?;
+            (rest[..end].parse().ok()?, &rest[end..])
+        } else if let Some(rest) = rest.strip_prefix('(') {
+            let (number, rest) = rest.split_once(')')?;
+            (number.parse().ok()?, rest)
+        } else {
+            return None;
+        };
+
+        // Skip the column Mesa adds, and the separator before the message
+        let rest = match rest.strip_prefix('(') {
+            Some(rest) => rest.split_once(')').map_or(rest, |(_, rest)| rest),
+            None => rest,
+        };
+        let message = rest.trim_start_matches(|c: char| c == ':' || c.is_whitespace());
+
+        Some(ShaderDiagnostic {
+            path    : files.get((line_number / LINES_PER_FILE) as usize).cloned().flatten(),
+            line    : line_number % LINES_PER_FILE,
+            message : message.to_string(),
+        })
+    }).collect()
+}
+
 impl ShaderBuilder {
     pub unsafe fn new() -> ShaderBuilder {
         ShaderBuilder {
             program_id: gl::CreateProgram(),
             shaders: vec![],
+            stages: vec![],
+            dependencies: vec![],
+            defines: vec![],
         }
     }
 
-    pub unsafe fn attach_file(self, shader_path: &str) -> ShaderBuilder {
-        let path = Path::new(shader_path);
-        if let Some(extension) = path.extension() {
-            let shader_type = ShaderType::from_ext(extension)
-                .expect("Failed to parse file extension.");
-            let shader_src = std::fs::read_to_string(path)
-                .expect(&format!("Failed to read shader source. {}", shader_path));
-            self.compile_shader(&shader_src, shader_type)
-        } else {
-            panic!("Failed to read extension of file with path: {}", shader_path);
-        }
+    // Adds `#define name value` to the shaders attached after this, e.g. to build a variant of
+    // a shader with `.define("WITH_FOG", "1")`
+    pub fn define(mut self, name: &str, value: &str) -> ShaderBuilder {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
     }
 
-    pub unsafe fn compile_shader(mut self, shader_src: &str, shader_type: ShaderType) -> ShaderBuilder {
+    pub unsafe fn attach_file<P: AsRef<Path>>(mut self, shader_path: P) -> Result<ShaderBuilder, ShaderError> {
+        let path = shader_path.as_ref();
+        let read_error = |stage, message| ShaderError {
+            dependencies: self.dependencies.iter().cloned().chain(std::iter::once(path.to_path_buf())).collect(),
+            ..ShaderError::read(path, stage, message)
+        };
+        let extension = path.extension()
+            .ok_or_else(|| read_error(None, "The file has no extension".to_string()))?;
+        let shader_type = ShaderType::from_ext(extension)
+            .map_err(|e| read_error(None, format!("Unknown shader extension: {}", e)))?;
+        let shader_src = std::fs::read_to_string(path)
+            .map_err(|e| read_error(Some(shader_type), e.to_string()))?;
+        self.stages.push(Stage::File(path.to_path_buf(), self.defines.clone()));
+        self.compile(&shader_src, shader_type, Some(path))
+    }
+
+    pub unsafe fn compile_shader(mut self, shader_src: &str, shader_type: ShaderType) -> Result<ShaderBuilder, ShaderError> {
+        self.stages.push(Stage::Source(shader_src.to_string(), shader_type, self.defines.clone()));
+        self.compile(shader_src, shader_type, None)
+    }
+
+    unsafe fn compile(mut self, shader_src: &str, shader_type: ShaderType, path: Option<&Path>) -> Result<ShaderBuilder, ShaderError> {
+        let preprocessed = preprocess(shader_src, path, shader_type, &self.defines).map_err(|mut e| {
+            e.dependencies.splice(0..0, self.dependencies.iter().cloned());
+            e
+        })?;
+        self.dependencies.extend(preprocessed.files.iter().flatten().cloned());
+        let shader_src = preprocessed.source.as_str();
+
+        let source_error = |log: String| ShaderError {
+            kind: ShaderErrorKind::Compile,
+            stage: Some(shader_type),
+            path: path.map(Path::to_path_buf),
+            log,
+            diagnostics: vec![],
+            dependencies: self.dependencies.clone(),
+        };
+        let c_str_shader = CString::new(shader_src.as_bytes())
+            .map_err(|_| source_error("The source contains a null byte".to_string()))?;
+
         let shader = gl::CreateShader(shader_type.into());
-        let c_str_shader = CString::new(shader_src.as_bytes()).unwrap();
         gl::ShaderSource(shader, 1, &c_str_shader.as_ptr(), ptr::null());
         gl::CompileShader(shader);
 
-        if !self.check_shader_errors(shader) {
-            panic!("Shader failed to compile.");
+        if let Err(log) = self.check_shader_errors(shader) {
+            gl::DeleteShader(shader);
+            return Err(ShaderError {
+                diagnostics: parse_info_log(&log, &preprocessed.files),
+                ..source_error(log)
+            });
         }
 
         self.shaders.push(shader);
 
-        self
+        Ok(self)
     }
 
-    unsafe fn check_shader_errors(&self, shader_id: u32) -> bool {
+    // Returns the full info log if the shader failed to compile
+    unsafe fn check_shader_errors(&self, shader_id: u32) -> Result<(), String> {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
         gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
+            let mut length = 0;
+            gl::GetShaderiv(shader_id, gl::INFO_LOG_LENGTH, &mut length);
+            let mut info_log = vec![0u8; length.max(1) as usize];
+            let mut written = 0;
             gl::GetShaderInfoLog(
                 shader_id,
-                512,
-                ptr::null_mut(),
+                info_log.len() as i32,
+                &mut written,
                 info_log.as_mut_ptr() as *mut gl::types::GLchar,
             );
-            println!("ERROR::Shader Compilation Failed!\n{}", String::from_utf8_lossy(&info_log));
-            return false;
+            info_log.truncate(written as usize);
+            return Err(String::from_utf8_lossy(&info_log).into_owned());
         }
-        true
+        Ok(())
     }
 
-    unsafe fn check_linker_errors(&self) -> bool {
+    // Returns the full info log if the program failed to link
+    unsafe fn check_linker_errors(&self) -> Result<(), String> {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
         gl::GetProgramiv(self.program_id, gl::LINK_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
+            let mut length = 0;
+            gl::GetProgramiv(self.program_id, gl::INFO_LOG_LENGTH, &mut length);
+            let mut info_log = vec![0u8; length.max(1) as usize];
+            let mut written = 0;
             gl::GetProgramInfoLog(
                 self.program_id,
-                512,
-                ptr::null_mut(),
+                info_log.len() as i32,
+                &mut written,
                 info_log.as_mut_ptr() as *mut gl::types::GLchar,
             );
-            println!("ERROR::SHADER::PROGRAM::COMPILATION_FAILED\n{}", String::from_utf8_lossy(&info_log));
-            return false;
+            info_log.truncate(written as usize);
+            return Err(String::from_utf8_lossy(&info_log).into_owned());
         }
-        true
+        Ok(())
     }
 
     #[must_use = "The shader program is useless if not stored in a variable."]
-    pub unsafe fn link(self) -> Shader {
+    pub unsafe fn link(mut self) -> Result<Shader, ShaderError> {
         for &shader in &self.shaders {
             gl::AttachShader(self.program_id, shader);
         }
         gl::LinkProgram(self.program_id);
 
-        // todo:: use this to make safer abstraction
-        self.check_linker_errors();
+        self.check_linker_errors().map_err(|log| ShaderError {
+            kind: ShaderErrorKind::Link,
+            stage: None,
+            path: None,
+            diagnostics: parse_info_log(&log, &[]),
+            dependencies: self.dependencies.clone(),
+            log,
+        })?;
 
-        for &shader in &self.shaders {
-            gl::DeleteShader(shader);
+        // The program is no longer ours to clean up
+        let program_id = std::mem::replace(&mut self.program_id, 0);
+        let mut dependencies = std::mem::take(&mut self.dependencies);
+        dependencies.sort();
+        dependencies.dedup();
+        Ok(Shader {
+            program_id,
+            uniforms: reflect_uniforms(program_id),
+            uniform_blocks: reflect_uniform_blocks(program_id),
+            attributes: reflect_attributes(program_id),
+            stages: std::mem::take(&mut self.stages),
+            modified: modification_times(&dependencies),
+            dependencies,
+        })
+    }
+}
+
+impl Drop for ShaderBuilder {
+    // The shaders are no longer needed once linked, and on failure neither is the program
+    fn drop(&mut self) {
+        unsafe {
+            for &shader in &self.shaders {
+                gl::DeleteShader(shader);
+            }
+            if self.program_id != 0 {
+                gl::DeleteProgram(self.program_id);
+            }
+        }
+    }
+}
+
+
+// Reflection
+
+// Arrays are reported as `name[0]`, and are looked up without the index
+fn variable_name(buffer: &[u8], length: i32) -> String {
+    let name = String::from_utf8_lossy(&buffer[..length as usize]);
+    name.strip_suffix("[0]").unwrap_or(&name).to_string()
+}
+
+unsafe fn reflect_uniforms(program_id: u32) -> HashMap<String, ShaderVariable> {
+    let (mut count, mut max_length) = (0, 0);
+    gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORMS, &mut count);
+    gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORM_MAX_LENGTH, &mut max_length);
+
+    let mut buffer = vec![0u8; max_length.max(1) as usize];
+    (0..count as u32).filter_map(|index| {
+        let (mut length, mut size, mut gl_type) = (0, 0, 0);
+        gl::GetActiveUniform(program_id, index, buffer.len() as i32,
+            &mut length, &mut size, &mut gl_type, buffer.as_mut_ptr() as *mut gl::types::GLchar);
+        let location = gl::GetUniformLocation(program_id, buffer.as_ptr() as *const gl::types::GLchar);
+        // Uniforms in blocks have no location, they are set through the buffer bound to the block
+        if location == -1 {
+            return None;
+        }
+        let name = variable_name(&buffer, length);
+        Some((name.clone(), ShaderVariable { name, gl_type, size, location }))
+    }).collect()
+}
+
+unsafe fn reflect_uniform_blocks(program_id: u32) -> HashMap<String, UniformBlock> {
+    let (mut count, mut max_length) = (0, 0);
+    gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORM_BLOCKS, &mut count);
+    gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORM_BLOCK_MAX_NAME_LENGTH, &mut max_length);
+
+    let mut buffer = vec![0u8; max_length.max(1) as usize];
+    (0..count as u32).map(|index| {
+        let (mut length, mut binding, mut data_size) = (0, 0, 0);
+        gl::GetActiveUniformBlockName(program_id, index, buffer.len() as i32,
+            &mut length, buffer.as_mut_ptr() as *mut gl::types::GLchar);
+        gl::GetActiveUniformBlockiv(program_id, index, gl::UNIFORM_BLOCK_BINDING, &mut binding);
+        gl::GetActiveUniformBlockiv(program_id, index, gl::UNIFORM_BLOCK_DATA_SIZE, &mut data_size);
+        let name = variable_name(&buffer, length);
+        (name.clone(), UniformBlock { name, index, binding: binding as u32, data_size })
+    }).collect()
+}
+
+unsafe fn reflect_attributes(program_id: u32) -> HashMap<String, ShaderVariable> {
+    let (mut count, mut max_length) = (0, 0);
+    gl::GetProgramiv(program_id, gl::ACTIVE_ATTRIBUTES, &mut count);
+    gl::GetProgramiv(program_id, gl::ACTIVE_ATTRIBUTE_MAX_LENGTH, &mut max_length);
+
+    let mut buffer = vec![0u8; max_length.max(1) as usize];
+    (0..count as u32).filter_map(|index| {
+        let (mut length, mut size, mut gl_type) = (0, 0, 0);
+        gl::GetActiveAttrib(program_id, index, buffer.len() as i32,
+            &mut length, &mut size, &mut gl_type, buffer.as_mut_ptr() as *mut gl::types::GLchar);
+        let location = gl::GetAttribLocation(program_id, buffer.as_ptr() as *const gl::types::GLchar);
+        // Built-in inputs like gl_VertexID have no location
+        if location == -1 {
+            return None;
+        }
+        let name = variable_name(&buffer, length);
+        Some((name.clone(), ShaderVariable { name, gl_type, size, location }))
+    }).collect()
+}
+
+// For error messages
+pub fn gl_type_name(gl_type: gl::types::GLenum) -> &'static str {
+    match gl_type {
+        gl::FLOAT             => "float",
+        gl::FLOAT_VEC2        => "vec2",
+        gl::FLOAT_VEC3        => "vec3",
+        gl::FLOAT_VEC4        => "vec4",
+        gl::INT               => "int",
+        gl::INT_VEC2          => "ivec2",
+        gl::INT_VEC3          => "ivec3",
+        gl::INT_VEC4          => "ivec4",
+        gl::UNSIGNED_INT      => "uint",
+        gl::BOOL              => "bool",
+        gl::FLOAT_MAT2        => "mat2",
+        gl::FLOAT_MAT3        => "mat3",
+        gl::FLOAT_MAT4        => "mat4",
+        gl::SAMPLER_2D        => "sampler2D",
+        gl::SAMPLER_3D        => "sampler3D",
+        gl::SAMPLER_CUBE      => "samplerCube",
+        gl::SAMPLER_2D_SHADOW => "sampler2DShadow",
+        _                     => "unknown type",
+    }
+}
+
+
+// Preprocessing
+
+// The line numbers of the file with source string number `n` start at `n * LINES_PER_FILE`.
+// Drivers are supposed to report the source string number of every error, but Mesa reports 0
+// for most of them, so the file is also encoded in the line number.
+const LINES_PER_FILE: u32 = 100_000;
+
+fn line_directive(file_index: usize, line: usize) -> String {
+    format!("#line {} {}\n", file_index * LINES_PER_FILE as usize + line, file_index)
+}
+
+// A shader with its includes and defines filled in
+struct Preprocessed {
+    source : String,
+    files  : Vec<Option<PathBuf>>,  // The file of every source string number used in `#line`
+}
+
+// Fills in `#include "file.glsl"`, relative to the directory of the including file, and adds the
+// defines right after `#version`. Every file gets its own source string number, and `#line`
+// directives are added around the includes, so that the line numbers in the info log can be
+// mapped back to the files by `parse_info_log`. Files longer than `LINES_PER_FILE` lines would
+// confuse the mapping.
+//
+// A file containing `#pragma once` is only included the first time, and a file which ends up
+// including itself is an error.
+fn preprocess(source: &str, path: Option<&Path>, stage: ShaderType, defines: &[(String, String)]) -> Result<Preprocessed, ShaderError> {
+    let mut preprocessor = Preprocessor {
+        root: path,
+        stage,
+        output: String::new(),
+        files: vec![path.map(Path::to_path_buf)],
+        included_once: vec![],
+        stack: vec![path.map(canonical)],
+    };
+
+    // The version must come before anything else, so the defines go right after it
+    let mut lines = source.lines().enumerate().peekable();
+    let has_version = source.lines().any(|line| directive(line, "version").is_some());
+    if has_version {
+        for (_, line) in lines.by_ref() {
+            preprocessor.output.push_str(line);
+            preprocessor.output.push('\n');
+            if directive(line, "version").is_some() {
+                break;
+            }
+        }
+    }
+    for (name, value) in defines {
+        preprocessor.output.push_str(&format!("#define {} {}\n", name, value));
+    }
+    if let Some((next, _)) = lines.peek() {
+        preprocessor.output.push_str(&line_directive(0, next + 1));
+    }
+
+    let base = path.and_then(Path::parent).unwrap_or_else(|| Path::new("."));
+    if let Err(mut e) = preprocessor.expand(lines, 0, base) {
+        // Including a missing file fails with the path of that file, which may appear later
+        e.dependencies = preprocessor.files.iter().flatten().cloned().collect();
+        if e.kind == ShaderErrorKind::Read {
+            e.dependencies.extend(e.path.clone());
+        }
+        return Err(e);
+    }
+
+    Ok(Preprocessed {
+        source: preprocessor.output,
+        files: preprocessor.files,
+    })
+}
+
+struct Preprocessor<'a> {
+    root          : Option<&'a Path>,
+    stage         : ShaderType,
+    output        : String,
+    files         : Vec<Option<PathBuf>>,
+    included_once : Vec<PathBuf>,           // Files with `#pragma once` which have been included
+    stack         : Vec<Option<PathBuf>>,   // The files currently being included, to find cycles
+}
+
+impl Preprocessor<'_> {
+    fn expand<'s, I>(&mut self, lines: I, file_index: usize, dir: &Path) -> Result<(), ShaderError>
+        where I: Iterator<Item = (usize, &'s str)>
+    {
+        for (i, line) in lines {
+            let argument = match directive(line, "include") {
+                Some(argument) => argument,
+                None => {
+                    if directive(line, "pragma") != Some("once") {
+                        self.output.push_str(line);
+                    }
+                    self.output.push('\n');
+                    continue;
+                }
+            };
+
+            let location = || match &self.files[file_index] {
+                Some(file) => format!("{}:{}", file.display(), i + 1),
+                None => format!("line {}", i + 1),
+            };
+            let name = argument.strip_prefix('"').and_then(|a| a.strip_suffix('"'))
+                .ok_or_else(|| ShaderError::preprocess(self.root, self.stage,
+                    format!("{}: expected #include \"file\"", location())))?;
+            let path = dir.join(name);
+            let canonical_path = canonical(&path);
+
+            if self.stack.contains(&Some(canonical_path.clone())) {
+                let cycle: Vec<String> = self.stack.iter().flatten()
+                    .chain(std::iter::once(&canonical_path))
+                    .map(|p| p.display().to_string())
+                    .collect();
+                return Err(ShaderError::preprocess(self.root, self.stage,
+                    format!("{}: include cycle: {}", location(), cycle.join(" -> "))));
+            }
+            if self.included_once.contains(&canonical_path) {
+                self.output.push('\n');
+                continue;
+            }
+
+            let source = std::fs::read_to_string(&path)
+                .map_err(|e| ShaderError::read(&path, Some(self.stage),
+                    format!("{}, included from {}", e, location())))?;
+            if source.lines().any(|line| directive(line, "pragma") == Some("once")) {
+                self.included_once.push(canonical_path.clone());
+            }
+
+            // Reuse the source string number if the file has been included before
+            let index = match self.files.iter().position(|f| f.as_ref() == Some(&path)) {
+                Some(index) => index,
+                None => {
+                    self.files.push(Some(path.clone()));
+                    self.files.len() - 1
+                }
+            };
+
+            self.output.push_str(&line_directive(index, 1));
+            self.stack.push(Some(canonical_path));
+            self.expand(source.lines().enumerate(), index, path.parent().unwrap_or(dir))?;
+            self.stack.pop();
+            self.output.push_str(&line_directive(file_index, i + 2));
+        }
+        Ok(())
+    }
+}
+
+// The argument of a preprocessor directive, if `line` is that directive
+fn directive<'s>(line: &'s str, name: &str) -> Option<&'s str> {
+    let rest = line.trim_start().strip_prefix('#')?.trim_start().strip_prefix(name)?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(rest.trim())
+}
+
+// Used to tell whether two paths are the same file
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own with the given files in it
+    fn directory(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("gloom-shader-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        for (file, source) in files {
+            let path = directory.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
         }
+        directory
+    }
+
+    fn preprocess_file(path: &Path, defines: &[(&str, &str)]) -> Result<Preprocessed, ShaderError> {
+        let source = std::fs::read_to_string(path).unwrap();
+        let defines: Vec<(String, String)> = defines.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect();
+        preprocess(&source, Some(path), ShaderType::Fragment, &defines)
+    }
 
-        Shader {
-            program_id: self.program_id
+    // The file and line the driver would report for the output line containing `marker`, going
+    // by the `#line` directives as GLSL does
+    fn origin(preprocessed: &Preprocessed, marker: &str) -> (Option<PathBuf>, u32) {
+        let mut line = 1;
+        for output_line in preprocessed.source.lines() {
+            if let Some(argument) = directive(output_line, "line") {
+                line = argument.split_whitespace().next().unwrap().parse().unwrap();
+                continue;
+            }
+            if output_line.contains(marker) {
+                let file = preprocessed.files[(line / LINES_PER_FILE) as usize].clone();
+                return (file, line % LINES_PER_FILE);
+            }
+            line += 1;
         }
+        panic!("{} is not in the output", marker);
+    }
+
+    #[test]
+    fn nested_includes_map_back_to_their_lines() {
+        let directory = directory("nested", &[
+            ("main.frag", "#version 330 core\n// main 2\n#include \"a.glsl\"\nfloat main_4;\n"),
+            ("a.glsl", "float a_1;\n#include \"sub/b.glsl\"\n\nfloat a_4;\n"),
+            ("sub/b.glsl", "// b 1\nfloat b_2;\n"),
+        ]);
+        let main = directory.join("main.frag");
+        let preprocessed = preprocess_file(&main, &[]).unwrap();
+
+        let a = directory.join("a.glsl");
+        let b = directory.join("sub/b.glsl");
+        assert_eq!(origin(&preprocessed, "main 2"), (Some(main.clone()), 2));
+        assert_eq!(origin(&preprocessed, "a_1"), (Some(a.clone()), 1));
+        assert_eq!(origin(&preprocessed, "b_2"), (Some(b), 2));
+        assert_eq!(origin(&preprocessed, "a_4"), (Some(a), 4));
+        assert_eq!(origin(&preprocessed, "main_4"), (Some(main), 4));
+        assert!(!preprocessed.source.contains("#include"));
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let directory = directory("cycle", &[
+            ("main.frag", "#include \"a.glsl\"\n"),
+            ("a.glsl", "#include \"b.glsl\"\n"),
+            ("b.glsl", "#include \"a.glsl\"\n"),
+        ]);
+        let error = preprocess_file(&directory.join("main.frag"), &[]).err().expect("a cycle is an error");
+        assert_eq!(error.kind, ShaderErrorKind::Preprocess);
+        assert!(error.log.contains("include cycle"), "{}", error.log);
+        assert!(error.log.contains("b.glsl:1"), "{}", error.log);
+    }
+
+    #[test]
+    fn pragma_once_includes_a_file_once() {
+        let directory = directory("once", &[
+            ("main.frag", "#include \"once.glsl\"\n#include \"twice.glsl\"\n#include \"once.glsl\"\n#include \"twice.glsl\"\n"),
+            ("once.glsl", "#pragma once\nfloat once;\n"),
+            ("twice.glsl", "float twice;\n"),
+        ]);
+        let preprocessed = preprocess_file(&directory.join("main.frag"), &[]).unwrap();
+        assert_eq!(preprocessed.source.matches("float once;").count(), 1);
+        assert_eq!(preprocessed.source.matches("float twice;").count(), 2);
+        assert!(!preprocessed.source.contains("#pragma once"));
+    }
+
+    #[test]
+    fn defines_go_after_the_version() {
+        let directory = directory("defines", &[
+            ("main.frag", "// A comment first\n#version 330 core\n#include \"a.glsl\"\nfloat main;\n"),
+            ("a.glsl", "float a;\n"),
+            ("plain.frag", "#include \"a.glsl\"\n"),
+        ]);
+        let defines = [("INSTANCED", "1"), ("NORMAL_MAP", "1")];
+        let preprocessed = preprocess_file(&directory.join("main.frag"), &defines).unwrap();
+        let lines: Vec<&str> = preprocessed.source.lines().collect();
+        let position = |wanted: &str| lines.iter().position(|line| *line == wanted).unwrap();
+        assert!(position("#version 330 core") < position("#define INSTANCED 1"));
+        assert_eq!(position("#define INSTANCED 1") + 1, position("#define NORMAL_MAP 1"));
+        assert!(position("#define NORMAL_MAP 1") < position("float a;"));
+        assert_eq!(origin(&preprocessed, "float main;").1, 4);
+
+        // Without a version, they go first
+        let preprocessed = preprocess_file(&directory.join("plain.frag"), &defines).unwrap();
+        assert!(preprocessed.source.starts_with("#define INSTANCED 1\n#define NORMAL_MAP 1\n"));
+    }
+
+    #[test]
+    fn failures_tell_what_was_read() {
+        let directory = directory("missing", &[
+            ("main.frag", "#include \"a.glsl\"\n"),
+            ("a.glsl", "#include \"missing.glsl\"\n"),
+        ]);
+        let main = directory.join("main.frag");
+        let error = preprocess_file(&main, &[]).err().expect("a missing include is an error");
+        assert_eq!(error.kind, ShaderErrorKind::Read);
+        assert_eq!(error.dependencies, [main, directory.join("a.glsl"), directory.join("missing.glsl")]);
+    }
+
+    // The fields of every diagnostic, for comparing
+    fn fields(diagnostics: &[ShaderDiagnostic]) -> Vec<(Option<PathBuf>, u32, &str)> {
+        diagnostics.iter().map(|d| (d.path.clone(), d.line, d.message.as_str())).collect()
+    }
+
+    #[test]
+    fn mesa_logs() {
+        let log = "0:12(5): error: `foo' undeclared\n\
+                   0:12(5): error: operands to arithmetic operators must be numeric\n\
+                   0:40(1): warning: `normal' used uninitialized\n";
+        let files = [Some(PathBuf::from("simple.frag"))];
+        let file = files[0].clone();
+        assert_eq!(fields(&parse_info_log(log, &files)), [
+            (file.clone(), 12, "error: `foo' undeclared"),
+            (file.clone(), 12, "error: operands to arithmetic operators must be numeric"),
+            (file, 40, "warning: `normal' used uninitialized"),
+        ]);
+    }
+
+    #[test]
+    fn nvidia_logs() {
+        let log = "0(12) : error C1008: undefined variable \"foo\"\n\
+                   0(27) : warning C7533: global variable gl_FragColor is deprecated after version 120\n";
+        let files = [Some(PathBuf::from("simple.frag"))];
+        let file = files[0].clone();
+        assert_eq!(fields(&parse_info_log(log, &files)), [
+            (file.clone(), 12, "error C1008: undefined variable \"foo\""),
+            (file, 27, "warning C7533: global variable gl_FragColor is deprecated after version 120"),
+        ]);
+    }
+
+    #[test]
+    fn amd_and_intel_logs() {
+        let log = "ERROR: 0:12: 'foo' : undeclared identifier \n\
+                   ERROR: 0:12: '=' :  cannot convert from 'const float' to 'out highp 4-component vector of float'\n\
+                   ERROR: 2 compilation errors.  No code generated.\n";
+        let files = [Some(PathBuf::from("simple.frag"))];
+        let file = files[0].clone();
+        assert_eq!(fields(&parse_info_log(log, &files)), [
+            (file.clone(), 12, "'foo' : undeclared identifier"),
+            (file, 12, "'=' :  cannot convert from 'const float' to 'out highp 4-component vector of float'"),
+        ]);
+    }
+
+    #[test]
+    fn lines_map_back_to_included_files() {
+        let files = [
+            Some(PathBuf::from("shaders/simple.frag")),
+            Some(PathBuf::from("shaders/lighting.glsl")),
+            Some(PathBuf::from("shaders/sub/noise.glsl")),
+        ];
+        let log = "0:7(3): error: syntax error, unexpected '}'\n\
+                   1(100021) : error C0000: syntax error, unexpected '}' at token \"}\"\n\
+                   ERROR: 2:200004: 'noise' : no matching overloaded function found\n\
+                   0:300001(1): error: a file which isn't there\n";
+        assert_eq!(fields(&parse_info_log(log, &files)), [
+            (files[0].clone(), 7, "error: syntax error, unexpected '}'"),
+            (files[1].clone(), 21, "error C0000: syntax error, unexpected '}' at token \"}\""),
+            (files[2].clone(), 4, "'noise' : no matching overloaded function found"),
+            (None, 1, "error: a file which isn't there"),
+        ]);
     }
 }
@@ -0,0 +1,358 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::CLEAR_COLOR;
+use crate::bounds::Frustum;
+use crate::mesh::{Material, Mesh};
+use crate::scene_graph::{DrawStats, NodeId, SceneGraph};
+use crate::toolbox;
+
+// A software rasterizer, drawing the same scene graph as the OpenGL renderer entirely on the CPU.
+//
+// It mirrors what `simple.vert` and `simple.frag` do: every vertex is transformed by the model
+// and view-projection matrices, its normal by the model matrix, and every fragment is lit by a
+// fixed directional light, with the Lambert term for the diffuse colour of the material and a
+// Blinn-Phong highlight for the specular colour. The depth test and blending are the same
+// `gl::LESS` and `SRC_ALPHA, ONE_MINUS_SRC_ALPHA` as in `setup_gl`.
+//
+// The diffuse texture and the normal map of the material are sampled bilinearly, repeating outside
+// of [0, 1]. There are no mipmaps, so textures far away will look noisier than with OpenGL.
+// Textures set on the scene nodes themselves live on the GPU and are not used.
+//
+// It's far slower than the GPU, but works on machines without any OpenGL driver, and serves as a
+// reference to compare the output of the OpenGL renderer against.
+
+// Should match `lightDirection` in `lighting.glsl`
+const LIGHT_DIRECTION : [f32; 3] = [0.8, -0.5, 0.6];
+
+// A vertex after the vertex shader
+#[derive(Clone, Copy)]
+struct ClipVertex {
+    position : glm::Vec4,   // In clip space
+    color    : glm::Vec4,
+    normal   : glm::Vec3,
+    world    : glm::Vec3,   // The position in world space
+    texcoord : glm::Vec2,
+    tangent  : glm::Vec4,   // w is the sign of the bitangent
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        ClipVertex {
+            position : glm::lerp(&self.position, &other.position, t),
+            color    : glm::lerp(&self.color, &other.color, t),
+            normal   : glm::lerp(&self.normal, &other.normal, t),
+            world    : glm::lerp(&self.world, &other.world, t),
+            texcoord : glm::lerp(&self.texcoord, &other.texcoord, t),
+            tangent  : glm::lerp(&self.tangent, &other.tangent, t),
+        }
+    }
+}
+
+// A vertex in window coordinates, ready for rasterization
+struct ScreenVertex {
+    x        : f32,
+    y        : f32,
+    depth    : f32,
+    inv_w    : f32,   // Used for perspective correct interpolation
+    color    : glm::Vec4,
+    normal   : glm::Vec3,
+    world    : glm::Vec3,
+    texcoord : glm::Vec2,
+    tangent  : glm::Vec4,
+}
+
+// What the fragment shader needs besides the vertex data, i.e. the uniforms
+struct Shading<'a> {
+    material        : &'a Material,
+    camera_position : glm::Vec3,
+    texture         : Option<Rc<image::RgbaImage>>,
+    normal_texture  : Option<Rc<image::RgbaImage>>,   // Only if the mesh has tangents
+}
+
+pub struct SoftwareRenderer {
+    width  : u32,
+    height : u32,
+    color  : Vec<glm::Vec4>,
+    depth  : Vec<f32>,
+    light_direction : glm::Vec3,
+    textures : HashMap<PathBuf, Option<Rc<image::RgbaImage>>>,  // None if it failed to load
+}
+
+impl SoftwareRenderer {
+    pub fn new(width: u32, height: u32) -> SoftwareRenderer {
+        let pixels = (width * height) as usize;
+        SoftwareRenderer {
+            width,
+            height,
+            color : vec![glm::make_vec4(&CLEAR_COLOR); pixels],
+            depth : vec![1.0; pixels],
+            light_direction : glm::normalize(&glm::make_vec3(&LIGHT_DIRECTION)),
+            textures : HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.color.iter_mut().for_each(|c| *c = glm::make_vec4(&CLEAR_COLOR));
+        self.depth.iter_mut().for_each(|d| *d = 1.0);
+    }
+
+    // Draws every node below (and including) `node_id` which has a mesh, at the same level of
+    // detail and skipping the same nodes as `draw_scene`. Like `draw_scene`, this uses the cached
+    // world matrices and bounds, so call `SceneGraph::update_transforms` first.
+    pub fn draw_scene(&mut self, scene: &SceneGraph, node_id: NodeId, view_projection_matrix: &glm::Mat4) -> DrawStats {
+        let frustum = Frustum::from_matrix(view_projection_matrix);
+        let mut stats = DrawStats::default();
+        let mut stack = vec![node_id];
+        while let Some(id) = stack.pop() {
+            let node = &scene[id];
+            if frustum.excludes(node.subtree_bounds()) {
+                stats.culled += scene.drawable_count(id);
+                continue;
+            }
+            stack.extend(node.children().iter().rev());
+            if !node.is_drawable() {
+                continue;
+            }
+            if frustum.excludes(node.bounds()) {
+                stats.culled += 1;
+                continue;
+            }
+            // Nodes with only a GPU-side mesh have nothing to draw here
+            if let (Some(mesh), _) = node.lod(view_projection_matrix) {
+                self.draw_mesh(mesh, node.world_matrix(), view_projection_matrix);
+                stats.drawn += 1;
+                stats.draw_calls += 1;
+            }
+        }
+        stats
+    }
+
+    pub fn draw_mesh(&mut self, mesh: &Mesh, model_matrix: &glm::Mat4, view_projection_matrix: &glm::Mat4) {
+        let shading = Shading {
+            material        : &mesh.material,
+            camera_position : toolbox::camera_position(view_projection_matrix),
+            texture         : self.load_texture(&mesh.material.diffuse_texture),
+            normal_texture  : if mesh.has_tangents() { self.load_texture(&mesh.material.normal_texture) } else { None },
+        };
+        let mvp = view_projection_matrix * model_matrix;
+        let normal_matrix = glm::mat4_to_mat3(model_matrix);
+
+        // The vertex shader
+        let vertex_count = mesh.vertices.len() / 3;
+        let transformed: Vec<ClipVertex> = (0..vertex_count).map(|i| {
+            let position = glm::vec4(mesh.vertices[3*i], mesh.vertices[3*i + 1], mesh.vertices[3*i + 2], 1.0);
+            let color = match mesh.colors.get(4*i..4*i + 4) {
+                Some(c) => glm::make_vec4(c),
+                None    => glm::zero(),
+            };
+            let normal = match mesh.normals.get(3*i..3*i + 3) {
+                Some(n) => glm::normalize(&(normal_matrix * glm::make_vec3(n))),
+                None    => glm::zero(),
+            };
+            let world = (model_matrix * position).xyz();
+            let texcoord = match mesh.texcoords.get(2*i..2*i + 2) {
+                Some(t) => glm::make_vec2(t),
+                None    => glm::zero(),
+            };
+            let tangent = match mesh.tangents.get(4*i..4*i + 4) {
+                Some(t) => {
+                    let direction = glm::normalize(&(normal_matrix * glm::make_vec3(&t[..3])));
+                    glm::vec4(direction.x, direction.y, direction.z, t[3])
+                }
+                None => glm::zero(),
+            };
+            ClipVertex { position: mvp * position, color, normal, world, texcoord, tangent }
+        }).collect();
+
+        for triangle in mesh.indices.chunks_exact(3) {
+            let corners = [
+                transformed[triangle[0] as usize],
+                transformed[triangle[1] as usize],
+                transformed[triangle[2] as usize],
+            ];
+            let clipped = clip_near(&corners);
+            // The clipped polygon is convex, so it can be drawn as a fan
+            for i in 1..clipped.len().saturating_sub(1) {
+                self.rasterize(&clipped[0], &clipped[i], &clipped[i + 1], &shading);
+            }
+        }
+    }
+
+    fn to_screen(&self, vertex: &ClipVertex) -> ScreenVertex {
+        let inv_w = 1.0 / vertex.position.w;
+        let ndc = vertex.position.xyz() * inv_w;
+        ScreenVertex {
+            x        : (ndc.x * 0.5 + 0.5) * self.width as f32,
+            // Images store the top row first, while OpenGL has y pointing up
+            y        : (0.5 - ndc.y * 0.5) * self.height as f32,
+            depth    : ndc.z * 0.5 + 0.5,
+            inv_w,
+            color    : vertex.color * inv_w,
+            normal   : vertex.normal * inv_w,
+            world    : vertex.world * inv_w,
+            texcoord : vertex.texcoord * inv_w,
+            tangent  : vertex.tangent * inv_w,
+        }
+    }
+
+    fn rasterize(&mut self, a: &ClipVertex, b: &ClipVertex, c: &ClipVertex, shading: &Shading) {
+        let (a, b, c) = (self.to_screen(a), self.to_screen(b), self.to_screen(c));
+
+        // Twice the signed area, the winding doesn't matter since culling is disabled
+        let area = edge(&a, &b, c.x, c.y);
+        if area == 0.0 || !area.is_finite() {
+            return;
+        }
+
+        // The pixels covered by the bounding box of the triangle, clamped to the screen
+        let (width, height) = (self.width as f32, self.height as f32);
+        let min_x = a.x.min(b.x).min(c.x).floor().clamp(0.0, width) as u32;
+        let min_y = a.y.min(b.y).min(c.y).floor().clamp(0.0, height) as u32;
+        let max_x = a.x.max(b.x).max(c.x).ceil().clamp(0.0, width) as u32;
+        let max_y = a.y.max(b.y).max(c.y).ceil().clamp(0.0, height) as u32;
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                // Sample at the center of the pixel, like OpenGL
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let wa = edge(&b, &c, px, py) / area;
+                let wb = edge(&c, &a, px, py) / area;
+                let wc = edge(&a, &b, px, py) / area;
+                if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                    continue;
+                }
+
+                let depth = wa * a.depth + wb * b.depth + wc * c.depth;
+                let index = (y * self.width + x) as usize;
+                if depth < 0.0 || depth >= self.depth[index] {   // also clips against the far plane
+                    continue;
+                }
+
+                // Perspective correct interpolation of the attributes
+                let inv_w = wa * a.inv_w + wb * b.inv_w + wc * c.inv_w;
+                let color  = (a.color * wa + b.color * wb + c.color * wc) / inv_w;
+                let normal = (a.normal * wa + b.normal * wb + c.normal * wc) / inv_w;
+                let world  = (a.world * wa + b.world * wb + c.world * wc) / inv_w;
+                let texcoord = (a.texcoord * wa + b.texcoord * wb + c.texcoord * wc) / inv_w;
+                let normal = match &shading.normal_texture {
+                    Some(normal_texture) => {
+                        let tangent = (a.tangent * wa + b.tangent * wb + c.tangent * wc) / inv_w;
+                        perturb_normal(&normal, &tangent, &sample(normal_texture, &texcoord).xyz())
+                    }
+                    None => normal,
+                };
+
+                let albedo = match &shading.texture {
+                    Some(texture) => color.component_mul(&sample(texture, &texcoord)),
+                    None          => color,
+                };
+
+                // Like the colour buffer of OpenGL, only values between 0 and 1 are kept
+                let shaded = glm::clamp(&self.shade(&albedo, &normal, &world, shading), 0.0, 1.0);
+
+                // Blending, which applies to the alpha channel as well
+                let destination = self.color[index];
+                self.depth[index] = depth;
+                self.color[index] = shaded * shaded.w + destination * (1.0 - shaded.w);
+            }
+        }
+    }
+
+    fn load_texture(&mut self, path: &Option<PathBuf>) -> Option<Rc<image::RgbaImage>> {
+        let path = path.as_ref()?;
+        self.textures.entry(path.clone()).or_insert_with(|| {
+            match image::open(path) {
+                Ok(image) => Some(Rc::new(image.to_rgba8())),
+                Err(e) => {
+                    eprintln!("Failed to load texture {}: {}", path.display(), e);
+                    None
+                }
+            }
+        }).clone()
+    }
+
+    // The fragment shader
+    fn shade(&self, color: &glm::Vec4, normal: &glm::Vec3, world: &glm::Vec3, shading: &Shading) -> glm::Vec4 {
+        let material = shading.material;
+        let ambient  = glm::make_vec3(&material.ambient);
+        let diffuse  = glm::make_vec3(&material.diffuse);
+        let specular = glm::make_vec3(&material.specular);
+
+        let to_light = -self.light_direction;
+        let lambert = glm::dot(normal, &to_light).max(0.0);
+        let highlight = if material.shininess <= 0.0 || glm::dot(normal, &to_light) <= 0.0 {
+            0.0
+        } else {
+            let view_direction = glm::normalize(&(shading.camera_position - world));
+            let halfway = glm::normalize(&(view_direction + to_light));
+            glm::dot(&glm::normalize(normal), &halfway).max(0.0).powf(material.shininess)
+        };
+
+        let lit = color.xyz().component_mul(&(ambient + diffuse * lambert)) + specular * highlight;
+        glm::vec4(lit.x, lit.y, lit.z, color.w * material.dissolve)
+    }
+
+    pub fn to_image(&self) -> image::RgbaImage {
+        let to_byte = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        image::RgbaImage::from_fn(self.width, self.height, |x, y| {
+            let c = self.color[(y * self.width + x) as usize];
+            image::Rgba([to_byte(c.x), to_byte(c.y), to_byte(c.z), to_byte(c.w)])
+        })
+    }
+}
+
+// Bilinear filtering between the four texels closest to the texture coordinate, like
+// `gl::LINEAR` with `gl::REPEAT`. Images store the top row first, while v = 0 is at the bottom.
+fn sample(texture: &image::RgbaImage, texcoord: &glm::Vec2) -> glm::Vec4 {
+    let (width, height) = (texture.width() as i64, texture.height() as i64);
+    let x = texcoord.x * width as f32 - 0.5;
+    let y = (1.0 - texcoord.y) * height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let texel = |x: i64, y: i64| {
+        let pixel = texture.get_pixel(x.rem_euclid(width) as u32, y.rem_euclid(height) as u32);
+        glm::vec4(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32, pixel[3] as f32) / 255.0
+    };
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top    = glm::lerp(&texel(x0, y0),     &texel(x0 + 1, y0),     fx);
+    let bottom = glm::lerp(&texel(x0, y0 + 1), &texel(x0 + 1, y0 + 1), fx);
+    glm::lerp(&top, &bottom, fy)
+}
+
+// The normal as bent by a texel of a normal map, like `surface_normal` in `simple.frag`
+fn perturb_normal(normal: &glm::Vec3, tangent: &glm::Vec4, texel: &glm::Vec3) -> glm::Vec3 {
+    let normal = glm::normalize(normal);
+    let sign = tangent.w;
+    let tangent = glm::normalize(&(tangent.xyz() - normal * glm::dot(&normal, &tangent.xyz())));
+    let bitangent = glm::cross(&normal, &tangent) * sign;
+    let mapped = texel * 2.0 - glm::vec3(1.0, 1.0, 1.0);
+    glm::normalize(&(tangent * mapped.x + bitangent * mapped.y + normal * mapped.z))
+}
+
+// Positive when (x, y) is to the left of the edge from a to b
+fn edge(a: &ScreenVertex, b: &ScreenVertex, x: f32, y: f32) -> f32 {
+    (b.x - a.x) * (y - a.y) - (b.y - a.y) * (x - a.x)
+}
+
+// Clips a triangle against the near plane (z > -w), so that nothing behind the camera is drawn.
+// Triangles crossing the plane become a polygon of up to four corners.
+fn clip_near(triangle: &[ClipVertex; 3]) -> Vec<ClipVertex> {
+    let distance = |v: &ClipVertex| v.position.z + v.position.w;
+    let mut polygon = Vec::with_capacity(4);
+    for i in 0..3 {
+        let (current, next) = (&triangle[i], &triangle[(i + 1) % 3]);
+        let (d_current, d_next) = (distance(current), distance(next));
+        if d_current >= 0.0 {
+            polygon.push(*current);
+        }
+        if (d_current >= 0.0) != (d_next >= 0.0) {
+            polygon.push(current.lerp(next, d_current / (d_current - d_next)));
+        }
+    }
+    polygon
+}
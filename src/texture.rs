@@ -0,0 +1,131 @@
+use std::path::{Path, PathBuf};
+
+// A 2D texture on the GPU. It owns the texture object and deletes it when dropped, so it must be
+// dropped while the OpenGL context it was created in is still current.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrap {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Linear,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TextureOptions {
+    pub wrap       : Wrap,      // For texture coordinates outside of [0, 1]
+    pub min_filter : Filter,    // When a pixel covers many texels
+    pub mag_filter : Filter,    // When a texel covers many pixels
+    pub mipmaps    : bool,      // Whether to generate mipmaps and filter between them
+}
+
+impl Default for TextureOptions {
+    fn default() -> TextureOptions {
+        TextureOptions {
+            wrap       : Wrap::Repeat,
+            min_filter : Filter::Linear,
+            mag_filter : Filter::Linear,
+            mipmaps    : true,
+        }
+    }
+}
+
+pub struct Texture {
+    texture_id : u32,
+    width      : u32,
+    height     : u32,
+    path       : Option<PathBuf>,   // The file it was loaded from
+}
+
+impl Texture {
+    // Loads a PNG or JPEG, or anything else the `image` crate can read
+    pub unsafe fn load<P: AsRef<Path>>(path: P, options: &TextureOptions) -> image::ImageResult<Texture> {
+        let image = image::open(path.as_ref())?.to_rgba8();
+        let mut texture = Texture::from_image(&image, options);
+        texture.path = Some(path.as_ref().to_path_buf());
+        Ok(texture)
+    }
+
+    pub unsafe fn from_image(image: &image::RgbaImage, options: &TextureOptions) -> Texture {
+        // Images store the top row first, while texture coordinates have v = 0 at the bottom
+        let image = image::imageops::flip_vertical(image);
+
+        let mut texture_id = 0;
+        gl::GenTextures(1, &mut texture_id);
+        gl::BindTexture(gl::TEXTURE_2D, texture_id);
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+        gl::TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA8 as i32,
+            image.width() as i32,
+            image.height() as i32,
+            0,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            image.as_ptr().cast()
+        );
+        if options.mipmaps {
+            gl::GenerateMipmap(gl::TEXTURE_2D);
+        }
+
+        let wrap = match options.wrap {
+            Wrap::Repeat         => gl::REPEAT,
+            Wrap::MirroredRepeat => gl::MIRRORED_REPEAT,
+            Wrap::ClampToEdge    => gl::CLAMP_TO_EDGE,
+        };
+        let min_filter = match (options.min_filter, options.mipmaps) {
+            (Filter::Nearest, false) => gl::NEAREST,
+            (Filter::Linear,  false) => gl::LINEAR,
+            (Filter::Nearest, true)  => gl::NEAREST_MIPMAP_NEAREST,
+            (Filter::Linear,  true)  => gl::LINEAR_MIPMAP_LINEAR,
+        };
+        let mag_filter = match options.mag_filter {
+            Filter::Nearest => gl::NEAREST,
+            Filter::Linear  => gl::LINEAR,
+        };
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, min_filter as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, mag_filter as i32);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+
+        Texture {
+            texture_id,
+            width  : image.width(),
+            height : image.height(),
+            path   : None,
+        }
+    }
+
+    pub fn texture_id(&self) -> u32 {
+        self.texture_id
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // Binds the texture to texture unit `unit`, which the sampler in the shader should be set to
+    pub unsafe fn bind(&self, unit: u32) {
+        gl::ActiveTexture(gl::TEXTURE0 + unit);
+        gl::BindTexture(gl::TEXTURE_2D, self.texture_id);
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.texture_id);
+        }
+    }
+}
@@ -9,6 +9,24 @@ pub struct Heading {
     pub yaw   : f32, // measured in radians
 }
 
+impl Heading {
+    // The roll, pitch and yaw as a single quaternion, so they don't suffer from gimbal lock and
+    // can be slerped. Equivalent to a rotation of (pitch, yaw, roll) in `EulerOrder::ZYX`.
+    pub fn orientation(&self) -> glm::Quat {
+        let roll  = glm::quat_angle_axis(self.roll,  &glm::vec3(0.0, 0.0, 1.0));
+        let yaw   = glm::quat_angle_axis(self.yaw,   &glm::vec3(0.0, 1.0, 0.0));
+        let pitch = glm::quat_angle_axis(self.pitch, &glm::vec3(1.0, 0.0, 0.0));
+        pitch * yaw * roll
+    }
+}
+
+// Where the camera is in the world, for a perspective view-projection matrix. The camera is the
+// point which ends up with x = y = w = 0 in clip space.
+pub fn camera_position(view_projection: &glm::Mat4) -> glm::Vec3 {
+    let camera = glm::inverse(view_projection) * glm::vec4(0.0, 0.0, 1.0, 0.0);
+    camera.xyz() / camera.w
+}
+
 pub fn simple_heading_animation(time: f32) -> Heading {
     let t             = time as f64;
     let step          = 0.05f64;
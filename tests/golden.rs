@@ -0,0 +1,335 @@
+// Golden-image regression tests for the renderer.
+//
+// Every test renders the lunar terrain and the helicopters at a fixed time through the headless
+// renderer (`--render-frame`), and compares the result to a reference image in `tests/golden`.
+// A frame passes when nearly all pixels are within a small tolerance of the reference, and the
+// structural similarity (SSIM) of the two images is high, so that tiny rasterization differences
+// between drivers are accepted while visible changes are not.
+//
+// When a frame does not match, the rendered image and a diff image are written to
+// `target/tmp/golden-diff` for review. If a change to the renderer is intended, the references
+// can be updated by running the tests with `GLOOM_BLESS=1`.
+//
+// The models in `resources` are handed out separately and are not part of the repository, so
+// the frames are rendered from the small stand-ins in `tests/golden/scene/resources` instead:
+// a heightfield for the terrain, and a helicopter made of boxes with the same part names. The
+// renderer runs in a copy of that scene under `target/tmp`, along with the shaders.
+//
+// The tests at the bottom check the comparison itself on small made-up images.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Once;
+
+use image::{Rgba, RgbaImage};
+
+const WIDTH  : u32 = 400;
+const HEIGHT : u32 = 300;
+
+// Largest difference in any channel for two pixels to be considered equal
+const CHANNEL_TOLERANCE : u8 = 8;
+// Fraction of the pixels which may differ by more than the tolerance
+const MAX_MISMATCHED    : f64 = 0.005;
+// Lowest acceptable mean SSIM between the rendered frame and the reference
+const MIN_SSIM          : f64 = 0.98;
+
+const MODELS: [&str; 3] = ["lunarsurface.obj", "helicopter.obj", "helicopter.mtl"];
+
+
+fn manifest_dir() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn diff_dir() -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden-diff")
+}
+
+// A directory to run the renderer in, with the stand-in models in `resources` and the shaders
+// in `shaders`. It's set up once for all tests, and a missing model fails them all.
+fn scene_dir() -> PathBuf {
+    static SETUP: Once = Once::new();
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden-scene");
+    SETUP.call_once(|| {
+        let _ = std::fs::remove_dir_all(&dir);
+        let resources = dir.join("resources");
+        let shaders = dir.join("shaders");
+        std::fs::create_dir_all(&resources).unwrap();
+        std::fs::create_dir_all(&shaders).unwrap();
+        let models = manifest_dir().join("tests").join("golden").join("scene").join("resources");
+        for model in MODELS.iter() {
+            std::fs::copy(models.join(model), resources.join(model))
+                .unwrap_or_else(|e| panic!("Missing {}: {}", models.join(model).display(), e));
+        }
+        for entry in std::fs::read_dir(manifest_dir().join("shaders")).unwrap() {
+            let path = entry.unwrap().path();
+            std::fs::copy(&path, shaders.join(path.file_name().unwrap())).unwrap();
+        }
+    });
+    dir
+}
+
+// Returns what the renderer printed
+fn render_frame(time: f32, output: &Path, backend: &str) -> String {
+    render_frame_with(time, output, backend, &[])
+}
+
+fn render_frame_with(time: f32, output: &Path, backend: &str, extra_args: &[&str]) -> String {
+    let result = Command::new(env!("CARGO_BIN_EXE_gloom-rs"))
+        .current_dir(scene_dir())
+        .arg("--render-frame").arg(format!("t={}", time))
+        .arg("--out").arg(output)
+        .arg("--size").arg(format!("{}x{}", WIDTH, HEIGHT))
+        .arg("--backend").arg(backend)
+        .args(extra_args)
+        .output()
+        .expect("Failed to run gloom-rs");
+    assert!(result.status.success(),
+        "Headless rendering failed:\n{}{}",
+        String::from_utf8_lossy(&result.stdout),
+        String::from_utf8_lossy(&result.stderr));
+    String::from_utf8_lossy(&result.stdout).into_owned()
+}
+
+// What was drawn in a frame, from the "(9 of 21 nodes drawn in 6 draw calls, 12 culled)" at the
+// end of its output
+#[derive(Debug, PartialEq)]
+struct DrawStats {
+    drawn      : usize,
+    total      : usize,
+    draw_calls : usize,
+    culled     : usize,
+}
+
+fn draw_stats(output: &str) -> DrawStats {
+    let line = output.lines().find(|line| line.starts_with("Rendered")).expect("No frame was rendered");
+    let stats = &line[line.rfind('(').expect("No draw stats")..];
+    let numbers: Vec<usize> = stats.split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().unwrap())
+        .collect();
+    match numbers[..] {
+        [drawn, total, draw_calls, culled] => DrawStats { drawn, total, draw_calls, culled },
+        _ => panic!("Unexpected draw stats {}", stats),
+    }
+}
+
+fn render_to_image(name: &str, time: f32, backend: &str) -> (RgbaImage, PathBuf) {
+    std::fs::create_dir_all(diff_dir()).unwrap();
+    let path = diff_dir().join(format!("{}-actual.png", name));
+    render_frame(time, &path, backend);
+    let image = image::open(&path).expect("Failed to read rendered frame").to_rgba8();
+    (image, path)
+}
+
+fn check_frame(name: &str, time: f32) {
+    let (actual, actual_path) = render_to_image(name, time, "gl");
+
+    let reference_path = manifest_dir().join("tests").join("golden").join(format!("{}.png", name));
+    if std::env::var_os("GLOOM_BLESS").is_some() {
+        std::fs::create_dir_all(reference_path.parent().unwrap()).unwrap();
+        actual.save(&reference_path).expect("Failed to write reference image");
+        eprintln!("Updated {}", reference_path.display());
+        return;
+    }
+    let expected = match image::open(&reference_path) {
+        Ok(image) => image.to_rgba8(),
+        Err(e) => panic!("Failed to read {}: {}\nRun with GLOOM_BLESS=1 to create it.",
+            reference_path.display(), e),
+    };
+    compare(name, &expected, &actual, &actual_path);
+}
+
+fn compare(name: &str, expected: &RgbaImage, actual: &RgbaImage, actual_path: &Path) {
+    assert_eq!(actual.dimensions(), expected.dimensions(), "Rendered frame has the wrong size");
+
+    let (diff, mismatched) = diff_image(expected, actual);
+    let mismatched = mismatched as f64 / (expected.width() * expected.height()) as f64;
+    let ssim = mean_ssim(expected, actual);
+
+    if mismatched > MAX_MISMATCHED || ssim < MIN_SSIM {
+        let diff_path = diff_dir().join(format!("{}-diff.png", name));
+        diff.save(&diff_path).expect("Failed to write diff image");
+        panic!("Frame {} differs from the reference: {:.3}% of the pixels differ (max {:.3}%), SSIM {:.4} (min {}).\n\
+                Rendered: {}\nDiff:     {}",
+            name, mismatched * 100.0, MAX_MISMATCHED * 100.0, ssim, MIN_SSIM,
+            actual_path.display(), diff_path.display());
+    }
+}
+
+// Highlights the pixels which differ by more than the tolerance in red, on top of a faded
+// grayscale copy of the reference. Also returns how many pixels differ.
+fn diff_image(expected: &RgbaImage, actual: &RgbaImage) -> (RgbaImage, usize) {
+    let mut mismatched = 0;
+    let diff = RgbaImage::from_fn(expected.width(), expected.height(), |x, y| {
+        let (e, a) = (expected.get_pixel(x, y), actual.get_pixel(x, y));
+        let max_delta = (0..4).map(|c| (e[c] as i32 - a[c] as i32).abs()).max().unwrap();
+        if max_delta > CHANNEL_TOLERANCE as i32 {
+            mismatched += 1;
+            Rgba([255, (255 - max_delta.min(255)) as u8 / 2, 0, 255])
+        } else {
+            let gray = (luma(e) * 0.3 * 255.0) as u8;
+            Rgba([gray, gray, gray, 255])
+        }
+    });
+    (diff, mismatched)
+}
+
+fn luma(pixel: &Rgba<u8>) -> f64 {
+    (0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64) / 255.0
+}
+
+// Mean structural similarity of the luma of two images, over 8x8 windows with a stride of 4
+fn mean_ssim(expected: &RgbaImage, actual: &RgbaImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const STRIDE: u32 = 4;
+    const C1: f64 = 0.01 * 0.01;
+    const C2: f64 = 0.03 * 0.03;
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for wy in (0..=expected.height() - WINDOW).step_by(STRIDE as usize) {
+        for wx in (0..=expected.width() - WINDOW).step_by(STRIDE as usize) {
+            let n = (WINDOW * WINDOW) as f64;
+            let (mut sum_e, mut sum_a, mut sum_ee, mut sum_aa, mut sum_ea) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in wy..wy + WINDOW {
+                for x in wx..wx + WINDOW {
+                    let e = luma(expected.get_pixel(x, y));
+                    let a = luma(actual.get_pixel(x, y));
+                    sum_e  += e;
+                    sum_a  += a;
+                    sum_ee += e * e;
+                    sum_aa += a * a;
+                    sum_ea += e * a;
+                }
+            }
+            let (mean_e, mean_a) = (sum_e / n, sum_a / n);
+            let var_e  = sum_ee / n - mean_e * mean_e;
+            let var_a  = sum_aa / n - mean_a * mean_a;
+            let cov_ea = sum_ea / n - mean_e * mean_a;
+
+            total += ((2.0 * mean_e * mean_a + C1) * (2.0 * cov_ea + C2))
+                   / ((mean_e * mean_e + mean_a * mean_a + C1) * (var_e + var_a + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+
+#[test]
+fn golden_t0() {
+    check_frame("t0", 0.0);
+}
+
+#[test]
+fn golden_t3_5() {
+    check_frame("t3_5", 3.5);
+}
+
+#[test]
+fn golden_t7_25() {
+    check_frame("t7_25", 7.25);
+}
+
+// The software rasterizer is the reference implementation of the OpenGL renderer, so the two
+// should agree up to the same tolerance as the golden images
+#[test]
+fn software_matches_gl() {
+    let (expected, _) = render_to_image("software-gl", 3.5, "gl");
+    let (actual, actual_path) = render_to_image("software-cpu", 3.5, "cpu");
+    compare("software", &expected, &actual, &actual_path);
+}
+
+// Some of the helicopters are behind the camera, and both renderers should skip the same ones
+#[test]
+fn culling_skips_offscreen_nodes() {
+    std::fs::create_dir_all(diff_dir()).unwrap();
+    let gl = draw_stats(&render_frame(3.5, &diff_dir().join("culling-gl.png"), "gl"));
+    let cpu = draw_stats(&render_frame(3.5, &diff_dir().join("culling-cpu.png"), "cpu"));
+    assert_eq!(gl.drawn + gl.culled, gl.total);
+    assert!(gl.drawn > 0 && gl.culled > 0, "Expected some nodes to be culled, got {:?}", gl);
+    assert_eq!((gl.drawn, gl.culled), (cpu.drawn, cpu.culled), "The renderers culled different nodes");
+}
+
+// The helicopters share their meshes, so however many there are, each part of them only takes one
+// draw call
+#[test]
+fn instancing_draws_copies_together() {
+    std::fs::create_dir_all(diff_dir()).unwrap();
+    let output = diff_dir().join("instancing-gl.png");
+    let few = draw_stats(&render_frame_with(3.5, &output, "gl", &["--helicopters", "5"]));
+    let many = draw_stats(&render_frame_with(3.5, &output, "gl", &["--helicopters", "500"]));
+    assert!(many.drawn > few.drawn, "Expected more nodes to be drawn, got {:?} and {:?}", few, many);
+    assert!(many.draw_calls * 10 < many.drawn, "Expected the copies to be drawn together, got {:?}", many);
+}
+
+
+// Self-tests of the comparison
+
+fn filled(width: u32, height: u32, pixel: [u8; 4]) -> RgbaImage {
+    RgbaImage::from_pixel(width, height, Rgba(pixel))
+}
+
+// Black and white squares of 4x4 pixels
+fn checkerboard(width: u32, height: u32, inverted: bool) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let white = ((x / 4 + y / 4) % 2 == 0) != inverted;
+        if white { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 255]) }
+    })
+}
+
+#[test]
+fn diff_tolerates_small_differences() {
+    let expected = filled(16, 16, [100, 100, 100, 255]);
+    let mut actual = expected.clone();
+    actual.put_pixel(0, 0, Rgba([108, 100, 100, 255]));   // Just within the tolerance
+    actual.put_pixel(1, 0, Rgba([100, 91, 100, 255]));    // Just past it
+    actual.put_pixel(2, 0, Rgba([100, 100, 100, 0]));     // Alpha counts too
+
+    let (diff, mismatched) = diff_image(&expected, &actual);
+    assert_eq!(mismatched, 2);
+    assert_eq!(diff.get_pixel(0, 0), diff.get_pixel(5, 5), "A pixel within the tolerance was highlighted");
+    for x in 1..3 {
+        let pixel = diff.get_pixel(x, 0);
+        assert_eq!((pixel[0], pixel[2]), (255, 0), "A mismatched pixel was not highlighted in red");
+    }
+}
+
+#[test]
+fn ssim_of_identical_images_is_one() {
+    let image = checkerboard(32, 32, false);
+    assert!((mean_ssim(&image, &image) - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn ssim_tells_structure_from_brightness() {
+    let image = checkerboard(32, 32, false);
+    let brighter = RgbaImage::from_fn(32, 32, |x, y| {
+        let pixel = image.get_pixel(x, y);
+        Rgba([pixel[0].max(4), pixel[1].max(4), pixel[2].max(4), 255])
+    });
+    assert!(mean_ssim(&image, &brighter) > MIN_SSIM);
+    assert!(mean_ssim(&image, &checkerboard(32, 32, true)) < 0.0);
+    assert!(mean_ssim(&image, &filled(32, 32, [128, 128, 128, 255])) < 0.1);
+}
+
+#[test]
+fn compare_writes_a_diff_image_on_mismatch() {
+    std::fs::create_dir_all(diff_dir()).unwrap();
+    let expected = checkerboard(32, 32, false);
+    let actual_path = diff_dir().join("selftest-actual.png");
+
+    let diff_path = diff_dir().join("selftest-match-diff.png");
+    let _ = std::fs::remove_file(&diff_path);
+    compare("selftest-match", &expected, &expected, &actual_path);
+    assert!(!diff_path.exists(), "A diff image was written for matching images");
+
+    let diff_path = diff_dir().join("selftest-mismatch-diff.png");
+    let _ = std::fs::remove_file(&diff_path);
+    let actual = checkerboard(32, 32, true);
+    let result = std::panic::catch_unwind(|| compare("selftest-mismatch", &expected, &actual, &actual_path));
+    assert!(result.is_err(), "Different images were accepted");
+    let diff = image::open(&diff_path).expect("No diff image was written").to_rgba8();
+    assert_eq!(diff.dimensions(), expected.dimensions());
+    assert_eq!(diff.get_pixel(0, 0)[0], 255);
+}